@@ -0,0 +1,42 @@
+// Lexing generated data files of growing size, time per byte should stay flat
+// cargo bench --bench lexer
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use zasm::Lexer;
+
+// a table like the generated data files, with strings, comments and some non-ascii text
+fn source(bytes: usize) -> String{
+    let mut content = String::from("/* generated table, größe in Wörtern */\ntable:\n");
+    let mut i: usize = 0;
+
+    while content.len() < bytes{
+        let value = i.wrapping_mul(2654435761) & 0xFFFF;
+        content += format!("    dw 0x{:04X}, {}, (table + {}), \"entry {}\" ; été {}\n", value, value % 1000, i % 64, i, i).as_str();
+        i += 1;
+    }
+
+    content
+}
+
+fn lex(c: &mut Criterion){
+    let mut group = c.benchmark_group("lex");
+    group.sample_size(10);
+
+    for size in [10_000, 100_000, 1_000_000]{
+        let content = source(size);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.iter(|| {
+                let mut lexer: Lexer = Lexer::new();
+                lexer.lex("bench.a", content).unwrap();
+                lexer.lexems.len()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, lex);
+criterion_main!(benches);
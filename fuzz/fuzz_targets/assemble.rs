@@ -0,0 +1,26 @@
+#![no_main]
+
+// everything zasm does with a file, including --strip-dead and the stack analysis
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use zasm::{Assembler, InstructionsLexer};
+
+fn instructions() -> &'static InstructionsLexer{
+    static INSTRUCTIONS: OnceLock<InstructionsLexer> = OnceLock::new();
+
+    INSTRUCTIONS.get_or_init(|| {
+        let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
+        instruction_lexer.lex_instructions().unwrap();
+        instruction_lexer
+    })
+}
+
+fuzz_target!(|content: &str| {
+    let mut assembler: Assembler = Assembler::new(&instructions().instructions);
+    assembler.strip_dead = true;
+    assembler.stack_size = Some(64);
+
+    let _ = assembler.assemble("fuzz.a", content);
+});
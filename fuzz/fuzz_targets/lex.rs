@@ -0,0 +1,11 @@
+#![no_main]
+
+// any text lexes to lexems or a diagnostic
+
+use libfuzzer_sys::fuzz_target;
+use zasm::Lexer;
+
+fuzz_target!(|content: &str| {
+    let mut lexer: Lexer = Lexer::new();
+    let _ = lexer.lex("fuzz.a", content);
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+// whatever lexes parses to tokens or a diagnostic
+
+use libfuzzer_sys::fuzz_target;
+use zasm::{Lexer, Parser};
+
+fuzz_target!(|content: &str| {
+    let mut lexer: Lexer = Lexer::new();
+    if lexer.lex("fuzz.a", content).is_err(){
+        return;
+    }
+
+    let mut parser: Parser = Parser::new();
+    let _ = parser.parse(&lexer.lexems);
+});
@@ -0,0 +1,289 @@
+// Language server for Zirc16 assembly, speaks LSP over stdin/stdout
+// diagnostics, go to definition, references, hover, completion and document symbols
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics};
+use lsp_types::request::{Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse, DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent,
+    MarkupKind, NumberOrString, OneOf, Position, PublishDiagnosticsParams, Range, ReferenceParams, ServerCapabilities, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url
+};
+
+use zasm::*;
+
+type ServerResult<T> = Result<T, Box<dyn Error + Sync + Send>>;
+
+struct Document{
+    text: String,
+    analysis: Analysis
+}
+
+struct Server<'a>{
+    connection: &'a Connection,
+    instructions: HashMap<&'static str, Vec<InstructionPart>>,
+    documents: HashMap<Url, Document>
+}
+
+fn filename(uri: &Url) -> String{
+    match uri.to_file_path(){
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => uri.to_string()
+    }
+}
+
+// lexems are 1-based
+fn lexem_range(analysis: &Analysis, lexem: &Lexem) -> Range{
+    let start = Position::new(lexem.row.saturating_sub(1) as u32, lexem.col.saturating_sub(1) as u32);
+    let end = Position::new(start.line, start.character + analysis.length(lexem) as u32);
+
+    Range::new(start, end)
+}
+
+fn row_col(position: &Position) -> (usize, usize){
+    (position.line as usize + 1, position.character as usize + 1)
+}
+
+impl Server<'_>{
+    fn update(self: &mut Self, uri: Url, text: String) -> ServerResult<()>{
+        let document = self.documents.entry(uri.clone()).or_insert_with(|| Document { text: String::new(), analysis: Analysis::new() });
+
+        document.text = text;
+        document.analysis.analyze(&filename(&uri), &document.text, &self.instructions);
+
+        let analysis = &document.analysis;
+
+        let diagnostics: Vec<lsp_types::Diagnostic> = analysis.diagnostics.iter().map(|diagnostic| {
+            let location = Lexem::new(String::new(), LexemType::Ident, diagnostic.row.max(1), diagnostic.col.max(1), diagnostic.filename.clone());
+
+            lsp_types::Diagnostic {
+                range: lexem_range(analysis, &location),
+                severity: Some(match diagnostic.severity{
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING
+                }),
+                code: diagnostic.warning.map(|name| NumberOrString::String(name.to_string())),
+                source: Some("zasm".to_string()),
+                message: diagnostic.message.clone(),
+                ..Default::default()
+            }
+        }).collect();
+
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(self: &Self, uri: Url, diagnostics: Vec<lsp_types::Diagnostic>) -> ServerResult<()>{
+        let params = PublishDiagnosticsParams { uri, diagnostics, version: None };
+        let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+
+        self.connection.sender.send(Message::Notification(notification))?;
+
+        Ok(())
+    }
+
+    fn respond(self: &Self, id: RequestId, result: serde_json::Value) -> ServerResult<()>{
+        self.connection.sender.send(Message::Response(Response::new_ok(id, result)))?;
+
+        Ok(())
+    }
+
+    fn location(uri: &Url, analysis: &Analysis, lexem: &Lexem) -> Location{
+        Location::new(uri.clone(), lexem_range(analysis, lexem))
+    }
+
+    fn definition(self: &Self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse>{
+        let uri = params.text_document_position_params.text_document.uri;
+        let analysis = &self.documents.get(&uri)?.analysis;
+        let (row, col) = row_col(&params.text_document_position_params.position);
+
+        let name = analysis.symbol_at(row, col)?;
+        let definition = analysis.definition(&name)?;
+
+        Some(GotoDefinitionResponse::Scalar(Self::location(&uri, analysis, definition)))
+    }
+
+    fn references(self: &Self, params: ReferenceParams) -> Option<Vec<Location>>{
+        let uri = params.text_document_position.text_document.uri;
+        let analysis = &self.documents.get(&uri)?.analysis;
+        let (row, col) = row_col(&params.text_document_position.position);
+
+        let name = analysis.symbol_at(row, col)?;
+        let mut locations: Vec<Location> = Vec::new();
+
+        if params.context.include_declaration{
+            if let Some(definition) = analysis.definition(&name){
+                locations.push(Self::location(&uri, analysis, definition));
+            }
+        }
+
+        for reference in analysis.references_to(&name){
+            locations.push(Self::location(&uri, analysis, reference));
+        }
+
+        Some(locations)
+    }
+
+    fn hover(self: &Self, params: HoverParams) -> Option<Hover>{
+        let uri = params.text_document_position_params.text_document.uri;
+        let analysis = &self.documents.get(&uri)?.analysis;
+        let (row, col) = row_col(&params.text_document_position_params.position);
+
+        let text = analysis.hover(row, col, &self.instructions)?;
+
+        Some(Hover { contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value: text }), range: None })
+    }
+
+    fn completion(self: &Self, params: CompletionParams) -> Option<CompletionResponse>{
+        let uri = params.text_document_position.text_document.uri;
+        let analysis = &self.documents.get(&uri)?.analysis;
+        let (row, col) = row_col(&params.text_document_position.position);
+
+        let items: Vec<CompletionItem> = analysis.completions(row, col).into_iter().map(|(label, kind)| CompletionItem {
+            label,
+            kind: Some(match kind{
+                CompletionKind::Instruction => CompletionItemKind::KEYWORD,
+                CompletionKind::Directive => CompletionItemKind::KEYWORD,
+                CompletionKind::Register => CompletionItemKind::VARIABLE,
+                CompletionKind::Condition => CompletionItemKind::ENUM_MEMBER,
+                CompletionKind::Label => CompletionItemKind::REFERENCE
+            }),
+            ..Default::default()
+        }).collect();
+
+        Some(CompletionResponse::Array(items))
+    }
+
+    #[allow(deprecated)]
+    fn document_symbols(self: &Self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse>{
+        let analysis = &self.documents.get(&params.text_document.uri)?.analysis;
+
+        let symbols: Vec<DocumentSymbol> = analysis.symbols().into_iter().map(|(name, lexem, address)| {
+            let range = lexem_range(analysis, lexem);
+
+            DocumentSymbol {
+                name,
+                detail: address.map(|address| format!("0x{:04X}", address)),
+                kind: SymbolKind::FUNCTION,
+                tags: None,
+                deprecated: None,
+                range,
+                selection_range: range,
+                children: None
+            }
+        }).collect();
+
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+
+    fn request(self: &mut Self, request: Request) -> ServerResult<()>{
+        let id = request.id.clone();
+
+        match request.method.as_str(){
+            GotoDefinition::METHOD => {
+                let params = serde_json::from_value(request.params)?;
+                self.respond(id, serde_json::to_value(self.definition(params))?)
+            }
+            References::METHOD => {
+                let params = serde_json::from_value(request.params)?;
+                self.respond(id, serde_json::to_value(self.references(params))?)
+            }
+            HoverRequest::METHOD => {
+                let params = serde_json::from_value(request.params)?;
+                self.respond(id, serde_json::to_value(self.hover(params))?)
+            }
+            Completion::METHOD => {
+                let params = serde_json::from_value(request.params)?;
+                self.respond(id, serde_json::to_value(self.completion(params))?)
+            }
+            DocumentSymbolRequest::METHOD => {
+                let params = serde_json::from_value(request.params)?;
+                self.respond(id, serde_json::to_value(self.document_symbols(params))?)
+            }
+            _ => {
+                let response = Response::new_err(id, lsp_server::ErrorCode::MethodNotFound as i32, format!("Unknown request {}", request.method));
+                self.connection.sender.send(Message::Response(response))?;
+                Ok(())
+            }
+        }
+    }
+
+    fn notification(self: &mut Self, notification: Notification) -> ServerResult<()>{
+        match notification.method.as_str(){
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+                self.update(params.text_document.uri, params.text_document.text)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+                // full sync, the last change holds the whole text
+                match params.content_changes.into_iter().last(){
+                    Some(change) => self.update(params.text_document.uri, change.text),
+                    None => Ok(())
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                self.publish(params.text_document.uri, Vec::new())
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+fn run(connection: Connection, instructions: HashMap<&'static str, Vec<InstructionPart>>) -> ServerResult<()>{
+    let mut server = Server {
+        connection: &connection,
+        instructions,
+        documents: HashMap::new()
+    };
+
+    for message in connection.receiver.iter(){
+        match message{
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)?{
+                    break;
+                }
+                server.request(request)?;
+            }
+            Message::Notification(notification) => server.notification(notification)?,
+            Message::Response(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> ServerResult<()>{
+    let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
+
+    if let Err(e) = instruction_lexer.lex_instructions(){
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    };
+
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    // the connection has to be dropped before joining, the writer thread ends with it
+    run(connection, instruction_lexer.instructions)?;
+
+    io_threads.join()?;
+
+    Ok(())
+}
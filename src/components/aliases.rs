@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::{Diagnostic, Lexem, LexemType, Lexer, Parser, Token, ABI_REGISTERS, REGISTERS_TO_VAL};
+
+// Register aliases
+// alias name, rN    (reg name, rN does the same)
+// an alias is usable from its definition onward, built-in ABI names are always defined
+// a label of the same name wins, operands naming it mean the label
+
+fn is_alias_directive(name: &str) -> bool{
+    name == "alias" || name == "reg"
+}
+
+// directives whose identifiers are labels or types, never registers
+fn is_data_directive(name: &str) -> bool{
+    matches!(name, "org" | "db" | "dw" | "dd" | "dq" | "struct" | "field" | "mmio")
+}
+
+// labels defined anywhere in the file, they are only known after aliases are resolved
+fn label_names(tokens: &[Token]) -> HashMap<String, Lexem>{
+    let mut labels: HashMap<String, Lexem> = HashMap::new();
+
+    for token in tokens{
+        match token{
+            Token::Label { name } => {
+                labels.insert(name.value.clone(), name.clone());
+            }
+            Token::Instruction { name, args } if name.value.to_lowercase() == "proc" && !args.is_empty() => {
+                labels.insert(args[0].value.clone(), args[0].clone());
+            }
+            _ => {}
+        }
+    }
+
+    labels
+}
+
+#[derive(Debug, Clone)]
+pub struct Aliases{
+    pub names: HashMap<String, String>,
+    // operands replaced by a register, by (filename, row, col) -> (register, name as written)
+    pub written: HashMap<(String, usize, usize), (String, String)>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl Aliases{
+    pub fn new() -> Aliases{
+        Aliases{
+            names: ABI_REGISTERS.entries().map(|(name, register)| (name.to_string(), register.to_string())).collect(),
+            written: HashMap::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    // register an alias is bound to
+    pub fn get(self: &Self, name: &str) -> Option<&String>{
+        self.names.get(&name.to_lowercase())
+    }
+
+    // rN -> alias, the alphabetically first one when a register has several
+    pub fn register_names(self: &Self) -> HashMap<String, String>{
+        return self.collect_register_names(true);
+    }
+
+    // like register_names, without built-in ABI names that still mean their own register
+    pub fn user_register_names(self: &Self) -> HashMap<String, String>{
+        return self.collect_register_names(false);
+    }
+
+    fn collect_register_names(self: &Self, builtin: bool) -> HashMap<String, String>{
+        let mut names: HashMap<String, String> = HashMap::new();
+
+        for (name, register) in self.names.iter(){
+            if !builtin && ABI_REGISTERS.get(name.as_str()) == Some(&register.as_str()){
+                continue;
+            }
+
+            match names.get(register){
+                Some(a) if a <= name => {}
+                _ => {
+                    names.insert(register.clone(), name.clone());
+                }
+            }
+        }
+
+        names
+    }
+
+    fn define(self: &mut Self, directive: &Lexem, args: &[Lexem]) -> Result<(), Diagnostic>{
+        if args.len() != 2 || args[0].ttype != LexemType::Ident || args[1].ttype != LexemType::Ident{
+            return Err(Diagnostic::at(directive, format!("{} expects name and register", directive.value)));
+        }
+
+        let name = args[0].value.to_lowercase();
+        let target = args[1].value.to_lowercase();
+
+        if REGISTERS_TO_VAL.contains_key(name.as_str()){
+            return Err(Diagnostic::at(&args[0], format!("Can't alias register {}", args[0].value)));
+        }
+
+        let register = match REGISTERS_TO_VAL.contains_key(target.as_str()){
+            true => target,
+            false => match self.names.get(&target){
+                Some(a) => a.clone(),
+                None => {
+                    return Err(Diagnostic::at(&args[1], format!("Expected register got {}", args[1].value)));
+                }
+            }
+        };
+
+        self.names.insert(name, register);
+
+        Ok(())
+    }
+
+    // definitions from a project file made only of alias directives
+    pub fn load(self: &mut Self, filename: &str, content: &str) -> Result<(), Diagnostic>{
+        let mut lexer: Lexer = Lexer::new();
+        lexer.lex(filename, content)?;
+
+        let mut parser: Parser = Parser::new();
+        parser.first_stage_parse(&lexer.lexems)?;
+
+        for token in parser.tokens.iter(){
+            match token{
+                Token::Instruction { name, args } if is_alias_directive(&name.value.to_lowercase()) => {
+                    self.define(name, args)?;
+                }
+                Token::Instruction { name, .. } | Token::Label { name } => {
+                    return Err(Diagnostic::at(name, format!("Expected alias got {}", name.value)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn resolve(self: &mut Self, tokens: &[Token]) -> Result<Vec<Token>, Diagnostic>{
+        let mut resolved: Vec<Token> = Vec::new();
+        let labels = label_names(tokens);
+
+        self.diagnostics.clear();
+        self.written.clear();
+
+        for token in tokens{
+            match token{
+                Token::Instruction { name, args } => {
+                    let directive = name.value.to_lowercase();
+
+                    if is_alias_directive(&directive){
+                        self.define(name, args)?;
+                        continue;
+                    }
+
+                    if is_data_directive(&directive){
+                        resolved.push(token.clone());
+                        continue;
+                    }
+
+                    let mut args = args.clone();
+                    for arg in args.iter_mut(){
+                        let register = match (&arg.ttype, self.get(&arg.value)){
+                            (LexemType::Ident, Some(register)) if !labels.contains_key(&arg.value) => register.clone(),
+                            _ => continue
+                        };
+                        self.written.entry((arg.filename.clone(), arg.row, arg.col)).or_insert((register.clone(), arg.value.clone()));
+                        *arg = Lexem::new(register, LexemType::Ident, arg.row, arg.col, arg.filename.clone());
+                    }
+
+                    resolved.push(Token::Instruction { name: name.clone(), args });
+                }
+                Token::Label { .. } => resolved.push(token.clone())
+            }
+        }
+
+        let mut shared: Vec<&Lexem> = labels.values().filter(|label| self.get(&label.value).is_some()).collect();
+        shared.sort_by_key(|label| (label.row, label.col));
+
+        for label in shared{
+            self.diagnostics.push(Diagnostic::warning("alias-label", label, format!("label {} has the name of a register alias, operands named {} mean the label", label.value, label.value)));
+        }
+
+        Ok(resolved)
+    }
+}
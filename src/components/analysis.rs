@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use crate::{instruction_form, CodeGen, Diagnostic, InstructionPart, Lexem, Lexer, Parser, Warnings, CONDITIONS_TO_VAL, INSTRUCTIONS, OVERLOADS, PSEUDO_INSTRUCTIONS, PSEUDO_INSTRUCTION_DOCS, REGISTERS_TO_VAL, VARIADIC_PSEUDO_INSTRUCTIONS};
+
+// What an editor asks about one source file, positions are 1-based rows and columns like lexems
+// the last source that parsed is kept, so labels can still be found while the file is being edited
+
+pub const DIRECTIVES: [&str; 23] = [
+    "org", "db", "dw", "dd", "dq", "struct", "field", "endstruct", "mmio", "scope", "endscope", "proc", "endp",
+    "rept", "irp", "while", "endr", "endw", "alias", "reg", "pragma", "export", "entry"
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompletionKind{
+    Instruction,
+    Directive,
+    Register,
+    Condition,
+    Label
+}
+
+fn is_word_char(ch: char) -> bool{
+    ch.is_alphanumeric() || ch == '.' || ch == '@'
+}
+
+pub struct Analysis{
+    lines: Vec<String>,
+    pub diagnostics: Vec<Diagnostic>,
+    // full name -> where it is defined
+    pub definitions: Vec<(String, Lexem)>,
+    pub references: Vec<(String, Lexem)>,
+    pub labels: HashMap<String, usize>,
+    pub aliases: HashMap<String, String>
+}
+
+impl Analysis{
+    pub fn new() -> Analysis{
+        Analysis{
+            lines: Vec::new(),
+            diagnostics: Vec::new(),
+            definitions: Vec::new(),
+            references: Vec::new(),
+            labels: HashMap::new(),
+            aliases: HashMap::new()
+        }
+    }
+
+    pub fn analyze(self: &mut Self, filename: &str, content: &str, instructions: &HashMap<&'static str, Vec<InstructionPart>>){
+        self.lines = content.lines().map(|line| line.to_string()).collect();
+        self.diagnostics.clear();
+
+        let mut lexer: Lexer = Lexer::new();
+        if let Err(e) = lexer.lex(filename, content){
+            self.diagnostics.push(e);
+            return;
+        }
+
+        let mut parser: Parser = Parser::new();
+        let parsed = parser.parse(&lexer.lexems);
+
+        let mut warnings: Warnings = Warnings::new();
+        warnings.add_pragmas(&parser.pragmas);
+
+        let mut diagnostics: Vec<Diagnostic> = lexer.diagnostics.clone();
+
+        match parsed{
+            Ok(()) => {
+                diagnostics.extend_from_slice(&parser.diagnostics);
+
+                let mut codegen: CodeGen = CodeGen::new(&parser.tokens, instructions);
+                codegen.operands = lexer.tree.operands();
+                match codegen.gen(){
+                    Ok(()) => diagnostics.extend_from_slice(&codegen.diagnostics),
+                    Err(e) => diagnostics.push(e)
+                }
+
+                self.definitions = parser.label_definitions();
+                self.references = parser.label_references.clone();
+                self.labels = parser.labels.clone();
+                self.aliases = parser.aliases.names.clone();
+            }
+            Err(e) => diagnostics.push(e)
+        }
+
+        self.diagnostics = diagnostics.into_iter().filter(|diagnostic| warnings.is_enabled(diagnostic)).collect();
+    }
+
+    // word under row, col and the column it starts at
+    pub fn word_at(self: &Self, row: usize, col: usize) -> Option<(String, usize)>{
+        let line: Vec<char> = self.lines.get(row.wrapping_sub(1))?.chars().collect();
+
+        let mut start = col.saturating_sub(1).min(line.len());
+        while start > 0 && is_word_char(line[start-1]){
+            start -= 1;
+        }
+
+        let mut end = start;
+        while end < line.len() && is_word_char(line[end]){
+            end += 1;
+        }
+
+        if start == end{
+            return None;
+        }
+
+        Some((line[start..end].iter().collect(), start + 1))
+    }
+
+    // length of the word lexem starts, operands hold the resolved name so the source is asked
+    pub fn length(self: &Self, lexem: &Lexem) -> usize{
+        match self.word_at(lexem.row, lexem.col){
+            Some((word, start)) if start == lexem.col => word.chars().count(),
+            _ => lexem.value.chars().count().max(1)
+        }
+    }
+
+    fn covers(self: &Self, lexem: &Lexem, row: usize, col: usize) -> bool{
+        lexem.row == row && lexem.col <= col && col < lexem.col + self.length(lexem)
+    }
+
+    // full name of the label defined or referenced at row, col
+    pub fn symbol_at(self: &Self, row: usize, col: usize) -> Option<String>{
+        self.definitions.iter().chain(self.references.iter()).find(|(_, lexem)| self.covers(lexem, row, col)).map(|(name, _)| name.clone())
+    }
+
+    pub fn definition(self: &Self, name: &str) -> Option<&Lexem>{
+        self.definitions.iter().find(|(defined, _)| defined == name).map(|(_, lexem)| lexem)
+    }
+
+    pub fn references_to(self: &Self, name: &str) -> Vec<&Lexem>{
+        self.references.iter().filter(|(referenced, _)| referenced == name).map(|(_, lexem)| lexem).collect()
+    }
+
+    // instructions and directives come first on a line, after an optional label
+    fn is_mnemonic_position(self: &Self, row: usize, col: usize) -> bool{
+        let line = match self.lines.get(row.wrapping_sub(1)){
+            Some(a) => a,
+            None => return true
+        };
+
+        let before: String = line.chars().take(col.saturating_sub(1)).collect();
+        let before = match before.rfind(':'){
+            Some(i) => &before[i+1..],
+            None => before.as_str()
+        };
+
+        !before.trim_start().contains(|ch: char| !is_word_char(ch))
+    }
+
+    fn instruction_hover(self: &Self, mnemonic: &str, instructions: &HashMap<&'static str, Vec<InstructionPart>>) -> Option<String>{
+        let mnemonic = mnemonic.split('.').next().unwrap_or("");
+
+        if let Some(doc) = PSEUDO_INSTRUCTION_DOCS.get(mnemonic){
+            return Some(format!("pseudo instruction `{}`\n\n{}", mnemonic, doc));
+        }
+
+        let candidates: Vec<&str> = match OVERLOADS.get(mnemonic){
+            Some(a) => a.to_vec(),
+            None if INSTRUCTIONS.contains_key(mnemonic) => vec![mnemonic],
+            None => return None
+        };
+
+        let mut text = String::from("```\n");
+        for candidate in candidates.iter(){
+            let form = instructions.get(candidate).map(|parts| instruction_form(candidate, parts)).unwrap_or_else(|| candidate.to_string());
+            text += format!("{:<24} {}\n", form, INSTRUCTIONS.get(candidate).unwrap_or(&"")).as_str();
+        }
+        text += "```";
+
+        Some(text)
+    }
+
+    pub fn hover(self: &Self, row: usize, col: usize, instructions: &HashMap<&'static str, Vec<InstructionPart>>) -> Option<String>{
+        if let Some(name) = self.symbol_at(row, col){
+            let display = name.split('#').next().unwrap_or("");
+            return match self.labels.get(&name){
+                Some(address) => Some(format!("`{}` = 0x{:04X} ({})", display, address, address)),
+                None => Some(format!("`{}`", display))
+            };
+        }
+
+        let (word, _) = self.word_at(row, col)?;
+        let lower = word.to_lowercase();
+
+        if self.is_mnemonic_position(row, col){
+            return self.instruction_hover(&lower, instructions);
+        }
+
+        if let Some(register) = self.aliases.get(&lower){
+            return Some(format!("`{}` = {}", word, register));
+        }
+
+        if let Some(value) = CONDITIONS_TO_VAL.get(lower.as_str()){
+            return Some(format!("condition `{}` = 0b{:04b}", word, value));
+        }
+
+        if let Some(address) = self.labels.get(&word){
+            return Some(format!("`{}` = 0x{:04X} ({})", word, address, address));
+        }
+
+        None
+    }
+
+    pub fn completions(self: &Self, row: usize, col: usize) -> Vec<(String, CompletionKind)>{
+        let mut items: Vec<(String, CompletionKind)> = Vec::new();
+
+        if self.is_mnemonic_position(row, col){
+            items.extend(INSTRUCTIONS.keys().map(|name| (name.to_string(), CompletionKind::Instruction)));
+            items.extend(PSEUDO_INSTRUCTIONS.keys().map(|name| (name.split(' ').next().unwrap_or("").to_string(), CompletionKind::Instruction)));
+            items.extend(VARIADIC_PSEUDO_INSTRUCTIONS.keys().map(|name| (name.to_string(), CompletionKind::Instruction)));
+            items.extend(DIRECTIVES.iter().map(|name| (name.to_string(), CompletionKind::Directive)));
+        }else{
+            items.extend(REGISTERS_TO_VAL.keys().map(|name| (name.to_string(), CompletionKind::Register)));
+            items.extend(self.aliases.keys().map(|name| (name.clone(), CompletionKind::Register)));
+            items.extend(CONDITIONS_TO_VAL.keys().map(|name| (name.to_string(), CompletionKind::Condition)));
+            items.extend(self.definitions.iter().filter(|(name, _)| !name.contains('#')).map(|(name, _)| (name.clone(), CompletionKind::Label)));
+        }
+
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        items.dedup_by(|a, b| a.0 == b.0);
+
+        items
+    }
+
+    // labels and procs in the order they are defined, with their address
+    pub fn symbols(self: &Self) -> Vec<(String, &Lexem, Option<usize>)>{
+        let mut symbols: Vec<(String, &Lexem, Option<usize>)> = self.definitions.iter().filter(|(name, _)| !name.contains('#')).map(|(name, lexem)| (name.clone(), lexem, self.labels.get(name).copied())).collect();
+        symbols.sort_by_key(|(_, lexem, _)| (lexem.row, lexem.col));
+
+        symbols
+    }
+}
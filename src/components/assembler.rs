@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use crate::{Aliases, CallingConventions, CodeGen, ControlFlowGraph, DeadCode, Diagnostic, Docs, InstructionPart, Lexer, Parser, Peephole, StackUsage};
+
+// Everything zasm does with a source file short of writing the output
+// lex, parse (twice with --strip-dead), generate code and run the analyses on the control flow graph.
+// Errors are returned instead of ending the process, so tests and tools can drive it
+
+pub struct Assembler<'a>{
+    instructions: &'a HashMap<&'static str, Vec<InstructionPart>>,
+    pub aliases: Aliases,
+    pub peephole: Peephole,
+    pub strip_dead: bool,
+    // stack use is only analyzed when asked for
+    pub stack_usage: bool,
+    pub stack_size: Option<usize>,
+    pub lexer: Lexer,
+    pub parser: Parser,
+    pub bytes: Vec<u8>,
+    // bytes of every token
+    pub spans: Vec<(usize, usize)>,
+    // every pass, Warnings decides which are shown
+    pub diagnostics: Vec<Diagnostic>,
+    pub stack_report: String
+}
+
+impl Assembler<'_>{
+    pub fn new<'a>(instructions: &'a HashMap<&'static str, Vec<InstructionPart>>) -> Assembler<'a>{
+        Assembler{
+            instructions,
+            aliases: Aliases::new(),
+            peephole: Peephole::new(),
+            strip_dead: false,
+            stack_usage: false,
+            stack_size: None,
+            lexer: Lexer::new(),
+            parser: Parser::new(),
+            bytes: Vec::new(),
+            spans: Vec::new(),
+            diagnostics: Vec::new(),
+            stack_report: String::new()
+        }
+    }
+
+    fn parse(self: &Self, peephole: Peephole) -> Result<Parser, Diagnostic>{
+        let mut parser: Parser = Parser::new();
+
+        parser.aliases = self.aliases.clone();
+        parser.peephole = peephole;
+
+        parser.parse(&self.lexer.lexems)?;
+
+        Ok(parser)
+    }
+
+    // instructions of routines nothing reaches, by index before labels and directives were removed
+    fn dead_tokens(self: &Self, parser: &Parser) -> Result<Vec<usize>, Diagnostic>{
+        let mut codegen: CodeGen = CodeGen::new(&parser.tokens, self.instructions);
+        codegen.operands = self.lexer.tree.operands();
+        codegen.gen()?;
+
+        let mut cfg: ControlFlowGraph = ControlFlowGraph::new(&parser.tokens, &codegen.spans, &parser.code_labels);
+        cfg.build();
+
+        let entries: Vec<usize> = parser.entry_labels.iter().map(|(_, address)| *address).collect();
+
+        let mut dead_code: DeadCode = DeadCode::new(&cfg, &parser.tokens);
+        dead_code.analyze(&entries, &parser.referenced_addresses());
+
+        Ok(dead_code.dead_tokens().iter().map(|i| parser.origins()[*i]).collect())
+    }
+
+    pub fn assemble(self: &mut Self, filename: &str, content: &str) -> Result<(), Diagnostic>{
+        self.lexer = Lexer::new();
+        self.lexer.lex(filename, content)?;
+
+        let mut parser = self.parse(self.peephole.clone())?;
+
+        // dead routines are only known once everything is laid out, assemble again without them
+        if self.strip_dead{
+            let dead = self.dead_tokens(&parser)?;
+
+            if !dead.is_empty(){
+                let mut stripped = self.peephole.clone();
+                stripped.strip(&dead);
+                parser = self.parse(stripped)?;
+            }
+        }
+
+        let mut codegen: CodeGen = CodeGen::new(&parser.tokens, self.instructions);
+        codegen.operands = self.lexer.tree.operands();
+        codegen.gen()?;
+
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        diagnostics.extend_from_slice(&self.lexer.diagnostics);
+        diagnostics.extend_from_slice(&parser.diagnostics);
+        diagnostics.extend_from_slice(&codegen.diagnostics);
+
+        let mut cfg: ControlFlowGraph = ControlFlowGraph::new(&parser.tokens, &codegen.spans, &parser.code_labels);
+        cfg.build();
+
+        let entries: Vec<usize> = parser.entry_labels.iter().map(|(_, address)| *address).collect();
+
+        let mut dead_code: DeadCode = DeadCode::new(&cfg, &parser.tokens);
+        dead_code.analyze(&entries, &parser.referenced_addresses());
+        dead_code.report();
+
+        diagnostics.extend_from_slice(&dead_code.diagnostics);
+
+        // doc comment problems are left to zasm doc, only the annotations are needed here
+        let mut docs: Docs = Docs::new();
+        docs.extract(&self.lexer.tree, filename, &parser.label_definitions(), &parser.labels, codegen.bytes.len() / 2);
+
+        let mut conventions: CallingConventions = CallingConventions::new(&cfg, &parser.tokens);
+        conventions.register_names = parser.aliases.user_register_names();
+        conventions.written = parser.aliases.written.clone();
+        conventions.declare_docs(&docs.routines, &parser.aliases);
+        conventions.declare_uses(&parser.proc_uses, &parser.labels);
+        conventions.analyze();
+
+        diagnostics.extend_from_slice(&conventions.diagnostics);
+
+        self.stack_report.clear();
+
+        if self.stack_usage || self.stack_size.is_some(){
+            let mut stack_usage: StackUsage = StackUsage::new(&cfg, &parser.tokens);
+            stack_usage.analyze();
+
+            if let Some(size) = self.stack_size{
+                stack_usage.check(size);
+            }
+
+            self.stack_report = stack_usage.report(self.stack_size);
+
+            diagnostics.extend_from_slice(&stack_usage.diagnostics);
+        }
+
+        // in source order, each stage reports in its own order (stable, so one position keeps the stage order)
+        diagnostics.sort_by(|a, b| (&a.filename, a.row, a.col).cmp(&(&b.filename, b.row, b.col)));
+
+        self.bytes = codegen.bytes.clone();
+        self.spans = codegen.spans.clone();
+        self.diagnostics = diagnostics;
+        self.parser = parser;
+
+        Ok(())
+    }
+}
@@ -1,22 +1,103 @@
 use std::collections::HashMap;
 
-use crate::{InstructionPart, Lexem, LexemType, Token, TYPES};
+use crate::{Diagnostic, InstructionPart, Lexem, LexemType, Token, EXTRA_FIELDS, OVERLOADS, TYPES};
 
 #[derive(Debug)]
 pub struct CodeGen<'a>{
     tokens: &'a[Token],
     instruction_set: &'a HashMap<&'static str,Vec<InstructionPart>>,
-    pub bytes: Vec<u8>
+    pub bytes: Vec<u8>,
+    // byte range every token was encoded into
+    pub spans: Vec<(usize, usize)>,
+    // SyntaxTree::operands, messages show operands as written instead of resolved values
+    pub operands: HashMap<(String, usize, usize), String>,
+    pub diagnostics: Vec<Diagnostic>
 }
 
-pub fn get_value_from_number_token<'a>(lexem: &Lexem) -> usize{
+pub fn get_value_from_number_token(lexem: &Lexem) -> Result<usize, Diagnostic>{
     match lexem.ttype{
-        LexemType::Number { radix } => {usize::from_str_radix(&lexem.value, radix as u32).unwrap()}
+        LexemType::Number { radix } => {
+            match usize::from_str_radix(&lexem.value, radix as u32){
+                Ok(a) => Ok(a),
+                Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => Err(Diagnostic::at(lexem, format!("Number is too big {}", lexem.value))),
+                Err(_) => Err(Diagnostic::at(lexem, format!("Invalid number {}", lexem.value)))
+            }
+        }
         _ => {
-            println!("{}:{}:{} Expected number got {}", lexem.filename, lexem.row, lexem.col,lexem.ttype);
-            std::process::exit(1);
+            return Err(Diagnostic::at(lexem, format!("Expected number got {}", lexem.ttype)));
+        }
+    }
+}
+
+// value of a named extra field, `lexem` is only used for error location
+pub fn extra_field_value(mnemonic: &str, modifier: &str, lexem: &Lexem) -> Result<usize, Diagnostic>{
+    let modifiers = EXTRA_FIELDS.get(mnemonic);
+
+    match modifiers.and_then(|a| a.get(modifier.to_lowercase().as_str())){
+        Some(a) => Ok(*a),
+        None => {
+            let mut names: Vec<&str> = match modifiers{
+                Some(a) => a.keys().copied().collect(),
+                None => Vec::new()
+            };
+            names.sort();
+
+            if names.is_empty(){
+                return Err(Diagnostic::at(lexem, format!("{} has no named extra fields", mnemonic)));
+            }
+
+            Err(Diagnostic::at(lexem, format!("{} has no extra field {}, expected one of {}", mnemonic, modifier, names.join(", "))))
+        }
+    }
+}
+
+// whether arg could be encoded into part, labels left unresolved still count as immediates
+fn operand_fits(encoding: &str, part: &InstructionPart, arg: &Lexem) -> bool{
+    let is_type_value = |ident: &str| TYPES.values().any(|a| a.contains_key(ident));
+
+    match (part, &arg.ttype){
+        (InstructionPart::Type { val, .. }, LexemType::Ident) => {
+            TYPES.get(val.as_str()).is_some_and(|a| a.contains_key(arg.value.to_lowercase().as_str()))
+        }
+        (InstructionPart::Imm { .. }, LexemType::Number { .. }) => true,
+        (InstructionPart::Imm { .. }, LexemType::Ident) => !is_type_value(&arg.value.to_lowercase()),
+        (InstructionPart::Extra { .. }, LexemType::Number { .. }) => true,
+        (InstructionPart::Extra { .. }, LexemType::Ident) => {
+            EXTRA_FIELDS.get(encoding).is_some_and(|a| a.contains_key(arg.value.to_lowercase().as_str()))
+        }
+        _ => false
+    }
+}
+
+// add reg, reg[, extra]
+pub fn instruction_form(encoding: &str, parts: &[InstructionPart]) -> String{
+    let mut operands: Vec<String> = Vec::new();
+    let mut extra = false;
+
+    for part in parts{
+        match part{
+            InstructionPart::Const { .. } => {}
+            InstructionPart::Imm { size } => operands.push(format!("imm{}", size)),
+            InstructionPart::Type { val, .. } => operands.push(match val.as_str(){
+                "R" => "reg".to_string(),
+                "C" => "cond".to_string(),
+                "SR" => "sreg".to_string(),
+                _ => val.to_lowercase()
+            }),
+            InstructionPart::Extra { .. } => extra = true
         }
     }
+
+    let mut form = encoding.to_string();
+    if !operands.is_empty(){
+        form += " ";
+        form += operands.join(", ").as_str();
+    }
+    if extra{
+        form += if operands.is_empty() { " [extra]" } else { "[, extra]" };
+    }
+
+    form
 }
 
 impl CodeGen<'_>{
@@ -24,58 +105,144 @@ impl CodeGen<'_>{
         CodeGen{
             tokens,
             instruction_set,
-            bytes: Vec::new()
+            bytes: Vec::new(),
+            spans: Vec::new(),
+            operands: HashMap::new(),
+            diagnostics: Vec::new()
         }
     }
 
-    pub fn str_to_bytes(self: &Self, str: &String) -> [u8; 2]{
+    pub fn str_to_bytes(self: &Self, str: &str) -> [u8; 2]{
         let ret: usize = usize::from_str_radix(str, 2).unwrap();
         (ret as u16).to_be_bytes()
     }
 
-    pub fn gen(self: &mut Self){
+    // picks the encoding of mnemonic whose operand kinds and count match args
+    fn select_encoding(self: &Self, name: &Lexem, mnemonic: &str, modifier: Option<&str>, args: &[Lexem]) -> Result<&'static str, Diagnostic>{
+        let candidates: Vec<&'static str> = match OVERLOADS.get(mnemonic){
+            Some(a) => a.to_vec(),
+            None => match self.instruction_set.get_key_value(mnemonic){
+                Some((a, _)) => vec![*a],
+                None => {
+                    return Err(Diagnostic::at(name, format!("Unknown instruction {}", name.value)));
+                }
+            }
+        };
+
+        let mut forms: Vec<String> = Vec::new();
+
+        for encoding in candidates.iter(){
+            let parts = match self.instruction_set.get(encoding){
+                Some(a) => a,
+                None => {
+                    return Err(Diagnostic::at(name, format!("Overload {} of {} isn't an instruction", encoding, mnemonic)));
+                }
+            };
+
+            let has_extra = parts.iter().any(|part| matches!(part, InstructionPart::Extra { .. }));
+
+            if modifier.is_some() && !has_extra{
+                continue;
+            }
+
+            forms.push(instruction_form(encoding, parts));
+
+            let mut args = args.iter();
+            let mut fits = true;
+
+            for part in parts{
+                match part{
+                    InstructionPart::Const { .. } => {}
+                    // the extra operand is optional, and given by the suffix when there is one
+                    InstructionPart::Extra { .. } => {
+                        if modifier.is_none(){
+                            if let Some(arg) = args.next(){
+                                fits &= operand_fits(encoding, part, arg);
+                            }
+                        }
+                    }
+                    _ => {
+                        fits &= match args.next(){
+                            Some(arg) => operand_fits(encoding, part, arg),
+                            None => false
+                        };
+                    }
+                }
+            }
+
+            if fits && args.next().is_none(){
+                return Ok(encoding);
+            }
+        }
+
+        if forms.is_empty(){
+            return Err(Diagnostic::at(name, format!("{} has no extra field", mnemonic)));
+        }
+
+        let given = args.iter().map(|arg| {
+            match self.operands.get(&(arg.filename.clone(), arg.row, arg.col)){
+                Some(written) => written.clone(),
+                None => arg.value.clone()
+            }
+        }).collect::<Vec<String>>().join(", ");
+
+        let location = match args.first(){
+            Some(a) => a,
+            None => name
+        };
+
+        Err(Diagnostic::at(location, format!("Invalid operands \"{}\" for {}, expected {}", given, name.value, forms.join(" or "))))
+    }
+
+    pub fn gen(self: &mut Self) -> Result<(), Diagnostic>{
 
         for token in self.tokens.iter(){
+            let start = self.bytes.len();
+
             match token{
                 Token::Instruction { name, args } => {
                     match name.value.as_str(){
                         "org" => {
-                            println!("Org: Error in parser");
-                            std::process::exit(1);
+                            return Err(Diagnostic::at(name, "Org: Error in parser".to_string()));
                         }
 
                         "db" => {
-                            if args.len() == 0{
-                                println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                                std::process::exit(1);
+                            if args.is_empty(){
+                                return Err(Diagnostic::error(&name.filename, name.row, name.col+name.value.len(), "No data was provided".to_string()));
                             }
-                            
-                            todo!()
+
+                            // memory is made of words, there is nothing smaller to put a byte in
+                            return Err(Diagnostic::at(name, "db isn't supported, memory is addressed in words, use dw".to_string()));
                         }
 
                         "dw" => {
-                            if args.len() == 0{
-                                println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                                std::process::exit(1);
+                            if args.is_empty(){
+                                return Err(Diagnostic::error(&name.filename, name.row, name.col+name.value.len(), "No data was provided".to_string()));
                             }
 
                             for arg in args{
                                 match arg.ttype{
-                                    LexemType::Number { radix } => {
-                                        let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFF ) as u16).to_be_bytes();
+                                    LexemType::Number { .. } => {
+                                        let value = get_value_from_number_token(arg)?;
+                                        if value > 0xFFFF{
+                                            self.diagnostics.push(Diagnostic::warning("truncated-data", arg, format!("{} doesn't fit into a word, truncated to {}", value, value & 0xFFFF)));
+                                        }
+                                        let b = ((value & 0xFFFF ) as u16).to_be_bytes();
                                         for b in b{
                                             self.bytes.push(b);
                                         }
                                     },
                                     LexemType::String => {
+                                        if arg.value.chars().any(|ch| ch as u32 > 0xFF){
+                                            self.diagnostics.push(Diagnostic::warning("truncated-data", arg, "String contains characters wider than a byte, truncated".to_string()));
+                                        }
                                         for ch in arg.value.chars(){
                                             self.bytes.push(0);
                                             self.bytes.push(ch as u8);
                                         }
                                     },
                                     _ => {
-                                        println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                        std::process::exit(1);
+                                        return Err(Diagnostic::at(arg, format!("Unexpected lexem {}", arg.ttype)));
                                     }
                                 }
                             }
@@ -83,20 +250,26 @@ impl CodeGen<'_>{
                         }
 
                         "dd" => {
-                            if args.len() == 0{
-                                println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                                std::process::exit(1);
+                            if args.is_empty(){
+                                return Err(Diagnostic::error(&name.filename, name.row, name.col+name.value.len(), "No data was provided".to_string()));
                             }
     
                             for arg in args{
                                 match arg.ttype{
-                                    LexemType::Number { radix } => {
-                                        let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFFFFFF ) as u32).to_be_bytes();
+                                    LexemType::Number { .. } => {
+                                        let value = get_value_from_number_token(arg)?;
+                                        if value > 0xFFFFFFFF{
+                                            self.diagnostics.push(Diagnostic::warning("truncated-data", arg, format!("{} doesn't fit into a double word, truncated to {}", value, value & 0xFFFFFFFF)));
+                                        }
+                                        let b = ((value & 0xFFFFFFFF ) as u32).to_be_bytes();
                                         for b in b{
                                             self.bytes.push(b);
                                         }
                                     },
                                     LexemType::String => {
+                                        if arg.value.chars().any(|ch| ch as u32 > 0xFF){
+                                            self.diagnostics.push(Diagnostic::warning("truncated-data", arg, "String contains characters wider than a byte, truncated".to_string()));
+                                        }
                                         for ch in arg.value.chars(){
                                             self.bytes.push(0);
                                             self.bytes.push(0);
@@ -105,23 +278,21 @@ impl CodeGen<'_>{
                                         }
                                     },
                                     _ => {
-                                        println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                        std::process::exit(1);
+                                        return Err(Diagnostic::at(arg, format!("Unexpected lexem {}", arg.ttype)));
                                     }
                                 }
                             }
                         }
 
                         "dq" => {
-                            if args.len() == 0{
-                                println!("{}:{}:{} No data was provided", name.filename, name.row, name.col+name.value.len());
-                                std::process::exit(1);
+                            if args.is_empty(){
+                                return Err(Diagnostic::error(&name.filename, name.row, name.col+name.value.len(), "No data was provided".to_string()));
                             }
 
                             for arg in args{
                                 match arg.ttype{
-                                    LexemType::Number { radix } => {
-                                        let b = ((usize::from_str_radix(&arg.value, radix as u32).unwrap() & 0xFFFFFFFFFFFFFFFF ) as u32).to_be_bytes();
+                                    LexemType::Number { .. } => {
+                                        let b = (get_value_from_number_token(arg)? as u64).to_be_bytes();
                                         for b in b{
                                             self.bytes.push(b);
                                         }
@@ -139,8 +310,7 @@ impl CodeGen<'_>{
                                         }
                                     },
                                     _ => {
-                                        println!("{}:{}:{} Unexpected lexem {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                        std::process::exit(1);
+                                        return Err(Diagnostic::at(arg, format!("Unexpected lexem {}", arg.ttype)));
                                     }
                                 }
                             }
@@ -148,13 +318,15 @@ impl CodeGen<'_>{
                         }
 
                         _ => {
-                            let instruction = match self.instruction_set.get(&name.value.as_str()){
-                                Some(a) => a,
-                                None => {
-                                    println!("{}:{}:{} Unknown instruction {}", name.filename, name.row, name.col, name.value);
-                                    std::process::exit(1);
-                                }
-                            }.as_slice();
+                            // add.c r1, r2 -> add r1, r2, c
+                            let (mnemonic, modifier) = match name.value.split_once('.'){
+                                Some((mnemonic, modifier)) => (mnemonic, Some(modifier)),
+                                None => (name.value.as_str(), None)
+                            };
+
+                            let mnemonic = self.select_encoding(name, mnemonic, modifier, args)?;
+
+                            let instruction = self.instruction_set.get(mnemonic).unwrap().as_slice();
 
                             let mut args = args.clone();
 
@@ -168,22 +340,19 @@ impl CodeGen<'_>{
                                     },
 
                                     InstructionPart::Type { val, size } => {
-                                        if args.len() == 0{
-                                            println!("{}:{}:{} Expected Argument", name.filename, name.row, name.col+name.value.len());
-                                            std::process::exit(1);
+                                        if args.is_empty(){
+                                            return Err(Diagnostic::error(&name.filename, name.row, name.col+name.value.len(), "Expected Argument".to_string()));
                                         }
                                         let arg = args.remove(0);
                                         if !matches!(arg.ttype, LexemType::Ident){
-                                            println!("{}:{}:{} Expected ident got {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                            std::process::exit(1);
+                                            return Err(Diagnostic::at(&arg, format!("Expected ident got {}", arg.ttype)));
                                         }
                                         let type_val = val.to_uppercase();
 
                                         let type_hashmap = match TYPES.get(&type_val){
                                             Some(a) => a,
                                             None => {
-                                                println!("{}:{}:{} following type {} doesn't exist", arg.filename, arg.row, arg.col, type_val);
-                                                std::process::exit(1);
+                                                return Err(Diagnostic::at(&arg, format!("following type {} doesn't exist", type_val)));
                                             }
                                         };
 
@@ -191,8 +360,7 @@ impl CodeGen<'_>{
                                         let val = match type_hashmap.get(arg.value.to_lowercase().as_str()){
                                             Some(a) => a,
                                             None => {
-                                                println!("{}:{}:{} type {} doesn't have {}", arg.filename, arg.row, arg.col, type_val, arg.value);
-                                                std::process::exit(1);
+                                                return Err(Diagnostic::at(&arg, format!("type {} doesn't have {}", type_val, arg.value)));
                                             }
                                         };
 
@@ -203,25 +371,22 @@ impl CodeGen<'_>{
                                     }
                                     
                                     InstructionPart::Imm { size } => {
-                                        if args.len() == 0{
-                                            println!("{}:{}:{} Expected Immediate", name.filename, name.row, name.col+name.value.len());
-                                            std::process::exit(1);
+                                        if args.is_empty(){
+                                            return Err(Diagnostic::error(&name.filename, name.row, name.col+name.value.len(), "Expected Immediate".to_string()));
                                         }
                                         let arg = args.remove(0);
                                         
                                         if arg.ttype == LexemType::Ident{
-                                            println!("{}:{}:{} Use of undeclared label {}", arg.filename, arg.row, arg.col, arg.value);
-                                            std::process::exit(1);
+                                            return Err(Diagnostic::at(&arg, format!("Use of undeclared label {}", arg.value)));
                                         }
 
-                                        let val = get_value_from_number_token(&arg);
+                                        let val = get_value_from_number_token(&arg)?;
                                         
                                         
                                         let val = format!("{:b}", val);
                                         
                                         if val.len() > *size{
-                                            println!("{}:{}:{} Number is too big {}", arg.filename, arg.row, arg.col, arg.value);
-                                            std::process::exit(1);
+                                            return Err(Diagnostic::at(&arg, format!("Number is too big {}", arg.value)));
                                         }
 
                                         bits_str+="0".repeat(*size - val.len()).as_str();
@@ -230,21 +395,28 @@ impl CodeGen<'_>{
                                     }
 
                                     InstructionPart::Extra { size } => {
-                                        if args.len() == 0{
-                                            bits_str += "0".repeat(*size).as_str();
-                                            continue;
-                                        }
-                                        
-                                        let arg = args.remove(0);
-                                        
-                                        let val = get_value_from_number_token(&arg);
-                                        
+                                        let (val, arg) = match modifier{
+                                            Some(modifier) => (extra_field_value(mnemonic, modifier, name)?, name.clone()),
+                                            None => {
+                                                if args.is_empty(){
+                                                    self.diagnostics.push(Diagnostic::warning("implicit-extra", name, format!("{} extra field defaults to 0", name.value)));
+                                                    bits_str += "0".repeat(*size).as_str();
+                                                    continue;
+                                                }
+
+                                                let arg = args.remove(0);
+
+                                                match arg.ttype{
+                                                    LexemType::Ident => (extra_field_value(mnemonic, &arg.value, &arg)?, arg),
+                                                    _ => (get_value_from_number_token(&arg)?, arg)
+                                                }
+                                            }
+                                        };
                                         
                                         let val = format!("{:b}", val);
                                         
                                         if val.len() > *size{
-                                            println!("{}:{}:{} Number is too big {}", arg.filename, arg.row, arg.col, arg.value);
-                                            std::process::exit(1);
+                                            return Err(Diagnostic::at(&arg, format!("Number is too big {}", arg.value)));
                                         }
                                         
                                         bits_str+="0".repeat(*size - val.len()).as_str();
@@ -264,14 +436,15 @@ impl CodeGen<'_>{
                 },
 
                 Token::Label { name } => {
-                    println!("{}:{}:{} Error in parser", name.filename, name.row, name.col);
-                    std::process::exit(1);
+                    return Err(Diagnostic::at(name, "Error in parser".to_string()));
                 }
 
             }
 
+            self.spans.push((start, self.bytes.len()));
         }
 
+        Ok(())
     }
 }
 
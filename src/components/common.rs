@@ -1,4 +1,4 @@
-use phf::phf_map;
+use phf::{phf_map, phf_set};
 
 //format {(type)(count in bits)}
 // possible types:
@@ -31,6 +31,31 @@ pub const REGISTERS_TO_VAL: phf::Map<&'static str, usize> = phf_map!{
     "r15" => 15,
 };
 
+// Calling convention names, usable anywhere a register is
+// more can be added with `alias name, rN` (or `reg name, rN`) and `--abi file`
+pub const ABI_REGISTERS: phf::Map<&'static str, &'static str> = phf_map!{
+    "zero" => "r0",
+    // arguments and return values
+    "a0" => "r1",
+    "a1" => "r2",
+    "a2" => "r3",
+    "a3" => "r4",
+    // temporaries, caller saved
+    "t0" => "r5",
+    "t1" => "r6",
+    "t2" => "r7",
+    "t3" => "r8",
+    // callee saved
+    "s0" => "r9",
+    "s1" => "r10",
+    "s2" => "r11",
+    // assembler scratch used by pseudo instructions
+    "at" => "r12",
+    "ra" => "r13",
+    "fp" => "r14",
+    "sp" => "r15",
+};
+
 pub const SPECIAL_REGISTERS_TO_VAL: phf::Map<&'static str, usize> = phf_map!{
     "sptr" => 0,
 };
@@ -84,6 +109,49 @@ pub const INSTRUCTIONS: phf::Map<&'static str, &'static str> = phf_map!{
     "ret" => "11010 0000 0000 000"
 };
 
+// Mnemonics with several encodings, the first one whose operand kinds match is used
+// add r1, r2 -> add    add r1, 5 -> adi
+pub const OVERLOADS: phf::Map<&'static str, &'static [&'static str]> = phf_map!{
+    "add" => &["add", "adi"],
+    "cmp" => &["cmp", "cmi"],
+    "brc" => &["brc", "bri"],
+};
+
+// Names for {E} field values, usable as a mnemonic suffix (add.c r1, r2)
+// or as the extra operand (add r1, r2, c), plain numbers still work
+// provisional, the meanings aren't checked against the reference emulator yet (see emulator.rs)
+pub const EXTRA_FIELDS: phf::Map<&'static str, phf::Map<&'static str, usize>> = phf_map!{
+    // with carry
+    "add" => phf_map!{ "c" => 1 },
+    // with borrow
+    "sub" => phf_map!{ "c" => 1 },
+    // signed
+    "mul" => phf_map!{ "s" => 1 },
+    // signed, remainder
+    "div" => phf_map!{ "s" => 1, "r" => 2, "sr" => 3 },
+    // right
+    "lsh" => phf_map!{ "r" => 1 },
+    "rot" => phf_map!{ "r" => 1 },
+    // signed, with carry
+    "cmp" => phf_map!{ "s" => 1, "c" => 2, "sc" => 3 },
+    "cmi" => phf_map!{ "s" => 1 },
+    // relative to the branch
+    "brc" => phf_map!{ "r" => 1 },
+    "bri" => phf_map!{ "r" => 1 },
+    "cal" => phf_map!{ "r" => 1 },
+};
+
+// Instructions overwriting their first register operand
+pub const WRITES_REGISTER: phf::Set<&'static str> = phf_set!{
+    "add", "adi", "sub", "mul", "div", "fsg", "and", "nor", "xor",
+    "lsh", "rot", "mov", "lim", "lsr", "pop", "lod"
+};
+
+// The ones above that don't read the old value first
+pub const OVERWRITES_REGISTER: phf::Set<&'static str> = phf_set!{
+    "fsg", "mov", "lim", "lsr", "pop", "lod"
+};
+
 // Closure ops
 // +  add
 // -  subtract
@@ -94,10 +162,66 @@ pub const INSTRUCTIONS: phf::Map<&'static str, &'static str> = phf_map!{
 // ^  bitwise xor
 // << bitshift left
 // >> bitshift right
+// <  less than (1 or 0)
+// >  greater than
+// <= less or equal
+// >= greater or equal
+// == equal
+// != not equal
 
+// lim and brc take their upper bits from the last lui (0 after reset)
+// r12 (at) is the scratch register pseudo instructions are allowed to clobber
 pub const PSEUDO_INSTRUCTIONS: phf::Map<&'static str, &'static str> = phf_map!{
     "limb rd, a" => "
         lui (a >> 6)
         lim rd, (a & 0x3F)
-    "
+    ",
+    "li rd, a" => "
+        lui (a >> 6)
+        lim rd, (a & 0x3F)
+    ",
+    "jmp a" => "
+        lui (a >> 6)
+        lim r12, (a & 0x3F)
+        bri t, r12
+    ",
+    "call a" => "
+        lui (a >> 6)
+        lim r12, (a & 0x3F)
+        cal t, r12
+    ",
+    "nop" => "
+        mov r0, r0
+    ",
+    "not rd" => "
+        nor rd, rd
+    ",
+    "neg rd" => "
+        nor rd, rd
+        adi rd, 1
+    ",
+    "clr rd" => "
+        xor rd, rd
+    ",
+};
+
+// Pseudo instructions taking any amount of registers, the instruction is repeated for each one
+// name => (instruction, in reverse order)
+pub const VARIADIC_PSEUDO_INSTRUCTIONS: phf::Map<&'static str, (&'static str, bool)> = phf_map!{
+    "push" => ("psh", false),
+    "pop" => ("pop", true),
+};
+
+// Shown in the generated pseudo instruction reference (zasm --pseudo-reference)
+pub const PSEUDO_INSTRUCTION_DOCS: phf::Map<&'static str, &'static str> = phf_map!{
+    "limb" => "Loads a full 16-bit value into `rd` with the two words below, the `lui` is dropped when the same upper bits are already loaded (unless `--no-lui-elide`).",
+    "li" => "Loads a full 16-bit value into `rd` with the shortest sequence once label addresses are known: `lim` alone when the upper bits are already loaded, otherwise the two words below. Never changes the flags. Only straight-line code since the last label or call is taken into account.",
+    "jmp" => "Jumps anywhere in memory through `at` (r12).",
+    "call" => "Calls a routine anywhere in memory through `at` (r12).",
+    "nop" => "Does nothing for one word.",
+    "not" => "Bitwise not of `rd`.",
+    "neg" => "Two's complement negation of `rd`.",
+    "clr" => "Sets `rd` to zero.",
+    "push" => "Pushes any amount of registers in order.",
+    "pop" => "Pops any amount of registers in reverse order, so `pop r1, r2, r3` undoes `push r1, r2, r3`.",
 };
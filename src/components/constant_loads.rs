@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use crate::{constant_value, Diagnostic, Lexem, LexemType, Token, WRITES_REGISTER};
+
+// Size-optimized constant loads
+// li rd, a becomes the shortest of
+//   lim rd, a & 0x3F                 the upper bits of a are already loaded
+//   lui a >> 6; lim rd, a & 0x3F
+// which depends on label addresses, so the layout is repeated until no li grows.
+// Only forms that leave the flags alone are used, a load mustn't change what a later branch sees
+
+pub const CONSTANT_LOAD: &str = "li";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadForm{
+    Lim,
+    LuiLim
+}
+
+impl LoadForm{
+    pub fn size(self: &Self) -> usize{
+        match self{
+            LoadForm::Lim => 1,
+            LoadForm::LuiLim => 2
+        }
+    }
+}
+
+pub fn is_constant_load(token: &Token) -> bool{
+    matches!(token, Token::Instruction { name, .. } if name.value == CONSTANT_LOAD)
+}
+
+// control can arrive here from somewhere else
+pub fn is_entry(token: &Token) -> bool{
+    match token{
+        Token::Label { .. } => true,
+        Token::Instruction { name, .. } => matches!(name.value.to_lowercase().as_str(), "proc" | "org")
+    }
+}
+
+// What is known about the machine in straight-line code
+#[derive(Debug, Clone)]
+pub struct MachineState{
+    // upper bits loaded by the last lui, 0 after reset
+    pub latch: Option<usize>,
+    pub registers: HashMap<String, usize>
+}
+
+impl MachineState{
+    pub fn new() -> MachineState{
+        MachineState{
+            latch: Some(0),
+            registers: HashMap::new()
+        }
+    }
+
+    pub fn invalidate(self: &mut Self){
+        self.latch = None;
+        self.registers.clear();
+    }
+
+    // shortest way to get value into rd
+    pub fn choose(self: &Self, value: usize) -> LoadForm{
+        if self.latch == Some(value >> 6){
+            return LoadForm::Lim;
+        }
+
+        LoadForm::LuiLim
+    }
+
+    pub fn load(self: &mut Self, rd: &str, value: usize, form: &LoadForm){
+        if *form == LoadForm::LuiLim{
+            self.latch = Some(value >> 6);
+        }
+        self.registers.insert(rd.to_string(), value);
+    }
+
+    pub fn step(self: &mut Self, token: &Token){
+        let (name, args) = match token{
+            Token::Instruction { name, args } => (name, args),
+            Token::Label { .. } => {
+                self.invalidate();
+                return;
+            }
+        };
+
+        let mnemonic = name.value.to_lowercase();
+        let mnemonic = mnemonic.split('.').next().unwrap_or("");
+
+        match mnemonic{
+            "lui" => {
+                self.latch = args.first().and_then(constant_value);
+            }
+            "cal" => {
+                self.invalidate();
+            }
+            "lim" if args.len() == 2 => {
+                let rd = args[0].value.to_lowercase();
+                match (self.latch, constant_value(&args[1])){
+                    (Some(latch), Some(imm)) => {
+                        self.registers.insert(rd, (latch << 6) | imm);
+                    }
+                    _ => {
+                        self.registers.remove(&rd);
+                    }
+                }
+            }
+            _ => {
+                if WRITES_REGISTER.contains(mnemonic){
+                    if let Some(rd) = args.first(){
+                        self.registers.remove(&rd.value.to_lowercase());
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct ConstantLoads{
+    // token index before labels are removed -> words reserved
+    sizes: HashMap<usize, usize>,
+    forms: HashMap<usize, (LoadForm, usize)>
+}
+
+impl ConstantLoads{
+    pub fn new() -> ConstantLoads{
+        ConstantLoads{
+            sizes: HashMap::new(),
+            forms: HashMap::new()
+        }
+    }
+
+    pub fn size(self: &Self, origin: usize) -> usize{
+        *self.sizes.get(&origin).unwrap_or(&1)
+    }
+
+    // form and value picked by the last grow
+    pub fn form(self: &Self, origin: usize) -> Option<&(LoadForm, usize)>{
+        self.forms.get(&origin)
+    }
+
+    // picks a form for every li given resolved tokens, returns true when one needs more room than it had
+    // sizes only grow so the layout always settles
+    pub fn grow(self: &mut Self, tokens: &[Token], origins: &[usize], source: &[Token]) -> Result<bool, Diagnostic>{
+        let mut state = MachineState::new();
+        let mut grew = false;
+        let mut from = 0;
+
+        self.forms.clear();
+
+        for (token, origin) in tokens.iter().zip(origins.iter()){
+            if source[from..*origin].iter().any(is_entry){
+                state.invalidate();
+            }
+            from = origin + 1;
+
+            let args = match token{
+                Token::Instruction { args, .. } if is_constant_load(token) && args.len() == 2 => args,
+                _ => {
+                    state.step(token);
+                    continue;
+                }
+            };
+
+            let rd = args[0].value.to_lowercase();
+
+            let value = match constant_value(&args[1]){
+                Some(a) if a > 0xFFFF => {
+                    return Err(Diagnostic::at(&args[1], format!("Number is too big {}", a)));
+                }
+                Some(a) => a,
+                None => {
+                    state.registers.remove(&rd);
+                    state.latch = None;
+                    continue;
+                }
+            };
+
+            let mut form = state.choose(value);
+
+            if form.size() > self.size(*origin){
+                self.sizes.insert(*origin, form.size());
+                grew = true;
+            }
+            if form.size() < self.size(*origin){
+                form = LoadForm::LuiLim;
+            }
+
+            state.load(&rd, value, &form);
+            self.forms.insert(*origin, (form, value));
+        }
+
+        Ok(grew)
+    }
+
+    // replaces every li with the form picked by the last grow
+    pub fn expand(self: &Self, tokens: &[Token], origins: &[usize]) -> Result<(Vec<Token>, Vec<usize>), Diagnostic>{
+        let mut expanded: Vec<Token> = Vec::new();
+        let mut expanded_origins: Vec<usize> = Vec::new();
+
+        for (token, origin) in tokens.iter().zip(origins.iter()){
+            let (name, args) = match token{
+                Token::Instruction { name, args } if is_constant_load(token) => (name, args),
+                _ => {
+                    expanded.push(token.clone());
+                    expanded_origins.push(*origin);
+                    continue;
+                }
+            };
+
+            let (form, value) = match self.forms.get(origin){
+                Some(a) => a,
+                None => {
+                    let arg = args.get(1).unwrap_or(name);
+                    return Err(Diagnostic::at(arg, format!("Use of undeclared label {}", arg.value)));
+                }
+            };
+
+            let instruction = |mnemonic: &str, args: Vec<Lexem>| Token::Instruction {
+                name: Lexem::new(mnemonic.to_string(), LexemType::Ident, name.row, name.col, name.filename.clone()),
+                args
+            };
+            let number = |value: usize| Lexem::new(format!("{}", value), LexemType::Number { radix: 10 }, name.row, name.col, name.filename.clone());
+
+            let rd = args[0].clone();
+
+            let mut load = match form{
+                LoadForm::Lim => vec![instruction("lim", vec![rd, number(value & 0x3F)])],
+                LoadForm::LuiLim => vec![instruction("lui", vec![number(value >> 6)]), instruction("lim", vec![rd, number(value & 0x3F)])]
+            };
+
+            for _ in 0..load.len(){
+                expanded_origins.push(*origin);
+            }
+            expanded.append(&mut load);
+        }
+
+        Ok((expanded, expanded_origins))
+    }
+}
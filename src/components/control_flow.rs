@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{constant_value, source_line, token_text, Token, REGISTERS_TO_VAL};
+
+use super::constant_loads::MachineState;
+
+// Control flow graph of the assembled program
+// blocks start at labels and after brc, bri, cal, ret and hlt, data isn't part of any block
+//
+// brc targets need the upper bits loaded by lui, they are followed from the reset state (0)
+// along every edge, what is loaded when entering a block is what all its known predecessors agree on.
+// bri and cal targets are resolved when the register was loaded in the same block (jmp, call),
+// relative branches and everything else go to an unknown target
+
+const DATA_DIRECTIVES: [&str; 4] = ["db", "dw", "dd", "dq"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind{
+    Fallthrough,
+    Branch,
+    Call,
+    // from a call to the instruction after it
+    Return
+}
+
+impl EdgeKind{
+    pub fn name(self: &Self) -> &'static str{
+        match self{
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Branch => "branch",
+            EdgeKind::Call => "call",
+            EdgeKind::Return => "return"
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock{
+    // word addresses, end is exclusive
+    pub start: usize,
+    pub end: usize,
+    // token indices
+    pub first: usize,
+    pub last: usize,
+    pub labels: Vec<String>,
+    // file, row, text
+    pub lines: Vec<(String, usize, String)>
+}
+
+#[derive(Debug, Clone)]
+pub struct Edge{
+    pub from: usize,
+    // None when the target isn't known
+    pub to: Option<usize>,
+    pub kind: EdgeKind,
+    pub condition: Option<String>
+}
+
+// edge leaving a block before targets are turned into blocks
+struct Exit{
+    target: Option<usize>,
+    kind: EdgeKind,
+    condition: Option<String>,
+    latch: Option<usize>
+}
+
+fn mnemonic(token: &Token) -> String{
+    match token{
+        Token::Instruction { name, .. } => name.value.to_lowercase().split('.').next().unwrap_or("").to_string(),
+        Token::Label { .. } => String::new()
+    }
+}
+
+fn ends_block(token: &Token) -> bool{
+    matches!(mnemonic(token).as_str(), "brc" | "bri" | "cal" | "ret" | "hlt")
+}
+
+fn escape(text: &str) -> String{
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub struct ControlFlowGraph<'a>{
+    tokens: &'a [Token],
+    spans: &'a [(usize, usize)],
+    labels: &'a [(String, usize)],
+    sources: HashMap<String, Vec<String>>,
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>
+}
+
+impl ControlFlowGraph<'_>{
+    pub fn new<'a>(tokens: &'a [Token], spans: &'a [(usize, usize)], labels: &'a [(String, usize)]) -> ControlFlowGraph<'a>{
+        ControlFlowGraph{
+            tokens,
+            spans,
+            labels,
+            sources: HashMap::new(),
+            blocks: Vec::new(),
+            edges: Vec::new()
+        }
+    }
+
+    pub fn add_source(self: &mut Self, filename: &str, content: &str){
+        self.sources.insert(filename.to_string(), content.lines().map(|line| line.to_string()).collect());
+    }
+
+    fn split_blocks(self: &mut Self){
+        let leaders: HashSet<usize> = self.labels.iter().map(|(_, address)| *address).collect();
+        let mut current: Option<BasicBlock> = None;
+
+        for (i, (token, (start, end))) in self.tokens.iter().zip(self.spans.iter()).enumerate(){
+            let (start, end) = (start / 2, end / 2);
+
+            if DATA_DIRECTIVES.contains(&mnemonic(token).as_str()){
+                self.blocks.extend(current.take());
+                continue;
+            }
+
+            let split = match &current{
+                Some(block) => leaders.contains(&start) || block.end != start || ends_block(&self.tokens[block.last]),
+                None => true
+            };
+
+            if split{
+                self.blocks.extend(current.take());
+                current = Some(BasicBlock {
+                    start,
+                    end,
+                    first: i,
+                    last: i,
+                    labels: self.labels.iter().filter(|(_, address)| *address == start).map(|(name, _)| name.clone()).collect(),
+                    lines: Vec::new()
+                });
+            }
+
+            let block = current.as_mut().unwrap();
+            block.end = end;
+            block.last = i;
+
+            if let Token::Instruction { name, .. } = token{
+                if block.lines.last().is_some_and(|(file, row, _)| *file == name.filename && *row == name.row){
+                    continue;
+                }
+
+                let mut text = source_line(&self.sources, name);
+                if text.is_empty(){
+                    text = token_text(token);
+                }
+                block.lines.push((name.filename.clone(), name.row, text));
+            }
+        }
+
+        self.blocks.extend(current.take());
+    }
+
+    // edges leaving block when latch holds the upper bits on entry
+    fn exits(self: &Self, block: &BasicBlock, latch: Option<usize>) -> Vec<Exit>{
+        let mut state = MachineState::new();
+        state.latch = latch;
+
+        for token in self.tokens[block.first..block.last].iter(){
+            state.step(token);
+        }
+
+        let token = &self.tokens[block.last];
+        let args: &[crate::Lexem] = match token{
+            Token::Instruction { args, .. } => args,
+            Token::Label { .. } => &[]
+        };
+        let relative = match token{
+            Token::Instruction { name, .. } => name.value.contains('.') || args.get(2).is_some_and(|extra| extra.value != "0"),
+            Token::Label { .. } => false
+        };
+
+        let mut exits: Vec<Exit> = Vec::new();
+        let mnemonic = mnemonic(token);
+
+        match mnemonic.as_str(){
+            "brc" | "bri" | "cal" if args.len() >= 2 => {
+                let condition = args[0].value.to_lowercase();
+                let operand = args[1].value.to_lowercase();
+
+                let target = if relative{
+                    None
+                }else if REGISTERS_TO_VAL.contains_key(operand.as_str()){
+                    state.registers.get(&operand).copied()
+                }else{
+                    match (state.latch, constant_value(&args[1])){
+                        (Some(latch), Some(imm)) => Some((latch << 6) | imm),
+                        _ => None
+                    }
+                };
+
+                let kind = if mnemonic == "cal" { EdgeKind::Call } else { EdgeKind::Branch };
+                exits.push(Exit { target, kind, condition: Some(condition.clone()), latch: state.latch });
+
+                state.step(token);
+
+                if mnemonic == "cal"{
+                    // whatever the callee loaded is unknown
+                    exits.push(Exit { target: Some(block.end), kind: EdgeKind::Return, condition: None, latch: None });
+                }else if condition != "t"{
+                    exits.push(Exit { target: Some(block.end), kind: EdgeKind::Fallthrough, condition: None, latch: state.latch });
+                }
+            }
+            "ret" | "hlt" => {}
+            _ => {
+                state.step(token);
+                exits.push(Exit { target: Some(block.end), kind: EdgeKind::Fallthrough, condition: None, latch: state.latch });
+            }
+        }
+
+        exits
+    }
+
+    fn block_at(self: &Self, address: usize) -> Option<usize>{
+        self.blocks.iter().position(|block| block.start <= address && address < block.end)
+    }
+
+    pub fn build(self: &mut Self){
+        self.blocks.clear();
+        self.edges.clear();
+
+        self.split_blocks();
+
+        if self.blocks.is_empty(){
+            return;
+        }
+
+        // None is not reached yet, Some(None) is unknown upper bits
+        let mut entries: Vec<Option<Option<usize>>> = vec![None; self.blocks.len()];
+        let mut worklist: Vec<usize> = Vec::new();
+
+        let entry = self.block_at(0).unwrap_or(0);
+        entries[entry] = Some(Some(0));
+        worklist.push(entry);
+
+        loop{
+            while let Some(i) = worklist.pop(){
+                for exit in self.exits(&self.blocks[i], entries[i].unwrap()){
+                    let to = match exit.target.and_then(|address| self.block_at(address)){
+                        Some(a) => a,
+                        None => continue
+                    };
+
+                    let merged = match entries[to]{
+                        None => Some(exit.latch),
+                        Some(latch) if latch == exit.latch => continue,
+                        Some(_) => Some(None)
+                    };
+
+                    if entries[to] != merged{
+                        entries[to] = merged;
+                        worklist.push(to);
+                    }
+                }
+            }
+
+            // only reached from somewhere unknown
+            match entries.iter().position(|entry| entry.is_none()){
+                Some(i) => {
+                    entries[i] = Some(None);
+                    worklist.push(i);
+                }
+                None => break
+            }
+        }
+
+        for (i, entry) in entries.iter().enumerate(){
+            for exit in self.exits(&self.blocks[i], entry.unwrap()){
+                let to = exit.target.and_then(|address| self.block_at(address));
+                self.edges.push(Edge { from: i, to, kind: exit.kind, condition: exit.condition });
+            }
+        }
+    }
+
+    fn block_title(self: &Self, block: &BasicBlock) -> String{
+        let range = format!("{:04X}-{:04X}", block.start, block.end - 1);
+
+        if block.labels.is_empty(){
+            return range;
+        }
+
+        format!("{} {}", block.labels.join(", "), range)
+    }
+
+    pub fn to_dot(self: &Self) -> String{
+        let mut text = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for (i, block) in self.blocks.iter().enumerate(){
+            let mut label = format!("{}\\l", escape(&self.block_title(block)));
+            for (file, row, line) in block.lines.iter(){
+                label += format!("{}:{}  {}\\l", escape(file), row, escape(line)).as_str();
+            }
+            text += format!("    b{} [label=\"{}\"];\n", i, label).as_str();
+        }
+
+        if self.edges.iter().any(|edge| edge.to.is_none()){
+            text += "    unknown [shape=ellipse, label=\"?\"];\n";
+        }
+
+        for edge in self.edges.iter(){
+            let to = match edge.to{
+                Some(a) => format!("b{}", a),
+                None => "unknown".to_string()
+            };
+
+            let mut attributes: Vec<String> = Vec::new();
+            match edge.kind{
+                EdgeKind::Fallthrough => {}
+                EdgeKind::Branch => attributes.push(format!("label=\"{}\"", escape(edge.condition.as_deref().unwrap_or("")))),
+                EdgeKind::Call => attributes.push("label=\"call\", style=bold".to_string()),
+                EdgeKind::Return => attributes.push("style=dashed".to_string())
+            }
+
+            if attributes.is_empty(){
+                text += format!("    b{} -> {};\n", edge.from, to).as_str();
+            }else{
+                text += format!("    b{} -> {} [{}];\n", edge.from, to, attributes.join(", ")).as_str();
+            }
+        }
+
+        text += "}\n";
+        text
+    }
+
+    pub fn to_json(self: &Self) -> String{
+        // source lines can hold any control character, serde_json escapes all of them
+        let string = |text: &str| serde_json::Value::from(text).to_string();
+
+        let blocks: Vec<String> = self.blocks.iter().enumerate().map(|(i, block)| {
+            let labels: Vec<String> = block.labels.iter().map(|label| string(label)).collect();
+            let lines: Vec<String> = block.lines.iter().map(|(file, row, line)| format!("{{\"file\": {}, \"row\": {}, \"text\": {}}}", string(file), row, string(line))).collect();
+
+            format!("    {{\"id\": {}, \"start\": {}, \"end\": {}, \"labels\": [{}], \"lines\": [{}]}}", i, block.start, block.end, labels.join(", "), lines.join(", "))
+        }).collect();
+
+        let edges: Vec<String> = self.edges.iter().map(|edge| {
+            let to = match edge.to{
+                Some(a) => format!("{}", a),
+                None => "null".to_string()
+            };
+            let condition = match &edge.condition{
+                Some(a) => string(a),
+                None => "null".to_string()
+            };
+
+            format!("    {{\"from\": {}, \"to\": {}, \"kind\": \"{}\", \"condition\": {}}}", edge.from, to, edge.kind.name(), condition)
+        }).collect();
+
+        format!("{{\n  \"blocks\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}\n", blocks.join(",\n"), edges.join(",\n"))
+    }
+}
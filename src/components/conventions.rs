@@ -0,0 +1,462 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use crate::{Aliases, ControlFlowGraph, Diagnostic, EdgeKind, Lexem, RoutineDoc, Token, OVERWRITES_REGISTER, REGISTERS_TO_VAL, WRITES_REGISTER};
+
+// Calling convention checker
+// a routine declares what it may clobber with @clobbers (or proc name uses r1, r2) and what it keeps
+// with @preserves, @returns registers are always allowed to change
+//
+// what a routine clobbers is every register that doesn't hold its value from entry on some path to ret,
+// psh rX ... pop rX gives it back, a call clobbers what the callee declares (or what it really clobbers
+// when it declares nothing) and a call that can't be followed clobbers everything but s0-s2, fp and sp.
+// After a call to an annotated routine, reading a register it clobbers before writing it is reported
+
+// s0-s2, fp and sp survive any call
+const CALLEE_SAVED: u16 = (1 << 9) | (1 << 10) | (1 << 11) | (1 << 14) | (1 << 15);
+
+// at, pseudo instructions clobber it without saying so
+const SCRATCH: u16 = 1 << 12;
+
+// r0 always reads as zero
+const ZERO: u16 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Convention{
+    pub name: String,
+    pub location: Lexem,
+    // None when only @preserves is given, what the routine really clobbers counts then
+    pub clobbers: Option<u16>,
+    pub preserves: u16,
+    pub returns: u16,
+    // registers the way the annotations name them, for messages
+    pub names: HashMap<usize, String>
+}
+
+#[derive(Debug, Clone)]
+struct Clobbers{
+    mask: u16,
+    // first instruction overwriting each register
+    causes: HashMap<usize, Lexem>,
+    // the register as that instruction wrote it
+    names: HashMap<usize, String>
+}
+
+// walking a body, the stack holds which register's entry value each pushed word is
+#[derive(Debug, Clone, PartialEq)]
+struct State{
+    mask: u16,
+    stack: Vec<Option<usize>>
+}
+
+fn token_name(token: &Token) -> &Lexem{
+    match token{
+        Token::Instruction { name, .. } | Token::Label { name } => name
+    }
+}
+
+fn mnemonic(token: &Token) -> String{
+    token_name(token).value.to_lowercase().split('.').next().unwrap_or("").to_string()
+}
+
+fn register(lexem: &Lexem) -> Option<usize>{
+    REGISTERS_TO_VAL.get(lexem.value.to_lowercase().as_str()).copied()
+}
+
+// registers an instruction reads and writes
+fn effects(token: &Token) -> (u16, u16){
+    let args = match token{
+        Token::Instruction { args, .. } => args,
+        Token::Label { .. } => return (0, 0)
+    };
+
+    let mnemonic = mnemonic(token);
+    let registers: Vec<Option<usize>> = args.iter().map(register).collect();
+
+    let writes = match registers.first(){
+        Some(Some(r)) if WRITES_REGISTER.contains(mnemonic.as_str()) => 1 << r,
+        _ => 0
+    };
+
+    // xor rX, rX and sub rX, rX don't depend on rX
+    if (mnemonic == "xor" || mnemonic == "sub") && registers.len() >= 2 && registers[0].is_some() && registers[0] == registers[1]{
+        return (0, writes & !ZERO);
+    }
+
+    let mut reads = 0;
+    for (i, r) in registers.iter().enumerate(){
+        if i == 0 && OVERWRITES_REGISTER.contains(mnemonic.as_str()){
+            continue;
+        }
+        if let Some(r) = r{
+            reads |= 1 << r;
+        }
+    }
+
+    (reads & !ZERO, writes & !ZERO)
+}
+
+fn bits(mask: u16) -> impl Iterator<Item = usize>{
+    (0..16).filter(move |r| mask & (1 << r) != 0)
+}
+
+pub struct CallingConventions<'a>{
+    cfg: &'a ControlFlowGraph<'a>,
+    tokens: &'a [Token],
+    // by the block the routine starts at
+    conventions: HashMap<usize, Convention>,
+    clobbers: HashMap<usize, Clobbers>,
+    visiting: Vec<usize>,
+    // rN -> user alias for messages, when neither the operand nor an annotation names the register
+    pub register_names: HashMap<String, String>,
+    // Aliases::written, operands the way the source spells them
+    pub written: HashMap<(String, usize, usize), (String, String)>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl CallingConventions<'_>{
+    pub fn new<'a>(cfg: &'a ControlFlowGraph<'a>, tokens: &'a [Token]) -> CallingConventions<'a>{
+        CallingConventions{
+            cfg,
+            tokens,
+            conventions: HashMap::new(),
+            clobbers: HashMap::new(),
+            visiting: Vec::new(),
+            register_names: HashMap::new(),
+            written: HashMap::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    // as an annotation of the routine wrote it, otherwise by user alias or rN
+    fn register_name(self: &Self, r: usize, names: &HashMap<usize, String>) -> String{
+        if let Some(name) = names.get(&r){
+            return name.clone();
+        }
+        let name = format!("r{}", r);
+        self.register_names.get(&name).cloned().unwrap_or(name)
+    }
+
+    // the operand of token naming register r, as written
+    fn operand_name(self: &Self, token: &Token, r: usize) -> Option<String>{
+        let args = match token{
+            Token::Instruction { args, .. } => args,
+            Token::Label { .. } => return None
+        };
+
+        let arg = args.iter().find(|arg| register(arg) == Some(r))?;
+        match self.written.get(&(arg.filename.clone(), arg.row, arg.col)){
+            Some((register, name)) if *register == arg.value.to_lowercase() => Some(name.clone()),
+            _ => Some(arg.value.clone())
+        }
+    }
+
+    fn block_at(self: &Self, address: usize) -> Option<usize>{
+        self.cfg.blocks.iter().position(|block| block.start == address)
+    }
+
+    fn convention(self: &mut Self, block: usize, name: &str, location: &Lexem) -> &mut Convention{
+        self.conventions.entry(block).or_insert_with(|| Convention {
+            name: name.to_string(),
+            location: location.clone(),
+            clobbers: None,
+            preserves: 0,
+            returns: 0,
+            names: HashMap::new()
+        })
+    }
+
+    // registers named in a doc tag, by alias or rN
+    fn doc_registers(self: &mut Self, names: &[String], tag: &str, routine: &RoutineDoc, aliases: &Aliases, written: &mut HashMap<usize, String>) -> u16{
+        let mut mask = 0;
+
+        for original in names.iter(){
+            let name = original.to_lowercase();
+            let resolved = aliases.get(&name).cloned().unwrap_or(name.clone());
+
+            match REGISTERS_TO_VAL.get(resolved.as_str()){
+                Some(r) => {
+                    mask |= 1 << r;
+                    written.entry(*r).or_insert(original.clone());
+                }
+                None => {
+                    self.diagnostics.push(Diagnostic::warning("calling-convention", &routine.location, format!("Unknown register {} in @{} of {}", name, tag, routine.name)));
+                }
+            }
+        }
+
+        mask
+    }
+
+    pub fn declare_docs(self: &mut Self, routines: &[RoutineDoc], aliases: &Aliases){
+        for routine in routines.iter(){
+            if routine.clobbers.is_empty() && routine.preserves.is_empty(){
+                continue;
+            }
+
+            let block = match self.block_at(routine.address){
+                Some(a) => a,
+                None => continue
+            };
+
+            let mut written: HashMap<usize, String> = HashMap::new();
+            let clobbers = self.doc_registers(&routine.clobbers, "clobbers", routine, aliases, &mut written);
+            let preserves = self.doc_registers(&routine.preserves, "preserves", routine, aliases, &mut written);
+
+            // @returns a0 description
+            let returns: u16 = routine.returns.iter().filter_map(|description| {
+                let name = description.split_whitespace().next()?.to_lowercase();
+                let resolved = aliases.get(&name).cloned().unwrap_or(name);
+                REGISTERS_TO_VAL.get(resolved.as_str()).map(|r| 1 << r)
+            }).fold(0, |mask, bit| mask | bit);
+
+            let convention = self.convention(block, &routine.name, &routine.location);
+            if !routine.clobbers.is_empty(){
+                convention.clobbers = Some(convention.clobbers.unwrap_or(0) | clobbers);
+            }
+            convention.preserves |= preserves;
+            convention.returns |= returns;
+            for (r, name) in written{
+                convention.names.entry(r).or_insert(name);
+            }
+        }
+    }
+
+    // proc name uses r1, r2
+    pub fn declare_uses(self: &mut Self, uses: &[(String, Vec<Lexem>)], labels: &HashMap<String, usize>){
+        for (name, registers) in uses.iter(){
+            let block = match labels.get(name).and_then(|address| self.block_at(*address)){
+                Some(a) => a,
+                None => continue
+            };
+
+            let mask: u16 = registers.iter().filter_map(register).fold(0, |mask, r| mask | (1 << r));
+
+            let convention = self.convention(block, name, &registers[0]);
+            convention.clobbers = Some(convention.clobbers.unwrap_or(0) | mask);
+            for lexem in registers.iter(){
+                if let Some(r) = register(lexem){
+                    convention.names.entry(r).or_insert(lexem.value.clone());
+                }
+            }
+        }
+    }
+
+    // what a call to block clobbers, None is a call that can't be followed
+    fn call_clobbers(self: &mut Self, callee: Option<usize>) -> u16{
+        let callee = match callee{
+            Some(a) => a,
+            None => return !CALLEE_SAVED & !ZERO
+        };
+
+        if let Some(Convention { clobbers: Some(clobbers), returns, .. }) = self.conventions.get(&callee){
+            return (clobbers | returns | SCRATCH) & !ZERO;
+        }
+
+        // recursion adds nothing the routine doesn't already clobber itself
+        if self.visiting.contains(&callee){
+            return 0;
+        }
+
+        self.function(callee).mask
+    }
+
+    fn function(self: &mut Self, entry: usize) -> Clobbers{
+        if let Some(clobbers) = self.clobbers.get(&entry){
+            return clobbers.clone();
+        }
+
+        self.visiting.push(entry);
+
+        let (cfg, tokens) = (self.cfg, self.tokens);
+
+        let mut states: HashMap<usize, State> = HashMap::new();
+        let mut worklist: Vec<usize> = vec![entry];
+        let mut causes: HashMap<usize, Lexem> = HashMap::new();
+        let mut names: HashMap<usize, String> = HashMap::new();
+        let mut returned: u16 = 0;
+        states.insert(entry, State { mask: 0, stack: Vec::new() });
+
+        while let Some(block) = worklist.pop(){
+            let mut state = states[&block].clone();
+            let (first, last) = (cfg.blocks[block].first, cfg.blocks[block].last);
+
+            for token in tokens[first..=last].iter(){
+                let args: &[Lexem] = match token{
+                    Token::Instruction { args, .. } => args,
+                    Token::Label { .. } => &[]
+                };
+                let operand = args.first().and_then(register);
+
+                let overwritten = match mnemonic(token).as_str(){
+                    "psh" => {
+                        if let Some(r) = operand{
+                            state.stack.push(if state.mask & (1 << r) == 0 { Some(r) } else { None });
+                        }
+                        0
+                    }
+                    "pop" => match (operand, state.stack.pop()){
+                        (Some(r), Some(Some(saved))) if saved == r => {
+                            state.mask &= !(1 << r);
+                            0
+                        }
+                        (Some(r), _) => 1 << r,
+                        (None, _) => 0
+                    },
+                    "cal" => {
+                        let callee = cfg.edges.iter().find(|edge| edge.from == block && edge.kind == EdgeKind::Call).and_then(|edge| edge.to);
+                        self.call_clobbers(callee)
+                    }
+                    "ret" => {
+                        returned |= state.mask;
+                        0
+                    }
+                    _ => effects(token).1
+                } & !ZERO;
+
+                for r in bits(overwritten){
+                    if let Entry::Vacant(cause) = causes.entry(r){
+                        cause.insert(token_name(token).clone());
+                        if let Some(name) = self.operand_name(token, r){
+                            names.insert(r, name);
+                        }
+                    }
+                }
+                state.mask |= overwritten;
+            }
+
+            for edge in cfg.edges.iter().filter(|edge| edge.from == block && edge.kind != EdgeKind::Call){
+                let to = match edge.to{
+                    Some(a) => a,
+                    None => continue
+                };
+
+                let merged = match states.get(&to){
+                    None => state.clone(),
+                    Some(known) => {
+                        // stacks of different depth are unbalanced-stack's business, the first one is kept
+                        let stack = if known.stack.len() == state.stack.len(){
+                            known.stack.iter().zip(state.stack.iter()).map(|(a, b)| if a == b { *a } else { None }).collect()
+                        }else{
+                            known.stack.clone()
+                        };
+                        State { mask: known.mask | state.mask, stack }
+                    }
+                };
+
+                if states.get(&to) != Some(&merged){
+                    states.insert(to, merged);
+                    worklist.push(to);
+                }
+            }
+        }
+
+        causes.retain(|r, _| returned & (1 << r) != 0);
+        names.retain(|r, _| returned & (1 << r) != 0);
+        let clobbers = Clobbers { mask: returned, causes, names };
+
+        self.visiting.pop();
+        self.clobbers.insert(entry, clobbers.clone());
+
+        clobbers
+    }
+
+    // what each annotated routine really clobbers against what it declares
+    fn verify(self: &mut Self, block: usize, convention: &Convention){
+        let clobbers = self.function(block);
+
+        for r in bits(clobbers.mask & convention.preserves){
+            let location = clobbers.causes.get(&r).unwrap_or(&convention.location);
+            let name = clobbers.names.get(&r).cloned().unwrap_or_else(|| self.register_name(r, &convention.names));
+            self.diagnostics.push(Diagnostic::warning("calling-convention", location, format!("{} promises to preserve {} but overwrites it here", convention.name, name)));
+        }
+
+        if let Some(declared) = convention.clobbers{
+            let allowed = declared | convention.returns | convention.preserves | SCRATCH;
+
+            for r in bits(clobbers.mask & !allowed){
+                let location = clobbers.causes.get(&r).unwrap_or(&convention.location);
+                let name = clobbers.names.get(&r).cloned().unwrap_or_else(|| self.register_name(r, &convention.names));
+                self.diagnostics.push(Diagnostic::warning("calling-convention", location, format!("{} clobbers {} here but doesn't declare it", convention.name, name)));
+            }
+        }
+    }
+
+    // registers read after a call to an annotated routine before anything writes them
+    fn check_call(self: &mut Self, block: usize){
+        let (cfg, tokens) = (self.cfg, self.tokens);
+
+        let callee = match cfg.edges.iter().find(|edge| edge.from == block && edge.kind == EdgeKind::Call).and_then(|edge| edge.to){
+            Some(a) => a,
+            None => return
+        };
+        let (name, returns, names) = match self.conventions.get(&callee){
+            Some(convention) => (convention.name.clone(), convention.returns, convention.names.clone()),
+            None => return
+        };
+        let after = match cfg.edges.iter().find(|edge| edge.from == block && edge.kind == EdgeKind::Return).and_then(|edge| edge.to){
+            Some(a) => a,
+            None => return
+        };
+
+        let clobbered = self.call_clobbers(Some(callee)) & !returns;
+
+        let mut worklist: Vec<(usize, u16)> = vec![(after, clobbered)];
+        let mut seen: HashSet<(usize, u16)> = HashSet::new();
+        let mut reported: u16 = 0;
+
+        while let Some((block, mut pending)) = worklist.pop(){
+            if pending == 0 || !seen.insert((block, pending)){
+                continue;
+            }
+
+            let (first, last) = (cfg.blocks[block].first, cfg.blocks[block].last);
+            let mut stopped = false;
+
+            for token in tokens[first..=last].iter(){
+                let (reads, writes) = effects(token);
+
+                for r in bits(reads & pending & !reported){
+                    let register = self.operand_name(token, r).unwrap_or_else(|| self.register_name(r, &names));
+                    self.diagnostics.push(Diagnostic::warning("calling-convention", token_name(token), format!("{} is read after calling {}, which clobbers it", register, name)));
+                }
+                reported |= reads & pending;
+                pending &= !writes;
+
+                // what happens after the next call is that call's business
+                if matches!(mnemonic(token).as_str(), "cal" | "ret" | "hlt"){
+                    stopped = true;
+                    break;
+                }
+            }
+
+            if stopped{
+                continue;
+            }
+
+            for edge in cfg.edges.iter().filter(|edge| edge.from == block && edge.kind != EdgeKind::Call){
+                if let Some(to) = edge.to{
+                    worklist.push((to, pending));
+                }
+            }
+        }
+    }
+
+    pub fn analyze(self: &mut Self){
+        if self.conventions.is_empty(){
+            return;
+        }
+
+        let mut annotated: Vec<(usize, Convention)> = self.conventions.iter().map(|(block, convention)| (*block, convention.clone())).collect();
+        annotated.sort_by_key(|(block, _)| *block);
+
+        for (block, convention) in annotated.iter(){
+            self.verify(*block, convention);
+        }
+
+        for block in 0..self.cfg.blocks.len(){
+            if mnemonic(&self.tokens[self.cfg.blocks[block].last]) == "cal"{
+                self.check_call(block);
+            }
+        }
+    }
+}
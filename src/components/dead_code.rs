@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+
+use crate::{ControlFlowGraph, Diagnostic, Lexem, Token};
+
+// Reachability from the reset address and export/entry symbols, over branches, calls and returns
+// as soon as something reachable jumps somewhere unknown, every label referred to anywhere
+// counts as reachable too, so code only reached through a pointer isn't reported
+
+fn token_name(token: &Token) -> &Lexem{
+    match token{
+        Token::Instruction { name, .. } | Token::Label { name } => name
+    }
+}
+
+pub struct DeadCode<'a>{
+    cfg: &'a ControlFlowGraph<'a>,
+    tokens: &'a [Token],
+    // indexed by block
+    pub reachable: Vec<bool>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl DeadCode<'_>{
+    pub fn new<'a>(cfg: &'a ControlFlowGraph<'a>, tokens: &'a [Token]) -> DeadCode<'a>{
+        DeadCode{
+            cfg,
+            tokens,
+            reachable: Vec::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    fn block_at(self: &Self, address: usize) -> Option<usize>{
+        self.cfg.blocks.iter().position(|block| block.start <= address && address < block.end)
+    }
+
+    fn visit(self: &mut Self, roots: Vec<usize>) -> bool{
+        let mut worklist = roots;
+        let mut unknown = false;
+
+        while let Some(block) = worklist.pop(){
+            if self.reachable[block]{
+                continue;
+            }
+            self.reachable[block] = true;
+
+            for edge in self.cfg.edges.iter().filter(|edge| edge.from == block){
+                match edge.to{
+                    Some(to) => worklist.push(to),
+                    None => unknown = true
+                }
+            }
+        }
+
+        unknown
+    }
+
+    // entries are export/entry addresses, referenced are addresses of labels used as operands
+    pub fn analyze(self: &mut Self, entries: &[usize], referenced: &HashSet<usize>){
+        self.reachable = vec![false; self.cfg.blocks.len()];
+
+        if self.cfg.blocks.is_empty(){
+            return;
+        }
+
+        let mut roots: Vec<usize> = vec![self.block_at(0).unwrap_or(0)];
+        roots.extend(entries.iter().filter_map(|address| self.block_at(*address)));
+
+        if self.visit(roots){
+            let pointers: Vec<usize> = referenced.iter().filter_map(|address| self.block_at(*address)).collect();
+            self.visit(pointers);
+        }
+    }
+
+    // first block of every run of unreachable blocks that fall into each other
+    fn dead_runs(self: &Self) -> Vec<(usize, usize)>{
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+
+        for (i, reachable) in self.reachable.iter().enumerate(){
+            if *reachable{
+                continue;
+            }
+
+            let continues = i > 0 && !self.reachable[i-1] && self.cfg.blocks[i-1].end == self.cfg.blocks[i].start && self.cfg.blocks[i].labels.is_empty();
+
+            match runs.last_mut(){
+                Some((_, last)) if continues => *last = i,
+                _ => runs.push((i, i))
+            }
+        }
+
+        runs
+    }
+
+    pub fn report(self: &mut Self){
+        for (first, last) in self.dead_runs(){
+            let block = &self.cfg.blocks[first];
+            let words = self.cfg.blocks[last].end - block.start;
+
+            // right after hlt/ret unreachable-code already tells about it
+            let after_stop = block.first > 0 && matches!(token_name(&self.tokens[block.first - 1]).value.to_lowercase().as_str(), "hlt" | "ret");
+            if block.labels.is_empty() && after_stop{
+                continue;
+            }
+
+            let name = match block.labels.first(){
+                Some(label) => label.clone(),
+                None => format!("code at {:04X}", block.start)
+            };
+
+            let location = token_name(&self.tokens[block.first]).clone();
+            self.diagnostics.push(Diagnostic::warning("dead-code", &location, format!("{} is never reached ({} words)", name, words)));
+        }
+    }
+
+    // token indices of unreachable code
+    pub fn dead_tokens(self: &Self) -> Vec<usize>{
+        let mut tokens: Vec<usize> = Vec::new();
+
+        for (block, reachable) in self.cfg.blocks.iter().zip(self.reachable.iter()){
+            if !reachable{
+                tokens.extend(block.first..=block.last);
+            }
+        }
+
+        tokens
+    }
+}
@@ -0,0 +1,251 @@
+use phf::phf_map;
+
+use crate::{Lexem, LexemType};
+
+// Named warnings and whether they are enabled by default
+// -W name / -Wname   enable
+// -Wno-name          disable
+// -Wall              enable every warning
+// -Werror            treat warnings as errors
+// pragma warning(disable: name) / pragma warning(enable: name) inside source
+pub const WARNINGS: phf::Map<&'static str, bool> = phf_map!{
+    "unused-label" => false,
+    "unreachable-code" => true,
+    "truncated-data" => true,
+    "implicit-extra" => false,
+    "shadowed-label" => true,
+    "alias-label" => true,
+    "unknown-escape" => true,
+    "unbalanced-stack" => true,
+    "recursion" => true,
+    "stack-overflow" => true,
+    "dead-code" => true,
+    "doc-comment" => true,
+    "calling-convention" => true,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Severity{
+    Error,
+    Warning
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic{
+    pub severity: Severity,
+    pub warning: Option<&'static str>,
+    pub filename: String,
+    pub row: usize,
+    pub col: usize,
+    pub message: String
+}
+
+impl Diagnostic{
+    pub fn new(message: String) -> Diagnostic{
+        Diagnostic{
+            severity: Severity::Error,
+            warning: None,
+            filename: String::new(),
+            row: 0,
+            col: 0,
+            message
+        }
+    }
+
+    pub fn error(filename: &str, row: usize, col: usize, message: String) -> Diagnostic{
+        Diagnostic{
+            severity: Severity::Error,
+            warning: None,
+            filename: filename.to_string(),
+            row,
+            col,
+            message
+        }
+    }
+
+    pub fn at(lexem: &Lexem, message: String) -> Diagnostic{
+        Diagnostic::error(&lexem.filename, lexem.row, lexem.col, message)
+    }
+
+    pub fn warning(name: &'static str, lexem: &Lexem, message: String) -> Diagnostic{
+        if !WARNINGS.contains_key(name){
+            panic!("Unregistered warning {}", name);
+        }
+
+        Diagnostic{
+            severity: Severity::Warning,
+            warning: Some(name),
+            filename: lexem.filename.clone(),
+            row: lexem.row,
+            col: lexem.col,
+            message
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        if !self.filename.is_empty(){
+            write!(f, "{}:{}:{} ", self.filename, self.row, self.col)?;
+        }
+
+        match (&self.severity, self.warning){
+            (Severity::Warning, Some(name)) => write!(f, "warning: {} [-W{}]", self.message, name),
+            _ => write!(f, "{}", self.message)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pragma{
+    pub filename: String,
+    pub row: usize,
+    pub warning: &'static str,
+    pub enabled: bool
+}
+
+fn warning_name(name: &str) -> Option<&'static str>{
+    WARNINGS.get_key(name).copied()
+}
+
+// pragma warning(disable: unused-label, truncated-data)
+pub fn parse_pragma(pragma: &Lexem, args: &[Lexem]) -> Result<Vec<Pragma>, Diagnostic>{
+    let expect = |i: usize, value: &str| -> Result<(), Diagnostic>{
+        match args.get(i){
+            Some(arg) if arg.value == value => Ok(()),
+            Some(arg) => Err(Diagnostic::at(arg, format!("Expected \"{}\" got \"{}\"", value, arg.value))),
+            None => Err(Diagnostic::at(pragma, format!("Expected \"{}\" got end of line", value)))
+        }
+    };
+
+    expect(0, "warning")?;
+    expect(1, "(")?;
+
+    let enabled = match args.get(2){
+        Some(arg) if arg.value == "disable" => false,
+        Some(arg) if arg.value == "enable" => true,
+        Some(arg) => return Err(Diagnostic::at(arg, format!("Expected disable or enable got {}", arg.value))),
+        None => return Err(Diagnostic::at(pragma, "Expected disable or enable got end of line".to_string()))
+    };
+
+    expect(3, ":")?;
+
+    let mut pragmas: Vec<Pragma> = Vec::new();
+    let mut name = String::new();
+    let mut name_lexem = pragma.clone();
+
+    for arg in args.iter().skip(4){
+        if arg.value == "," || arg.value == ")"{
+            let warning = match warning_name(&name){
+                Some(a) => a,
+                None => return Err(Diagnostic::at(&name_lexem, format!("Unknown warning {}", name)))
+            };
+
+            pragmas.push(Pragma { filename: pragma.filename.clone(), row: pragma.row, warning, enabled });
+
+            if arg.value == ")"{
+                return Ok(pragmas);
+            }
+
+            name.clear();
+            continue;
+        }
+
+        if name.is_empty(){
+            name_lexem = arg.clone();
+        }
+
+        match arg.ttype{
+            LexemType::Ident | LexemType::Operator => name += arg.value.as_str(),
+            _ => return Err(Diagnostic::at(arg, format!("Expected warning name got {}", arg.value)))
+        }
+    }
+
+    Err(Diagnostic::at(pragma, "Expected \")\" got end of line".to_string()))
+}
+
+pub struct Warnings{
+    enabled: std::collections::HashMap<&'static str, bool>,
+    pragmas: Vec<Pragma>,
+    pub werror: bool
+}
+
+impl Warnings{
+    pub fn new() -> Warnings{
+        Warnings{
+            enabled: WARNINGS.entries().map(|(name, enabled)| (*name, *enabled)).collect(),
+            pragmas: Vec::new(),
+            werror: false
+        }
+    }
+
+    // returns false when flag isn't a warning flag
+    pub fn parse_flag(self: &mut Self, flag: &str) -> Result<bool, Diagnostic>{
+        let name = match flag.strip_prefix("-W"){
+            Some(a) => a,
+            None => return Ok(false)
+        };
+
+        match name{
+            "error" => {
+                self.werror = true;
+                return Ok(true);
+            }
+            "all" => {
+                for enabled in self.enabled.values_mut(){
+                    *enabled = true;
+                }
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        let (name, enabled) = match name.strip_prefix("no-"){
+            Some(a) => (a, false),
+            None => (name, true)
+        };
+
+        match warning_name(name){
+            Some(a) => {
+                self.enabled.insert(a, enabled);
+                Ok(true)
+            }
+            None => Err(Diagnostic::new(format!("Unknown warning {}", name)))
+        }
+    }
+
+    pub fn add_pragmas(self: &mut Self, pragmas: &[Pragma]){
+        self.pragmas.extend_from_slice(pragmas);
+    }
+
+    pub fn is_enabled(self: &Self, diagnostic: &Diagnostic) -> bool{
+        let name = match diagnostic.warning{
+            Some(a) => a,
+            None => return true
+        };
+
+        let mut enabled = *self.enabled.get(name).unwrap_or(&true);
+
+        for pragma in self.pragmas.iter(){
+            if pragma.warning == name && pragma.filename == diagnostic.filename && pragma.row <= diagnostic.row{
+                enabled = pragma.enabled;
+            }
+        }
+
+        enabled
+    }
+
+    // prints enabled diagnostics and returns how many were printed
+    pub fn report(self: &Self, diagnostics: &[Diagnostic]) -> usize{
+        let mut count = 0;
+
+        for diagnostic in diagnostics{
+            if self.is_enabled(diagnostic){
+                println!("{}", diagnostic);
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use crate::{Diagnostic, InstructionPart, EXTRA_FIELDS, TYPES};
+
+#[derive(Debug, Clone)]
+pub struct DisassembledLine{
+    pub address: usize,
+    pub word: u16,
+    pub text: String
+}
+
+// mask and value of the constant bits of an instruction
+fn constant_bits(parts: &[InstructionPart]) -> (u16, u16){
+    let mut mask: u16 = 0;
+    let mut value: u16 = 0;
+    let mut bit: usize = 16;
+
+    for part in parts{
+        match part{
+            InstructionPart::Const { val } => {
+                for ch in val.chars(){
+                    bit -= 1;
+                    mask |= 1 << bit;
+                    if ch == '1'{
+                        value |= 1 << bit;
+                    }
+                }
+            }
+            InstructionPart::Imm { size } | InstructionPart::Type { size, .. } | InstructionPart::Extra { size } => {
+                bit -= size;
+            }
+        }
+    }
+
+    (mask, value)
+}
+
+fn type_name(ttype: &str, value: usize) -> Option<&'static str>{
+    let mut names: Vec<&'static str> = TYPES.get(ttype)?.entries().filter(|(_, v)| **v == value).map(|(k, _)| *k).collect();
+    names.sort();
+    names.first().copied()
+}
+
+fn extra_name(mnemonic: &str, value: usize) -> Option<&'static str>{
+    let mut names: Vec<&'static str> = EXTRA_FIELDS.get(mnemonic)?.entries().filter(|(_, v)| **v == value).map(|(k, _)| *k).collect();
+    names.sort();
+    names.first().copied()
+}
+
+pub struct Disassembler<'a>{
+    cursor: usize,
+    instruction_set: &'a HashMap<&'static str,Vec<InstructionPart>>,
+    patterns: Vec<(&'static str, u16, u16)>,
+    // rN -> name printed instead, empty prints plain registers
+    pub register_names: HashMap<String, String>,
+    pub lines: Vec<DisassembledLine>
+}
+
+impl Disassembler<'_>{
+    pub fn new<'a>(instruction_set: &'a HashMap<&'static str,Vec<InstructionPart>>) -> Disassembler<'a>{
+        let mut patterns: Vec<(&'static str, u16, u16)> = instruction_set.iter().map(|(name, parts)| {
+            let (mask, value) = constant_bits(parts);
+            (*name, mask, value)
+        }).collect();
+
+        // most specific pattern first, then by name so output is stable
+        patterns.sort_by(|a, b| b.1.count_ones().cmp(&a.1.count_ones()).then(a.0.cmp(b.0)));
+
+        Disassembler{
+            cursor: 0,
+            instruction_set,
+            patterns,
+            register_names: HashMap::new(),
+            lines: Vec::new()
+        }
+    }
+
+    // pairs of mnemonics some word matches the constant bits of both, decoding it depends on which is tried first
+    pub fn ambiguous(self: &Self) -> Vec<(&'static str, &'static str)>{
+        let mut pairs: Vec<(&'static str, &'static str)> = Vec::new();
+
+        for (i, (a, a_mask, a_value)) in self.patterns.iter().enumerate(){
+            for (b, b_mask, b_value) in self.patterns.iter().skip(i + 1){
+                // bits both fix have to differ somewhere
+                if (a_value ^ b_value) & a_mask & b_mask == 0{
+                    pairs.push(if a < b { (*a, *b) } else { (*b, *a) });
+                }
+            }
+        }
+
+        pairs.sort();
+        pairs
+    }
+
+    // mnemonic, operand fields in order and the extra field (0 when there is none)
+    pub fn fields(self: &Self, word: u16) -> Option<(&'static str, Vec<usize>, usize)>{
+        let (mnemonic, _, _) = self.patterns.iter().find(|(_, mask, value)| word & mask == *value)?;
+
+        let mut operands: Vec<usize> = Vec::new();
+        let mut extra: usize = 0;
+        let mut bit: usize = 16;
+
+        for part in self.instruction_set.get(mnemonic)?{
+            let size = match part{
+                InstructionPart::Const { val } => val.len(),
+                InstructionPart::Imm { size } | InstructionPart::Type { size, .. } | InstructionPart::Extra { size } => *size
+            };
+            bit -= size;
+
+            let field = ((word as usize) >> bit) & ((1 << size) - 1);
+
+            match part{
+                InstructionPart::Const { .. } => {}
+                InstructionPart::Imm { .. } | InstructionPart::Type { .. } => operands.push(field),
+                InstructionPart::Extra { .. } => extra = field
+            }
+        }
+
+        Some((mnemonic, operands, extra))
+    }
+
+    fn decode(self: &Self, word: u16) -> Option<String>{
+        let (mnemonic, fields, extra) = self.fields(word)?;
+
+        let parts = self.instruction_set.get(mnemonic)?.iter().filter(|part| matches!(part, InstructionPart::Imm { .. } | InstructionPart::Type { .. }));
+
+        let mut operands: Vec<String> = Vec::new();
+        let mut suffix = String::new();
+
+        for (part, field) in parts.zip(fields){
+            match part{
+                InstructionPart::Type { val, .. } => {
+                    let name = type_name(val, field)?;
+                    match self.register_names.get(name){
+                        Some(alias) if val == "R" => operands.push(alias.clone()),
+                        _ => operands.push(name.to_string())
+                    }
+                }
+                _ => operands.push(format!("{}", field))
+            }
+        }
+
+        if extra != 0{
+            match extra_name(mnemonic, extra){
+                Some(name) => suffix = format!(".{}", name),
+                None => operands.push(format!("{}", extra))
+            }
+        }
+
+        if operands.is_empty(){
+            return Some(format!("{}{}", mnemonic, suffix));
+        }
+
+        Some(format!("{}{} {}", mnemonic, suffix, operands.join(", ")))
+    }
+
+    pub fn disassemble(self: &mut Self, bytes: &[u8]) -> Result<(), Diagnostic>{
+        if !bytes.len().is_multiple_of(2){
+            return Err(Diagnostic::new(format!("Expected whole words got {} bytes", bytes.len())));
+        }
+
+        self.lines.clear();
+        self.cursor = 0;
+
+        while self.cursor < bytes.len(){
+            let word = u16::from_be_bytes([bytes[self.cursor], bytes[self.cursor+1]]);
+
+            // words that don't decode are most likely data
+            let text = match self.decode(word){
+                Some(a) => a,
+                None => format!("dw 0x{:04X}", word)
+            };
+
+            self.lines.push(DisassembledLine { address: self.cursor / 2, word, text });
+            self.cursor += 2;
+        }
+
+        Ok(())
+    }
+}
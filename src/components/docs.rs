@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+
+use crate::{Diagnostic, Lexem, LexemType, NodeKind, SyntaxNode, SyntaxTree, TriviaKind};
+
+// Routine reference generated from doc comments
+// /// lines right above a label (or proc) document it, free text is the description
+//
+// /// Prints a zero terminated string
+// /// @param a0 address of the string
+// /// @returns a0 number of characters printed
+// /// @clobbers t0, t1
+// /// @preserves s0
+// print:
+//
+// every global routine is listed, sub-labels, local labels and labels inside a proc are part of
+// the routine above them, labels of data aren't routines
+
+// labels followed by these hold data or layout, not code
+const DATA_DIRECTIVES: [&str; 12] = ["db", "dw", "dd", "dq", "struct", "field", "endstruct", "mmio", "org", "scope", "endscope", "endp"];
+
+// can stand between a label and its first instruction
+const NEUTRAL_DIRECTIVES: [&str; 5] = ["alias", "reg", "pragma", "export", "entry"];
+
+#[derive(Debug, Clone)]
+pub struct RoutineDoc{
+    pub name: String,
+    pub address: usize,
+    // words up to the next global label
+    pub size: usize,
+    pub description: Vec<String>,
+    pub params: Vec<(String, String)>,
+    pub returns: Vec<String>,
+    pub clobbers: Vec<String>,
+    pub preserves: Vec<String>,
+    pub location: Lexem
+}
+
+// a global label or proc, routine once its first statement is known to be code
+struct Candidate{
+    location: Lexem,
+    doc: Vec<(String, Lexem)>,
+    code: Option<bool>
+}
+
+fn first_lexem(node: &SyntaxNode) -> Option<&Lexem>{
+    node.tokens().first().map(|token| &token.lexem)
+}
+
+// the operand of proc name
+fn proc_name(statement: &SyntaxNode) -> Option<&Lexem>{
+    let mnemonic = first_lexem(statement)?;
+
+    if mnemonic.value.to_lowercase() != "proc"{
+        return None;
+    }
+
+    statement.nodes(NodeKind::Operand).first().and_then(|operand| first_lexem(operand))
+}
+
+fn orphan(doc: &mut Vec<(String, Lexem)>, diagnostics: &mut Vec<Diagnostic>){
+    if let Some((_, location)) = doc.first(){
+        diagnostics.push(Diagnostic::warning("doc-comment", location, "Doc comment isn't followed by a label".to_string()));
+    }
+    doc.clear();
+}
+
+// @clobbers t0, t1 or t0 t1
+fn register_list(text: &str) -> impl Iterator<Item = String> + '_{
+    text.split(|ch: char| ch == ',' || ch.is_whitespace()).filter(|register| !register.is_empty()).map(|register| register.to_string())
+}
+
+fn escape_html(text: &str) -> String{
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn anchor(name: &str) -> String{
+    name.chars().map(|ch| if ch.is_alphanumeric() { ch.to_ascii_lowercase() } else { '-' }).collect()
+}
+
+pub struct Docs{
+    pub routines: Vec<RoutineDoc>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl Docs{
+    pub fn new() -> Docs{
+        Docs{
+            routines: Vec::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    // /// comments on a line of nothing else
+    fn doc_lines(line: &SyntaxNode, filename: &str) -> Option<Vec<(String, Lexem)>>{
+        let tokens = line.tokens();
+
+        if tokens.iter().any(|token| token.lexem.ttype != LexemType::NewLine){
+            return None;
+        }
+
+        let comments = line.comments();
+
+        if comments.is_empty() || !comments.iter().all(|comment| comment.kind == TriviaKind::LineComment && comment.text.starts_with("///")){
+            return None;
+        }
+
+        Some(comments.iter().map(|comment| {
+            let text = comment.text.trim_end().strip_prefix("///").unwrap_or("");
+            let text = text.strip_prefix(' ').unwrap_or(text);
+            (text.to_string(), Lexem::new(comment.text.clone(), LexemType::Comment, comment.row, comment.col, filename.to_string()))
+        }).collect())
+    }
+
+    fn collect(self: &mut Self, tree: &SyntaxTree, filename: &str) -> Vec<Candidate>{
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut doc: Vec<(String, Lexem)> = Vec::new();
+        let mut proc_depth = 0;
+
+        for line in tree.lines(){
+            if let Some(lines) = Docs::doc_lines(line, filename){
+                doc.extend(lines);
+                continue;
+            }
+
+            let labels = line.nodes(NodeKind::Label);
+            let statement = line.nodes(NodeKind::Statement).first().copied();
+
+            let mut documented = false;
+
+            for label in labels.iter(){
+                let lexem = match first_lexem(label){
+                    Some(a) => a,
+                    None => continue
+                };
+
+                let global = lexem.ttype == LexemType::Ident && !lexem.value.starts_with('.') && lexem.value != "@@" && proc_depth == 0;
+                if !global{
+                    continue;
+                }
+
+                candidates.push(Candidate { location: lexem.clone(), doc: std::mem::take(&mut doc), code: None });
+                documented = true;
+            }
+
+            let statement = match statement{
+                Some(a) => a,
+                None => {
+                    if !documented{
+                        orphan(&mut doc, &mut self.diagnostics);
+                    }
+                    continue;
+                }
+            };
+
+            let mnemonic = first_lexem(statement).map(|lexem| lexem.value.to_lowercase()).unwrap_or_default();
+
+            // the first statement after a label tells whether it is code
+            if !NEUTRAL_DIRECTIVES.contains(&mnemonic.as_str()){
+                let code = !DATA_DIRECTIVES.contains(&mnemonic.as_str()) && mnemonic != "proc";
+                for candidate in candidates.iter_mut().filter(|candidate| candidate.code.is_none()){
+                    candidate.code = Some(code);
+                }
+            }
+
+            if let Some(name) = proc_name(statement){
+                candidates.push(Candidate { location: name.clone(), doc: std::mem::take(&mut doc), code: None });
+                documented = true;
+                proc_depth += 1;
+            }
+
+            if mnemonic == "endp" && proc_depth > 0{
+                proc_depth -= 1;
+            }
+
+            if !documented{
+                orphan(&mut doc, &mut self.diagnostics);
+            }
+        }
+
+        orphan(&mut doc, &mut self.diagnostics);
+
+        candidates
+    }
+
+    fn document(self: &mut Self, routine: &mut RoutineDoc, doc: &[(String, Lexem)]){
+        // untagged lines continue the tag above them until an empty line
+        let mut tag: Option<&str> = None;
+
+        for (text, location) in doc.iter(){
+            if text.trim().is_empty(){
+                tag = None;
+                routine.description.push(String::new());
+                continue;
+            }
+
+            let (name, rest) = match text.strip_prefix('@'){
+                Some(tagged) => match tagged.split_once(char::is_whitespace){
+                    Some((name, rest)) => (Some(name), rest.trim()),
+                    None => (Some(tagged), "")
+                },
+                None => (None, text.trim())
+            };
+
+            match (name, tag){
+                (Some("param"), _) => {
+                    let (register, description) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    routine.params.push((register.to_string(), description.trim().to_string()));
+                    tag = Some("param");
+                }
+                (Some("returns") | Some("return"), _) => {
+                    routine.returns.push(rest.to_string());
+                    tag = Some("returns");
+                }
+                (Some("clobbers"), _) => {
+                    routine.clobbers.extend(register_list(rest));
+                    tag = Some("clobbers");
+                }
+                (Some("preserves"), _) => {
+                    routine.preserves.extend(register_list(rest));
+                    tag = Some("preserves");
+                }
+                (Some(name), _) => {
+                    self.diagnostics.push(Diagnostic::warning("doc-comment", location, format!("Unknown doc tag @{}, expected @param, @returns, @clobbers or @preserves", name)));
+                    tag = None;
+                }
+                (None, Some("param")) => {
+                    if let Some((_, description)) = routine.params.last_mut(){
+                        *description = format!("{} {}", description, rest).trim().to_string();
+                    }
+                }
+                (None, Some("returns")) => {
+                    if let Some(description) = routine.returns.last_mut(){
+                        *description = format!("{} {}", description, rest).trim().to_string();
+                    }
+                }
+                (None, Some("clobbers")) => routine.clobbers.extend(register_list(rest)),
+                (None, Some("preserves")) => routine.preserves.extend(register_list(rest)),
+                (None, _) => routine.description.push(rest.to_string())
+            }
+        }
+
+        while routine.description.last().is_some_and(|line| line.is_empty()){
+            routine.description.pop();
+        }
+    }
+
+    // definitions are the parser's label definitions, end is the address after the last word
+    pub fn extract(self: &mut Self, tree: &SyntaxTree, filename: &str, definitions: &[(String, Lexem)], labels: &HashMap<String, usize>, end: usize){
+        let names: HashMap<(usize, usize), &String> = definitions.iter().map(|(name, lexem)| ((lexem.row, lexem.col), name)).collect();
+
+        let candidates = self.collect(tree, filename);
+
+        let resolved: Vec<(&Candidate, &String, usize)> = candidates.iter().filter_map(|candidate| {
+            let name = names.get(&(candidate.location.row, candidate.location.col))?;
+            let address = labels.get(*name)?;
+            Some((candidate, *name, *address))
+        }).collect();
+
+        // data labels end a routine too
+        let mut boundaries: Vec<usize> = resolved.iter().map(|(_, _, address)| *address).collect();
+        boundaries.push(end);
+        boundaries.sort();
+
+        for (candidate, name, address) in resolved.iter(){
+            if candidate.code != Some(true){
+                if let Some((_, location)) = candidate.doc.first(){
+                    self.diagnostics.push(Diagnostic::warning("doc-comment", location, format!("Doc comment of {} which isn't a routine", name)));
+                }
+                continue;
+            }
+
+            let next = boundaries.iter().find(|boundary| *boundary > address).copied().unwrap_or(*address);
+
+            let mut routine = RoutineDoc {
+                name: name.to_string(),
+                address: *address,
+                size: next - address,
+                description: Vec::new(),
+                params: Vec::new(),
+                returns: Vec::new(),
+                clobbers: Vec::new(),
+                preserves: Vec::new(),
+                location: candidate.location.clone()
+            };
+
+            self.document(&mut routine, &candidate.doc);
+            self.routines.push(routine);
+        }
+
+        self.routines.sort_by_key(|routine| routine.address);
+    }
+
+    pub fn to_markdown(self: &Self, title: &str) -> String{
+        let mut text = format!("# {}\n\n", title);
+
+        text += "| routine | address | size |\n|---|---|---|\n";
+        for routine in self.routines.iter(){
+            text += format!("| [`{}`](#{}) | 0x{:04X} | {} words |\n", routine.name, anchor(&routine.name), routine.address, routine.size).as_str();
+        }
+
+        for routine in self.routines.iter(){
+            text += format!("\n<a id=\"{}\"></a>\n\n## {}\n\n`0x{:04X}`, {} words\n", anchor(&routine.name), routine.name, routine.address, routine.size).as_str();
+
+            if !routine.description.is_empty(){
+                text += format!("\n{}\n", routine.description.join("\n")).as_str();
+            }
+
+            if !routine.params.is_empty(){
+                text += "\n**Parameters**\n\n";
+                for (register, description) in routine.params.iter(){
+                    text += format!("- `{}` {}\n", register, description).trim_end();
+                    text += "\n";
+                }
+            }
+
+            if !routine.returns.is_empty(){
+                text += "\n**Returns**\n\n";
+                for description in routine.returns.iter(){
+                    text += format!("- {}\n", description).as_str();
+                }
+            }
+
+            if !routine.clobbers.is_empty(){
+                let clobbers: Vec<String> = routine.clobbers.iter().map(|register| format!("`{}`", register)).collect();
+                text += format!("\n**Clobbers** {}\n", clobbers.join(", ")).as_str();
+            }
+
+            if !routine.preserves.is_empty(){
+                let preserves: Vec<String> = routine.preserves.iter().map(|register| format!("`{}`", register)).collect();
+                text += format!("\n**Preserves** {}\n", preserves.join(", ")).as_str();
+            }
+        }
+
+        text
+    }
+
+    pub fn to_html(self: &Self, title: &str) -> String{
+        let mut text = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", escape_html(title), escape_html(title));
+
+        text += "<table>\n<tr><th>routine</th><th>address</th><th>size</th></tr>\n";
+        for routine in self.routines.iter(){
+            text += format!("<tr><td><a href=\"#{}\"><code>{}</code></a></td><td>0x{:04X}</td><td>{} words</td></tr>\n", anchor(&routine.name), escape_html(&routine.name), routine.address, routine.size).as_str();
+        }
+        text += "</table>\n";
+
+        for routine in self.routines.iter(){
+            text += format!("<h2 id=\"{}\">{}</h2>\n<p><code>0x{:04X}</code>, {} words</p>\n", anchor(&routine.name), escape_html(&routine.name), routine.address, routine.size).as_str();
+
+            // empty lines separate paragraphs
+            for paragraph in routine.description.split(|line| line.is_empty()).filter(|paragraph| !paragraph.is_empty()){
+                text += format!("<p>{}</p>\n", escape_html(&paragraph.join(" "))).as_str();
+            }
+
+            if !routine.params.is_empty(){
+                text += "<h3>Parameters</h3>\n<ul>\n";
+                for (register, description) in routine.params.iter(){
+                    text += format!("<li><code>{}</code> {}</li>\n", escape_html(register), escape_html(description)).as_str();
+                }
+                text += "</ul>\n";
+            }
+
+            if !routine.returns.is_empty(){
+                text += "<h3>Returns</h3>\n<ul>\n";
+                for description in routine.returns.iter(){
+                    text += format!("<li>{}</li>\n", escape_html(description)).as_str();
+                }
+                text += "</ul>\n";
+            }
+
+            if !routine.clobbers.is_empty(){
+                let clobbers: Vec<String> = routine.clobbers.iter().map(|register| format!("<code>{}</code>", escape_html(register))).collect();
+                text += format!("<p><strong>Clobbers</strong> {}</p>\n", clobbers.join(", ")).as_str();
+            }
+
+            if !routine.preserves.is_empty(){
+                let preserves: Vec<String> = routine.preserves.iter().map(|register| format!("<code>{}</code>", escape_html(register))).collect();
+                text += format!("<p><strong>Preserves</strong> {}</p>\n", preserves.join(", ")).as_str();
+            }
+        }
+
+        text += "</body>\n</html>\n";
+
+        text
+    }
+}
@@ -0,0 +1,345 @@
+use crate::Disassembler;
+
+// Zirc16 emulator for running routines without hardware
+// 64K words of memory, the program is loaded at 0. r0 always reads as zero, lui latches the upper bits
+// used by lim and absolute brc (0 after reset). psh/pop/cal/ret use the stack at sptr, which starts
+// at 0 and grows down (the first word pushed is at 0xFFFF)
+//
+// flags are set by add, adi, sub, mul, div, and, nor, xor, lsh, rot, cmp and cmi, a condition is
+// bit (condition >> 1) of the flags, inverted when its lowest bit is set
+//   0 z   result is zero              4 o   signed overflow
+//   1 c   carry out or borrow         5 e   equal (cmp) or zero
+//   2 p   result is positive          6 ge  greater or equal (cmp), l is its inverse
+//   3 s   result is negative          7 t   always set
+//
+// str rs, ra stores rs at the address in ra, lod rd, ra loads it back. Relative branches and calls
+// (.r) add their target to the address of the branch
+//
+// Provisional: the flags above (which instructions set them, c/o/e/ge and the signedness of cmp) and the
+// meaning of the extra fields (EXTRA_FIELDS in common.rs) aren't taken from the reference emulator
+// (https://github.com/Kaktus14/zirc16) and haven't been checked against it yet, tests that depend on
+// them can change when they are
+
+pub const MEMORY_WORDS: usize = 0x10000;
+
+const Z: u16 = 1 << 0;
+const C: u16 = 1 << 1;
+const P: u16 = 1 << 2;
+const S: u16 = 1 << 3;
+const O: u16 = 1 << 4;
+const E: u16 = 1 << 5;
+const GE: u16 = 1 << 6;
+const T: u16 = 1 << 7;
+
+// why running stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop{
+    // ret out of the routine that was called
+    Returned,
+    Halted
+}
+
+fn sign_extend(value: usize, bits: usize) -> u16{
+    let value = value as u16;
+    let shift = 16 - bits;
+    (((value << shift) as i16) >> shift) as u16
+}
+
+// flags of a result, without carry and overflow
+fn result_flags(result: u16) -> u16{
+    let mut flags = T;
+
+    if result == 0{
+        flags |= Z | E;
+    }
+    if (result as i16) < 0{
+        flags |= S;
+    }
+    if (result as i16) > 0{
+        flags |= P;
+    }
+
+    flags
+}
+
+// a + b + carry with every flag
+fn add_flags(a: u16, b: u16, carry: u16) -> (u16, u16){
+    let wide = a as u32 + b as u32 + carry as u32;
+    let result = wide as u16;
+    let mut flags = result_flags(result);
+
+    if wide > 0xFFFF{
+        flags |= C;
+    }
+    // both operands have the same sign and the result doesn't
+    if (!(a ^ b) & (a ^ result)) & 0x8000 != 0{
+        flags |= O;
+    }
+    if (flags & S != 0) == (flags & O != 0){
+        flags |= GE;
+    }
+
+    (result, flags)
+}
+
+// a - b - borrow with every flag
+fn sub_flags(a: u16, b: u16, borrow: u16) -> (u16, u16){
+    let result = a.wrapping_sub(b).wrapping_sub(borrow);
+    let mut flags = result_flags(result);
+
+    if (a as u32) < b as u32 + borrow as u32{
+        flags |= C;
+    }
+    if ((a ^ b) & (a ^ result)) & 0x8000 != 0{
+        flags |= O;
+    }
+    if (flags & S != 0) == (flags & O != 0){
+        flags |= GE;
+    }
+
+    (result, flags)
+}
+
+// cmp sets e and ge from the operands, signed or not
+fn compare_flags(a: u16, b: u16, borrow: u16, signed: bool) -> u16{
+    let (_, mut flags) = sub_flags(a, b, borrow);
+    flags &= !(E | GE);
+
+    if a == b{
+        flags |= E;
+    }
+
+    let greater_equal = if signed { (a as i16) >= (b as i16) } else { a >= b };
+    if greater_equal{
+        flags |= GE;
+    }
+
+    flags
+}
+
+#[derive(Debug, Clone)]
+pub struct Emulator{
+    pub memory: Vec<u16>,
+    pub registers: [u16; 16],
+    pub flags: u16,
+    pub pc: u16,
+    // upper bits for lim and brc
+    pub latch: u16,
+    pub sptr: u16,
+    pub steps: usize
+}
+
+impl Emulator{
+    pub fn new() -> Emulator{
+        Emulator{
+            memory: vec![0; MEMORY_WORDS],
+            registers: [0; 16],
+            flags: T,
+            pc: 0,
+            latch: 0,
+            sptr: 0,
+            steps: 0
+        }
+    }
+
+    // big endian words as written by codegen
+    pub fn load(self: &mut Self, bytes: &[u8]){
+        for (i, word) in bytes.chunks(2).enumerate().take(MEMORY_WORDS){
+            let low = word.get(1).copied().unwrap_or(0);
+            self.memory[i] = u16::from_be_bytes([word[0], low]);
+        }
+    }
+
+    pub fn condition(self: &Self, condition: usize) -> bool{
+        let flag = (self.flags >> (condition >> 1)) & 1 == 1;
+        flag != (condition & 1 == 1)
+    }
+
+    fn set(self: &mut Self, register: usize, value: u16){
+        if register != 0{
+            self.registers[register] = value;
+        }
+    }
+
+    fn get(self: &Self, register: usize) -> u16{
+        if register == 0 { 0 } else { self.registers[register] }
+    }
+
+    pub fn push(self: &mut Self, value: u16){
+        self.sptr = self.sptr.wrapping_sub(1);
+        self.memory[self.sptr as usize] = value;
+    }
+
+    pub fn pop(self: &mut Self) -> u16{
+        let value = self.memory[self.sptr as usize];
+        self.sptr = self.sptr.wrapping_add(1);
+        value
+    }
+
+    fn target(self: &Self, relative: bool, offset: u16) -> u16{
+        if relative { self.pc.wrapping_add(offset) } else { offset }
+    }
+
+    // runs one instruction, Some when it was hlt or ret
+    pub fn step(self: &mut Self, decoder: &Disassembler) -> Result<Option<Stop>, String>{
+        let word = self.memory[self.pc as usize];
+
+        let (mnemonic, fields, extra) = match decoder.fields(word){
+            Some(a) => a,
+            None => return Err(format!("0x{:04X} isn't an instruction (0x{:04X})", self.pc, word))
+        };
+
+        let field = |i: usize| fields.get(i).copied().unwrap_or(0);
+        // register operands of the first two fields, masked since lui and the immediates are wider
+        // than a register index and only the mnemonics that have register operands use them
+        let (a, b) = (self.get(field(0) & 0xF), self.get(field(1) & 0xF));
+        let carry = if extra & 1 == 1 && self.flags & C != 0 { 1 } else { 0 };
+
+        self.steps += 1;
+        let mut next = self.pc.wrapping_add(1);
+
+        match mnemonic{
+            "hlt" => return Ok(Some(Stop::Halted)),
+            "add" => {
+                let (result, flags) = add_flags(a, b, carry);
+                self.set(field(0), result);
+                self.flags = flags;
+            }
+            "adi" => {
+                let (result, flags) = add_flags(a, field(1) as u16, 0);
+                self.set(field(0), result);
+                self.flags = flags;
+            }
+            "sub" => {
+                let (result, flags) = sub_flags(a, b, carry);
+                self.set(field(0), result);
+                self.flags = flags;
+            }
+            "mul" => {
+                let (result, fits) = if extra & 1 == 1{
+                    let wide = (a as i16 as i32) * (b as i16 as i32);
+                    (wide as u16, wide == wide as i16 as i32)
+                }else{
+                    let wide = (a as u32) * (b as u32);
+                    (wide as u16, wide <= 0xFFFF)
+                };
+                self.set(field(0), result);
+                self.flags = result_flags(result) | if fits { 0 } else { C | O };
+            }
+            "div" => {
+                if b == 0{
+                    return Err(format!("0x{:04X} divides by zero", self.pc));
+                }
+                let result = match (extra & 1 == 1, extra & 2 == 2){
+                    (false, false) => a / b,
+                    (false, true) => a % b,
+                    (true, false) => (a as i16).wrapping_div(b as i16) as u16,
+                    (true, true) => (a as i16).wrapping_rem(b as i16) as u16
+                };
+                self.set(field(0), result);
+                self.flags = result_flags(result);
+            }
+            "fsg" => self.set(field(0), self.flags),
+            "and" | "nor" | "xor" => {
+                let result = match mnemonic{
+                    "and" => a & b,
+                    "nor" => !(a | b),
+                    _ => a ^ b
+                };
+                self.set(field(0), result);
+                self.flags = result_flags(result);
+            }
+            "lsh" | "rot" => {
+                let amount = field(1) as u32;
+                let right = extra & 1 == 1;
+                let result = match (mnemonic, right){
+                    ("lsh", false) => a.checked_shl(amount).unwrap_or(0),
+                    ("lsh", true) => a.checked_shr(amount).unwrap_or(0),
+                    (_, false) => a.rotate_left(amount),
+                    (_, true) => a.rotate_right(amount)
+                };
+                // last bit shifted out
+                let out = match right{
+                    _ if amount == 0 => false,
+                    false => (a as u32) << (amount - 1) & 0x8000 != 0,
+                    true => (a as u32) >> (amount - 1) & 1 != 0
+                };
+                self.set(field(0), result);
+                self.flags = result_flags(result) | if out { C } else { 0 };
+            }
+            "mov" => self.set(field(0), b),
+            "cmp" => self.flags = compare_flags(a, b, if extra & 2 == 2 && self.flags & C != 0 { 1 } else { 0 }, extra & 1 == 1),
+            "cmi" => {
+                let signed = extra & 1 == 1;
+                let immediate = if signed { sign_extend(field(1), 6) } else { field(1) as u16 };
+                self.flags = compare_flags(a, immediate, 0, signed);
+            }
+            "lim" => self.set(field(0), (self.latch << 6) | field(1) as u16),
+            "lui" => self.latch = field(0) as u16,
+            // sptr is the only special register
+            "ssr" => {
+                if field(0) == 0{
+                    self.sptr = b;
+                }
+            }
+            "lsr" => {
+                let value = if field(1) == 0 { self.sptr } else { 0 };
+                self.set(field(0), value);
+            }
+            "psh" => self.push(a),
+            "pop" => {
+                let value = self.pop();
+                self.set(field(0), value);
+            }
+            "str" => self.memory[b as usize] = a,
+            "lod" => self.set(field(0), self.memory[b as usize]),
+            "brc" => {
+                if self.condition(field(0)){
+                    next = match extra & 1 == 1{
+                        true => self.pc.wrapping_add(sign_extend(field(1), 6)),
+                        false => (self.latch << 6) | field(1) as u16
+                    };
+                }
+            }
+            "bri" | "cal" => {
+                if self.condition(field(0)){
+                    if mnemonic == "cal"{
+                        self.push(next);
+                    }
+                    next = self.target(extra & 1 == 1, b);
+                }
+            }
+            "ret" => {
+                self.pc = self.pop();
+                return Ok(Some(Stop::Returned));
+            }
+            _ => return Err(format!("0x{:04X} {} can't be emulated", self.pc, mnemonic))
+        }
+
+        self.pc = next;
+
+        Ok(None)
+    }
+
+    // calls address like cal would and runs until it returns or halts
+    pub fn call(self: &mut Self, decoder: &Disassembler, address: u16, budget: usize) -> Result<Stop, String>{
+        let base = self.sptr;
+        let start = self.steps;
+
+        self.push(self.pc);
+        self.pc = address;
+
+        loop{
+            if self.steps - start >= budget{
+                return Err(format!("didn't return within {} steps (pc 0x{:04X})", budget, self.pc));
+            }
+
+            match self.step(decoder)?{
+                Some(Stop::Returned) if self.sptr == base => return Ok(Stop::Returned),
+                Some(Stop::Halted) => return Ok(Stop::Halted),
+                _ => {}
+            }
+        }
+    }
+}
+
@@ -0,0 +1,489 @@
+use crate::{Diagnostic, Lexem, LexemType, Lexer, NodeKind, SyntaxToken, SyntaxTree, Trivia, REGISTERS_TO_VAL, SPECIAL_REGISTERS_TO_VAL};
+
+// Source formatter, re-emits a file from its syntax tree
+// labels start at column 0, block directives too, everything else is indented,
+// operands are separated by ", " and binary operators get a space on both sides,
+// strings and numbers are written as they are in the source unless an option converts them
+//
+// main:
+//     lim  R1, ((5 + 5) << 2)     // comment
+//     limb R2, .loop
+
+// directives that open or close a block, written at column 0 like labels
+const BLOCK_DIRECTIVES: [&str; 20] = [
+    "org", "struct", "endstruct", "mmio", "scope", "endscope", "proc", "endp",
+    "rept", "irp", "while", "endr", "endw", "alias", "reg", "pragma", "export", "entry",
+    "test", "endtest"
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Case{
+    Keep,
+    Lower,
+    Upper
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align{
+    // a single space
+    Off,
+    // the same column across lines that aren't separated by a blank line
+    Block,
+    Column(usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Radix{
+    Keep,
+    Dec,
+    Hex
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatConfig{
+    pub indent: usize,
+    pub tabs: bool,
+    pub operands: Align,
+    pub comments: Align,
+    pub mnemonic_case: Case,
+    pub register_case: Case,
+    pub hex_case: Case,
+    // decimal and hex literals are converted into each other, binary ones are kept
+    pub radix: Radix
+}
+
+fn parse_case(option: &str, value: &str) -> Result<Case, Diagnostic>{
+    match value{
+        "keep" => Ok(Case::Keep),
+        "lower" => Ok(Case::Lower),
+        "upper" => Ok(Case::Upper),
+        _ => Err(Diagnostic::new(format!("Expected keep, lower or upper after --{} got \"{}\"", option, value)))
+    }
+}
+
+fn parse_align(option: &str, value: &str) -> Result<Align, Diagnostic>{
+    match value{
+        "off" => Ok(Align::Off),
+        "block" => Ok(Align::Block),
+        _ => match value.parse::<usize>(){
+            Ok(a) => Ok(Align::Column(a)),
+            Err(_) => Err(Diagnostic::new(format!("Expected off, block or a column after --{} got \"{}\"", option, value)))
+        }
+    }
+}
+
+fn apply_case(text: &str, case: Case) -> String{
+    match case{
+        Case::Keep => text.to_string(),
+        Case::Lower => text.to_lowercase(),
+        Case::Upper => text.to_uppercase()
+    }
+}
+
+impl FormatConfig{
+    pub fn new() -> FormatConfig{
+        FormatConfig{
+            indent: 4,
+            tabs: false,
+            operands: Align::Off,
+            comments: Align::Block,
+            mnemonic_case: Case::Lower,
+            register_case: Case::Keep,
+            hex_case: Case::Keep,
+            radix: Radix::Keep
+        }
+    }
+
+    // --name=value
+    pub fn parse_flag(self: &mut Self, flag: &str) -> Result<bool, Diagnostic>{
+        if flag == "--tabs"{
+            self.tabs = true;
+            return Ok(true);
+        }
+
+        let (option, value) = match flag.strip_prefix("--").and_then(|a| a.split_once('=')){
+            Some(a) => a,
+            None => return Ok(false)
+        };
+
+        match option{
+            "indent" => {
+                self.indent = match value.parse::<usize>(){
+                    Ok(a) => a,
+                    Err(_) => return Err(Diagnostic::new(format!("Expected number after --indent got \"{}\"", value)))
+                };
+            }
+            "align" => self.operands = parse_align(option, value)?,
+            "comment-align" => self.comments = parse_align(option, value)?,
+            "mnemonic-case" => {
+                // pseudo instructions and directives are only known in lowercase
+                self.mnemonic_case = match parse_case(option, value)?{
+                    Case::Upper => return Err(Diagnostic::new("Mnemonics can't be uppercased, pseudo instructions and directives are lowercase".to_string())),
+                    a => a
+                };
+            }
+            "register-case" => self.register_case = parse_case(option, value)?,
+            "hex-case" => self.hex_case = parse_case(option, value)?,
+            "radix" => {
+                self.radix = match value{
+                    "keep" => Radix::Keep,
+                    "dec" => Radix::Dec,
+                    "hex" => Radix::Hex,
+                    _ => return Err(Diagnostic::new(format!("Expected keep, dec or hex after --radix got \"{}\"", value)))
+                };
+            }
+            _ => return Ok(false)
+        }
+
+        Ok(true)
+    }
+}
+
+struct Line<'a>{
+    labels: Vec<&'a Lexem>,
+    statement: Vec<&'a SyntaxToken>,
+    comments: Vec<&'a Trivia>
+}
+
+// a line with its code laid out, columns are worked out afterwards
+struct Layout{
+    prefix: String,
+    prefix_width: usize,
+    mnemonic: String,
+    operands: String,
+    comment: Option<String>,
+    // comment on its own line written at column 0
+    comment_at_start: bool
+}
+
+impl Layout{
+    fn is_blank(self: &Self) -> bool{
+        self.prefix.is_empty() && self.mnemonic.is_empty() && self.comment.is_none()
+    }
+
+    fn has_code(self: &Self) -> bool{
+        !self.prefix.is_empty() || !self.mnemonic.is_empty()
+    }
+}
+
+fn is_single(lexem: &Lexem, value: &str) -> bool{
+    lexem.ttype == LexemType::Single && lexem.value == value
+}
+
+pub struct Formatter{
+    config: FormatConfig,
+    pub text: String
+}
+
+impl Formatter{
+    pub fn new(config: FormatConfig) -> Formatter{
+        Formatter{
+            config,
+            text: String::new()
+        }
+    }
+
+    fn indent(self: &Self) -> String{
+        match self.config.tabs{
+            true => "\t".to_string(),
+            false => " ".repeat(self.config.indent)
+        }
+    }
+
+    fn number_text(self: &Self, token: &SyntaxToken, radix: usize) -> String{
+        let lexem = &token.lexem;
+        let hex = |digits: String| -> String{
+            format!("0x{}", apply_case(&digits, self.config.hex_case))
+        };
+
+        let value = u128::from_str_radix(&lexem.value, radix as u32).ok();
+
+        match (radix, self.config.radix, value){
+            (10, Radix::Hex, Some(value)) => hex(format!("{:X}", value)),
+            (16, Radix::Dec, Some(value)) => format!("{}", value),
+            (16, _, _) if self.config.hex_case != Case::Keep => hex(lexem.value.clone()),
+            _ => token.text.clone()
+        }
+    }
+
+    fn token_text(self: &Self, token: &SyntaxToken) -> String{
+        let lexem = &token.lexem;
+
+        match &lexem.ttype{
+            LexemType::Number { radix } => self.number_text(token, *radix),
+            LexemType::String => token.text.clone(),
+            LexemType::Ident => {
+                let lower = lexem.value.to_lowercase();
+                if REGISTERS_TO_VAL.contains_key(lower.as_str()) || SPECIAL_REGISTERS_TO_VAL.contains_key(lower.as_str()){
+                    return apply_case(&lexem.value, self.config.register_case);
+                }
+                lexem.value.clone()
+            }
+            _ => lexem.value.clone()
+        }
+    }
+
+    // unary when nothing that ends a value comes before it
+    fn is_unary(previous: Option<&Lexem>) -> bool{
+        match previous{
+            None => true,
+            Some(a) => a.ttype == LexemType::Operator || is_single(a, "(") || is_single(a, ",") || is_single(a, ":")
+        }
+    }
+
+    fn operands_text(self: &Self, operands: &[&SyntaxToken], tight_operators: bool) -> String{
+        let mut text = String::new();
+        let mut previous: Option<&Lexem> = None;
+        let mut previous_unary = false;
+
+        for token in operands.iter(){
+            let lexem = &token.lexem;
+            let unary = lexem.ttype == LexemType::Operator && Formatter::is_unary(previous);
+
+            let space = match previous{
+                None => false,
+                Some(_) if is_single(lexem, ",") || is_single(lexem, ")") || is_single(lexem, ":") => false,
+                Some(p) if is_single(p, "(") => false,
+                Some(p) if is_single(p, ",") || is_single(p, ":") => true,
+                Some(p) if tight_operators && (lexem.ttype == LexemType::Operator || p.ttype == LexemType::Operator) => false,
+                Some(p) if p.ttype == LexemType::Operator => !previous_unary,
+                Some(_) if lexem.ttype == LexemType::Operator => true,
+                // warning(disable: ...) stays together, (a)(b) too
+                Some(p) if is_single(lexem, "(") => p.row != lexem.row || p.col + p.value.chars().count() != lexem.col,
+                Some(_) => true
+            };
+
+            if space{
+                text += " ";
+            }
+            text += self.token_text(token).as_str();
+
+            previous = Some(lexem);
+            previous_unary = unary;
+        }
+
+        text
+    }
+
+    fn split_lines(tree: &SyntaxTree) -> Vec<Line<'_>>{
+        let mut lines: Vec<Line> = tree.lines().into_iter().map(|line| {
+            let labels: Vec<&Lexem> = line.nodes(NodeKind::Label).into_iter().map(|label| &label.tokens()[0].lexem).collect();
+
+            let statement: Vec<&SyntaxToken> = match line.nodes(NodeKind::Statement).first(){
+                Some(statement) => statement.tokens(),
+                None => Vec::new()
+            };
+
+            // comments anywhere on the line end up after its code
+            Line { labels, statement, comments: line.comments() }
+        }).collect();
+
+        let trailing: Vec<&Trivia> = tree.trailing.iter().filter(|trivia| trivia.is_comment()).collect();
+        if !trailing.is_empty(){
+            lines.push(Line { labels: Vec::new(), statement: Vec::new(), comments: trailing });
+        }
+
+        lines
+    }
+
+    fn layout(self: &Self, line: &Line) -> Layout{
+        let mut layout = Layout {
+            prefix: String::new(),
+            prefix_width: 0,
+            mnemonic: String::new(),
+            operands: String::new(),
+            comment: None,
+            comment_at_start: false
+        };
+
+        if !line.comments.is_empty(){
+            let comments: Vec<&str> = line.comments.iter().map(|comment| comment.text.trim_end()).collect();
+            layout.comment = Some(comments.join(" "));
+        }
+
+        let indent_width = if self.config.tabs { self.config.indent.max(1) } else { self.config.indent };
+
+        if !line.labels.is_empty(){
+            let labels: Vec<String> = line.labels.iter().map(|label| format!("{}:", label.value)).collect();
+            layout.prefix = labels.join(" ");
+            layout.prefix_width = layout.prefix.chars().count();
+        }
+
+        let (mnemonic, operands) = match line.statement.split_first(){
+            Some(a) => a,
+            None => {
+                layout.comment_at_start = line.labels.is_empty() && line.comments.first().is_some_and(|comment| comment.col == 1);
+                if line.labels.is_empty() && !layout.comment_at_start && layout.comment.is_some(){
+                    layout.prefix = self.indent();
+                    layout.prefix_width = indent_width;
+                }
+                return layout;
+            }
+        };
+
+        let directive = mnemonic.lexem.value.to_lowercase();
+        let block = BLOCK_DIRECTIVES.contains(&directive.as_str());
+
+        if line.labels.is_empty(){
+            if !block{
+                layout.prefix = self.indent();
+                layout.prefix_width = indent_width;
+            }
+        }else if layout.prefix_width < indent_width && !block && !self.config.tabs{
+            layout.prefix += " ".repeat(indent_width - layout.prefix_width).as_str();
+            layout.prefix_width = indent_width;
+        }else{
+            layout.prefix += " ";
+            layout.prefix_width += 1;
+        }
+
+        layout.mnemonic = match mnemonic.lexem.ttype{
+            LexemType::Ident => apply_case(&mnemonic.lexem.value, self.config.mnemonic_case),
+            _ => self.token_text(mnemonic)
+        };
+        layout.operands = self.operands_text(operands, directive == "pragma");
+
+        layout
+    }
+
+    // column every line of a block aligns to, widths is None for lines that don't take part
+    fn column(align: Align, widths: &[Option<usize>], gap: usize) -> Vec<usize>{
+        match align{
+            Align::Off => widths.iter().map(|width| width.unwrap_or(0) + gap).collect(),
+            Align::Column(column) => widths.iter().map(|width| column.max(width.unwrap_or(0) + gap)).collect(),
+            Align::Block => {
+                let column = widths.iter().flatten().max().copied().unwrap_or(0) + gap;
+                widths.iter().map(|width| column.max(width.unwrap_or(0) + gap)).collect()
+            }
+        }
+    }
+
+    fn render_block(self: &Self, block: &[Layout]) -> Vec<String>{
+        let indent_width = if self.config.tabs { self.config.indent.max(1) } else { self.config.indent };
+
+        // only indented lines align, long labels in front of an instruction would push the whole block
+        let mnemonic_widths: Vec<Option<usize>> = block.iter().map(|layout| {
+            match !layout.operands.is_empty() && layout.prefix_width == indent_width{
+                true => Some(layout.prefix_width + layout.mnemonic.chars().count()),
+                false => None
+            }
+        }).collect();
+        let operand_columns = Formatter::column(self.config.operands, &mnemonic_widths, 1);
+
+        let mut code: Vec<String> = Vec::new();
+        let mut widths: Vec<usize> = Vec::new();
+
+        for (i, layout) in block.iter().enumerate(){
+            let mut text = layout.prefix.clone();
+            let mut width = layout.prefix_width;
+
+            text += layout.mnemonic.as_str();
+            width += layout.mnemonic.chars().count();
+
+            if !layout.operands.is_empty(){
+                let column = match mnemonic_widths[i]{
+                    Some(_) => operand_columns[i],
+                    None => width + 1
+                };
+                text += " ".repeat(column - width).as_str();
+                text += layout.operands.as_str();
+                width = column + layout.operands.chars().count();
+            }
+
+            code.push(text);
+            widths.push(width);
+        }
+
+        let comment_widths: Vec<Option<usize>> = block.iter().zip(widths.iter()).map(|(layout, width)| {
+            match layout.comment.is_some() && layout.has_code() && !layout.mnemonic.is_empty(){
+                true => Some(*width),
+                false => None
+            }
+        }).collect();
+        let comment_columns = Formatter::column(self.config.comments, &comment_widths, 1);
+
+        let mut lines: Vec<String> = Vec::new();
+
+        for (i, layout) in block.iter().enumerate(){
+            let mut text = code[i].clone();
+
+            if let Some(comment) = &layout.comment{
+                if comment_widths[i].is_some(){
+                    text += " ".repeat(comment_columns[i] - widths[i]).as_str();
+                }else if layout.has_code() && !layout.prefix.trim().is_empty(){
+                    text += " ";
+                }
+                text += comment.as_str();
+            }
+
+            lines.push(text.trim_end().to_string());
+        }
+
+        lines
+    }
+
+    pub fn format(self: &mut Self, tree: &SyntaxTree){
+        let layouts: Vec<Layout> = Formatter::split_lines(tree).iter().map(|line| self.layout(line)).collect();
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut block: Vec<Layout> = Vec::new();
+
+        for layout in layouts{
+            if layout.is_blank(){
+                lines.extend(self.render_block(&block));
+                block.clear();
+                lines.push(String::new());
+                continue;
+            }
+            block.push(layout);
+        }
+        lines.extend(self.render_block(&block));
+
+        while lines.last().is_some_and(|line| line.is_empty()){
+            lines.pop();
+        }
+
+        self.text = lines.join("\n");
+        if !self.text.is_empty(){
+            self.text += "\n";
+        }
+    }
+
+    fn same_value(self: &Self, a: &Lexem, b: &Lexem) -> bool{
+        match (&a.ttype, &b.ttype){
+            (LexemType::Number { radix: x }, LexemType::Number { radix: y }) => {
+                match (u128::from_str_radix(&a.value, *x as u32), u128::from_str_radix(&b.value, *y as u32)){
+                    (Ok(x), Ok(y)) => x == y,
+                    _ => a.value.eq_ignore_ascii_case(&b.value)
+                }
+            }
+            // mnemonics and registers are looked up in lowercase
+            (LexemType::Ident, LexemType::Ident) => a.value == b.value || a.value.to_lowercase() == b.value.to_lowercase(),
+            (x, y) => std::mem::discriminant(x) == std::mem::discriminant(y) && a.value == b.value
+        }
+    }
+
+    // the formatted text has to lex into the same program
+    pub fn verify(self: &Self, filename: &str, tree: &SyntaxTree) -> Result<(), Diagnostic>{
+        let mut lexer: Lexer = Lexer::new();
+        lexer.lex(filename, &self.text)?;
+
+        let meaningful = |lexems: &[Lexem]| -> Vec<Lexem>{
+            lexems.iter().filter(|lexem| lexem.ttype != LexemType::NewLine).cloned().collect()
+        };
+
+        let (before, after) = (meaningful(&tree.lexems()), meaningful(&lexer.lexems));
+
+        for (a, b) in before.iter().zip(after.iter()){
+            if !self.same_value(a, b){
+                return Err(Diagnostic::at(a, format!("Formatting would change \"{}\" into \"{}\"", a.value, b.value)));
+            }
+        }
+
+        if before.len() != after.len() || tree.comments(filename).len() != lexer.comments.len(){
+            return Err(Diagnostic::error(filename, 1, 1, "Formatting would change the program".to_string()));
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::{INSTRUCTIONS, TYPES};
+use crate::{Diagnostic, INSTRUCTIONS, TYPES};
 
 #[derive(Debug, Clone)]
 pub enum InstructionPart{
@@ -35,7 +35,7 @@ impl InstructionsLexer{
     }
 
     fn peek(self: &mut Self, str: &'static str) -> Option<char>{
-        str.chars().nth(self.cursor).clone()
+        str.chars().nth(self.cursor)
     }
 
     fn chop(self: &mut Self, str: &'static str) -> char{
@@ -63,7 +63,7 @@ impl InstructionsLexer{
             }
         }
 
-        if val.len() == 0{
+        if val.is_empty(){
             self.cursor = initial_cursor;
             return None;
         }
@@ -73,7 +73,7 @@ impl InstructionsLexer{
         
     }
 
-    fn chop_curly(self: &mut Self,name: &'static str, str: &'static str) -> Option<InstructionPart>{
+    fn chop_curly(self: &mut Self,name: &'static str, str: &'static str) -> Result<Option<InstructionPart>, Diagnostic>{
 
         let initial_cursor = self.cursor;
 
@@ -81,7 +81,7 @@ impl InstructionsLexer{
 
         if self.peek(str).unwrap() != '{'{
             self.cursor = initial_cursor;
-            return None
+            return Ok(None)
         }
 
         self.chop(str);
@@ -94,9 +94,8 @@ impl InstructionsLexer{
             ttype += self.chop(str).to_string().as_str();
         }
 
-        if ttype.len() == 0{
-            println!("Instruction Lexer \"{}\": You need to provide type for types", name);
-            std::process::exit(1);
+        if ttype.is_empty(){
+            return Err(Diagnostic::new(format!("Instruction Lexer \"{}\": You need to provide type for types", name)));
         }
 
         self.chop_white_space(str);
@@ -107,9 +106,8 @@ impl InstructionsLexer{
             size += self.chop(str).to_string().as_str();
         }
 
-        if size.len() == 0{
-            println!("Instruction Lexer \"{}\": You need to provide size for types", name);
-            std::process::exit(1);
+        if size.is_empty(){
+            return Err(Diagnostic::new(format!("Instruction Lexer \"{}\": You need to provide size for types", name)));
         }
 
         self.chop_white_space(str);
@@ -117,70 +115,62 @@ impl InstructionsLexer{
         let ch = self.chop(str);
 
         if ch != '}'{
-            println!("Instruction Lexer \"{}\": expected closed curly got {}", name, ch);
-            std::process::exit(1);
+            return Err(Diagnostic::new(format!("Instruction Lexer \"{}\": expected closed curly got {}", name, ch)));
         }
 
-        let size = usize::from_str_radix(&size, 10).unwrap();
+        let size = size.parse::<usize>().unwrap();
 
         match ttype.to_uppercase().as_str(){
             "IMM" => {
-                return Some(InstructionPart::Imm { size });
+                return Ok(Some(InstructionPart::Imm { size }));
             },
             "E" => {
-                return Some(InstructionPart::Extra { size });
+                return Ok(Some(InstructionPart::Extra { size }));
             }
             _ => {
 
                 if !TYPES.contains_key(ttype.to_uppercase().as_str()){
-                    println!("Instruction Lexer \"{}\": Unknown type {}", name, ttype.to_uppercase());
-                    std::process::exit(1);
+                    return Err(Diagnostic::new(format!("Instruction Lexer \"{}\": Unknown type {}", name, ttype.to_uppercase())));
                 }
 
-                return Some(InstructionPart::Type { val: ttype.to_uppercase().clone(), size });
+                return Ok(Some(InstructionPart::Type { val: ttype.to_uppercase().clone(), size }));
             }
         }
     }
 
-    fn lex_instruction(self: &mut Self, name: &'static str, instruction: &'static str) -> Vec<InstructionPart>{
+    fn lex_instruction(self: &mut Self, name: &'static str, instruction: &'static str) -> Result<Vec<InstructionPart>, Diagnostic>{
         
         let mut parts: Vec<InstructionPart> = Vec::new();
         
         self.cursor = 0;
         while self.cursor < instruction.len(){
 
-            match self.chop_ones_zeroes(instruction){
-                Some(x) => {
-                    parts.push(x);
-                    continue;
-                }
-                None => {}
+            if let Some(x) = self.chop_ones_zeroes(instruction){
+                parts.push(x);
+                continue;
             }
 
-            match self.chop_curly(name, instruction){
-                Some(x) => {
-                    parts.push(x);
-                    continue;
-                }
-                None => {}
+            if let Some(x) = self.chop_curly(name, instruction)?{
+                parts.push(x);
+                continue;
             }
 
-            println!("instruction_lexer: unknown character: \"{}\"", self.peek(instruction).unwrap());
-            dbg!(parts);
-            std::process::exit(1);
+            return Err(Diagnostic::new(format!("instruction_lexer \"{}\": unknown character: \"{}\"", name, self.peek(instruction).unwrap())));
         }
 
 
-        parts
+        Ok(parts)
     }
 
-    pub fn lex_instructions(self: &mut Self){
+    pub fn lex_instructions(self: &mut Self) -> Result<(), Diagnostic>{
         for (name, instruction) in INSTRUCTIONS.entries(){
             let name = *name;
             let instruction = *instruction;
 
-            let instruction = self.lex_instruction(name, instruction);
+            let instruction = self.lex_instruction(name, instruction)?;
             self.instructions.insert(name, instruction);
         }
+
+        Ok(())
     }
 }
\ No newline at end of file
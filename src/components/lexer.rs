@@ -1,6 +1,8 @@
+use crate::{Diagnostic, SyntaxToken, SyntaxTree, Trivia, TriviaKind};
+
 pub const SINGLE_LEXEMS: &[char] = &[',',':', '(', ')'];
 
-pub const OP_LEXEMS: &[&'static str] = &["+", "-", "/", "*", "&", "|", "^","<<", ">>"];
+pub const OP_LEXEMS: &[&str] = &["+", "-", "/", "*", "&", "|", "^","<<", ">>", "<=", ">=", "==", "!=", "<", ">"];
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum LexemType{
@@ -14,7 +16,8 @@ pub enum LexemType{
     Closure {
         args: [Box<Lexem>; 3]
     },
-    NewLine
+    NewLine,
+    Comment
 }
 
 impl PartialEq for Box<Lexem>{
@@ -33,6 +36,7 @@ impl std::fmt::Display for LexemType{
             LexemType::String => {write!(f, "String")}
             LexemType::Operator => {write!(f, "Operator")}
             LexemType::Closure {..} => {write!(f, "Closure")}
+            LexemType::Comment => {write!(f, "Comment")}
         }
     }
 }
@@ -54,32 +58,48 @@ impl Lexem{
 
 pub struct Lexer{
     content: String,
+    // in bytes, like cursor, which is always on a char boundary
+    length: usize,
     source_filename: String,
     cursor: usize,
     row: usize,
     col: usize,
+    // trivia waiting for the next token
+    trivia: Vec<Trivia>,
+    tokens: Vec<SyntaxToken>,
+    pub tree: SyntaxTree,
+    // derived from the tree
     pub lexems: Vec<Lexem>,
+    pub comments: Vec<Lexem>,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Lexer{
     pub fn new() -> Lexer{
         Lexer{
             content: String::new(),
+            length: 0,
             source_filename: String::new(),
             cursor: 0,
             row: 1,
             col: 1,
-            lexems: Vec::new()
+            trivia: Vec::new(),
+            tokens: Vec::new(),
+            tree: SyntaxTree::new(),
+            lexems: Vec::new(),
+            comments: Vec::new(),
+            diagnostics: Vec::new()
         }
     }
 
     fn peek(self: &Self) -> Option<char> {
-        return self.content.chars().nth(self.cursor)
+        return self.content[self.cursor..].chars().next()
     }
 
     fn chop(self: &mut Self) -> char{
         let ch = self.peek().unwrap();
-        self.cursor += 1;
+        self.cursor += ch.len_utf8();
+        // columns count chars
         self.col += 1;
         if ch == '\n'{
             self.row += 1;
@@ -88,153 +108,145 @@ impl Lexer{
         return ch;
     }
 
-    fn seek_whitespace(self: &mut Self){
-        while self.peek().unwrap().is_whitespace(){
-            if self.peek().unwrap() == '\n'{
-                break;
-            }
+    fn starts_with(self: &Self, pattern: &str) -> bool{
+        self.content[self.cursor..].starts_with(pattern)
+    }
+
+    fn text_from(self: &Self, start: usize) -> String{
+        self.content[start..self.cursor].to_string()
+    }
+
+    fn seek_whitespace(self: &mut Self) -> bool{
+        let start = self.cursor;
+        let row = self.row;
+        let col = self.col;
+
+        while self.cursor < self.length && self.peek().unwrap().is_whitespace() && self.peek().unwrap() != '\n'{
             self.chop();
-            if self.cursor >= self.content.len(){
-                break;
-            }
         }
-    }
 
-    fn chop_single(self: &mut Self) -> bool{
-        if self.cursor >= self.content.len(){
+        if self.cursor == start{
             return false;
         }
+
+        self.trivia.push(Trivia { kind: TriviaKind::Whitespace, text: self.text_from(start), span: (start, self.cursor), row, col });
+        return true;
+    }
+
+    fn chop_single(self: &mut Self) -> Option<Lexem>{
+        if self.cursor >= self.length{
+            return None;
+        }
         if self.peek().unwrap() == '\n'{
             let row = self.row;
             let col = self.col;
             let ch = self.chop();
-            self.lexems.push(Lexem::new(ch.to_string(), LexemType::NewLine, row, col, self.source_filename.clone()));
-            return true;
+            return Some(Lexem::new(ch.to_string(), LexemType::NewLine, row, col, self.source_filename.clone()));
         }
         if SINGLE_LEXEMS.contains(&self.peek().unwrap()) {
             let row = self.row;
             let col = self.col;
             let ch = self.chop();
-            self.lexems.push(Lexem::new(ch.to_string(), LexemType::Single,row, col, self.source_filename.clone()));
-            return true;
+            return Some(Lexem::new(ch.to_string(), LexemType::Single,row, col, self.source_filename.clone()));
         }
-        return false;
+        return None;
     }
 
-    fn chop_pattern(self: &mut Self) -> bool{
-        if self.cursor >= self.content.len(){
-            return false;
+    fn chop_pattern(self: &mut Self) -> Option<Lexem>{
+        if self.cursor >= self.length{
+            return None;
         }
 
-
-
         for pattern in OP_LEXEMS{
             let pattern = *pattern;
-            if self.content.len() - self.cursor >= pattern.len(){
-                if pattern == &self.content[self.cursor..self.cursor+pattern.len()]{
-                    self.lexems.push(Lexem::new(pattern.to_string(), LexemType::Operator, self.row, self.col, self.source_filename.clone()));
-                    for _ in 0..pattern.len(){
-                        self.chop();
-                    }
-                    return true;
+            if self.starts_with(pattern){
+                let lexem = Lexem::new(pattern.to_string(), LexemType::Operator, self.row, self.col, self.source_filename.clone());
+                for _ in pattern.chars(){
+                    self.chop();
                 }
+                return Some(lexem);
             }
         }
         
-        false
+        None
     }
 
-    fn chop_word(self: &mut Self) -> bool{
-        let mut lexem: String = String::new();
+    fn chop_word(self: &mut Self) -> Result<Option<Lexem>, Diagnostic>{
+        let start = self.cursor;
 
         let row = self.row;
         let col = self.col;
 
-        while self.cursor < self.content.len() && (self.peek().unwrap().is_alphanumeric() || self.peek().unwrap() == '.'){
-
-            lexem += self.chop().to_string().as_str();
+        while self.peek().is_some_and(|ch| ch.is_alphanumeric() || ch == '.' || ch == '@'){
+            self.chop();
         }
 
+        let lexem: String = self.text_from(start);
+
         if lexem.is_empty(){
-            return false;
+            return Ok(None);
         }
 
+
+        // local label references (1b, 1f) look like numbers
+        if lexem.len() > 1 && (lexem.ends_with('b') || lexem.ends_with('f')) && lexem[..lexem.len()-1].chars().all(|ch| ch.is_ascii_digit()){
+            return Ok(Some(Lexem::new(lexem, LexemType::Ident, row,col, self.source_filename.clone())));
+        }
         
         if lexem.starts_with("0x"){
             for (i, ch) in lexem.chars().skip(2).enumerate(){
                 if !ch.is_ascii_hexdigit(){
-                    println!("{}:{}:{} Expected hexlit got {}", self.source_filename, row, col+i+2, ch);
-                    std::process::exit(1);
+                    return Err(Diagnostic::error(&self.source_filename, row, col+i+2, format!("Expected hexlit got {}", ch)));
                 }
                 
             }
-            self.lexems.push(Lexem::new(lexem.chars().skip(2).collect(), LexemType::Number { radix: 16 }, row,col, self.source_filename.clone()));
-            return true;
+            return Ok(Some(Lexem::new(lexem.chars().skip(2).collect(), LexemType::Number { radix: 16 }, row,col, self.source_filename.clone())));
         }
         
         if lexem.starts_with("0b"){
             for (i, ch) in lexem.chars().skip(2).enumerate(){
                 if ch != '0' && ch != '1' {
-                    println!("{}:{}:{} Expected binlit got {}", self.source_filename, row, col+i+2, ch);
-                    std::process::exit(1);
+                    return Err(Diagnostic::error(&self.source_filename, row, col+i+2, format!("Expected binlit got {}", ch)));
                 }
                 
             }
-            self.lexems.push(Lexem::new(lexem.chars().skip(2).collect(), LexemType::Number { radix: 2 }, row,col, self.source_filename.clone()));
-            return true;
+            return Ok(Some(Lexem::new(lexem.chars().skip(2).collect(), LexemType::Number { radix: 2 }, row,col, self.source_filename.clone())));
         }
         
         if lexem.chars().nth(0).unwrap().is_numeric(){
             for (i, ch) in lexem.chars().enumerate(){
                 if !ch.is_numeric(){
-                    println!("{}:{}:{} Expected number got {}", self.source_filename, row, col+i, ch);
-                    std::process::exit(1);
+                    return Err(Diagnostic::error(&self.source_filename, row, col+i, format!("Expected number got {}", ch)));
                 }
 
             }
-            self.lexems.push(Lexem::new(lexem, LexemType::Number { radix: 10 }, row,col, self.source_filename.clone()));
-            return true;
+            return Ok(Some(Lexem::new(lexem, LexemType::Number { radix: 10 }, row,col, self.source_filename.clone())));
         }
 
-        self.lexems.push(Lexem::new(lexem, LexemType::Ident, row,col, self.source_filename.clone()));
-
-        return true;
+        return Ok(Some(Lexem::new(lexem, LexemType::Ident, row,col, self.source_filename.clone())));
     }
 
-    fn chop_string(self: &mut Self) -> bool{
+    fn chop_string(self: &mut Self) -> Result<Option<Lexem>, Diagnostic>{
         let row = self.row;
         let col = self.col;
 
-        let initial_cursor = self.cursor;
-        let initial_row = self.row;
-        let initial_col = self.col;
-
-        if self.cursor >= self.content.len(){
-            return false;
+        if self.cursor >= self.length{
+            return Ok(None);
         }
 
         if self.peek().unwrap() != '\"' && self.peek().unwrap() != '\''{
-            self.cursor = initial_cursor;
-            self.row = initial_row;
-            self.col = initial_col;
-            return false;
+            return Ok(None);
         }
 
         self.chop();
 
         let mut value = String::new();
 
-        while self.peek().unwrap() != '\"' && self.peek().unwrap() != '\''{
-            if self.cursor >= self.content.len(){
-                println!("{}:{}:{} Expected \" got end of file", self.source_filename, self.row, self.col);
-                std::process::exit(1);
-            }
-
+        while self.cursor < self.length && self.peek().unwrap() != '\"' && self.peek().unwrap() != '\''{
             if self.peek().unwrap() == '\\'{
                 self.chop();
-                if self.cursor >= self.content.len(){
-                    println!("{}:{}:{} Expected something got end of file", self.source_filename, self.row, self.col);
-                    std::process::exit(1);
+                if self.cursor >= self.length{
+                    return Err(Diagnostic::error(&self.source_filename, self.row, self.col, "Expected something got end of file".to_string()));
                 }
                 match self.chop(){
                     'n' => value += "\n",
@@ -243,7 +255,8 @@ impl Lexer{
                     '\"' => value += "\"",
                     '\'' => value += "\'",
                      a  => {
-                        println!("{}:{}:{} Unexpected character {}", self.source_filename, self.row, self.col, a);
+                        let at = Lexem::new(a.to_string(), LexemType::String, self.row, self.col, self.source_filename.clone());
+                        self.diagnostics.push(Diagnostic::warning("unknown-escape", &at, format!("Unknown escape sequence \\{}", a)));
                      }
                 };
 
@@ -254,83 +267,96 @@ impl Lexer{
             value += self.chop().to_string().as_str();
         }
 
-        self.chop();
+        if self.cursor >= self.length{
+            return Err(Diagnostic::error(&self.source_filename, self.row, self.col, "Expected \" got end of file".to_string()));
+        }
 
-        self.lexems.push(Lexem::new(value, LexemType::String, row, col, self.source_filename.clone() ));
+        self.chop();
 
-        return true;
+        return Ok(Some(Lexem::new(value, LexemType::String, row, col, self.source_filename.clone())));
     }
 
-    fn seek_comments(self: &mut Self){
-        
-        let initial_cursor = self.cursor;
-        let initial_row = self.row;
-        let initial_col = self.col;
-
-        if self.content.len() - self.cursor >= 2{
-
-
+    // // and ; run to the end of the line, /* */ to its closing */
+    fn seek_comments(self: &mut Self) -> Result<bool, Diagnostic>{
+        let start = self.cursor;
+        let row = self.row;
+        let col = self.col;
 
-            let test = self.chop().to_string() + self.chop().to_string().as_str();
-            if test == "//"{
-                if self.cursor >= self.content.len(){
-                    self.cursor = initial_cursor;
-                    self.row = initial_row;
-                    self.col = initial_col;
-                    return;
-                }
-                while self.peek().unwrap() != '\n'{
-                    self.chop();
-                    if self.cursor >= self.content.len(){
-                        self.cursor = initial_cursor;
-                        self.row = initial_row;
-                        self.col = initial_col;
-                        return;
-                    }
+        let kind = if self.starts_with("//") || self.starts_with(";"){
+            while self.cursor < self.length && self.peek().unwrap() != '\n'{
+                self.chop();
+            }
+            TriviaKind::LineComment
+        }else if self.starts_with("/*"){
+            self.chop();
+            self.chop();
+            while !self.starts_with("*/"){
+                if self.cursor >= self.length{
+                    return Err(Diagnostic::error(&self.source_filename, row, col, "Expected */ got end of file".to_string()));
                 }
-            }else{
-                self.cursor = initial_cursor;
-                self.row = initial_row;
-                self.col = initial_col;
-                return;
+                self.chop();
             }
-        }
+            self.chop();
+            self.chop();
+            TriviaKind::BlockComment
+        }else{
+            return Ok(false);
+        };
 
+        self.trivia.push(Trivia { kind, text: self.text_from(start), span: (start, self.cursor), row, col });
+        return Ok(true);
     }
 
-    fn chop_lexem(self: &mut Self){
+    fn chop_lexem(self: &mut Self) -> Result<(), Diagnostic>{
 
-        if self.cursor >= self.content.len() {return}
-        
-        self.seek_whitespace();
-        self.seek_comments();
+        while self.seek_whitespace() || self.seek_comments()? {}
 
+        if self.cursor >= self.length {return Ok(())}
 
-        if self.chop_single() {return}
+        let start = self.cursor;
 
-        if self.chop_pattern() {return}
-
-        if self.chop_string() {return}
-
-        if self.chop_word() {return}
+        let lexem = match self.chop_single(){
+            Some(a) => Some(a),
+            None => match self.chop_pattern(){
+                Some(a) => Some(a),
+                None => match self.chop_string()?{
+                    Some(a) => Some(a),
+                    None => self.chop_word()?
+                }
+            }
+        };
 
-        if self.cursor >= self.content.len(){
-            return;
+        if let Some(lexem) = lexem{
+            let leading = std::mem::take(&mut self.trivia);
+            self.tokens.push(SyntaxToken { leading, text: self.text_from(start), span: (start, self.cursor), lexem });
+            return Ok(());
         }
 
         
-        println!("{}:{}:{} unexpected character: \"{}\" at {}", self.source_filename, self.row, self.col, self.peek().unwrap(), self.cursor);
-        std::process::exit(1);
+        return Err(Diagnostic::error(&self.source_filename, self.row, self.col, format!("unexpected character: \"{}\" at {}", self.peek().unwrap(), self.cursor)));
 
     }
 
-    pub fn lex<'a>(self: &mut Self, source_filename: &'a str, content: &'a str){
+    pub fn lex<'a>(self: &mut Self, source_filename: &'a str, content: &'a str) -> Result<(), Diagnostic>{
         self.cursor = 0;
+        self.row = 1;
+        self.col = 1;
         self.content = content.to_string();
+        self.length = self.content.len();
+        self.trivia.clear();
+        self.tokens.clear();
         self.lexems.clear();
+        self.comments.clear();
+        self.diagnostics.clear();
         self.source_filename = source_filename.to_string();
-        while self.cursor < self.content.len(){
-            self.chop_lexem();
+        while self.cursor < self.length{
+            self.chop_lexem()?;
         }
+
+        self.tree = SyntaxTree::build(std::mem::take(&mut self.tokens), std::mem::take(&mut self.trivia));
+        self.lexems = self.tree.lexems();
+        self.comments = self.tree.comments(&self.source_filename);
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
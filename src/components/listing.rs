@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use crate::{Lexem, LexemType, Token};
+
+// Listing of the assembled program
+// every source line is followed by what it was expanded and encoded into
+//
+// test.a:3     limb R1, stack
+//   0001  8000      lui 0
+//   0002  78C6      lim r1, 35
+
+const WORDS_PER_ROW: usize = 4;
+
+pub fn lexem_text(lexem: &Lexem) -> String{
+    match &lexem.ttype{
+        LexemType::Number { radix: 16 } => format!("0x{}", lexem.value),
+        LexemType::Number { radix: 2 } => format!("0b{}", lexem.value),
+        LexemType::String => format!("{:?}", lexem.value),
+        LexemType::Closure { args } => format!("({} {} {})", lexem_text(&args[0]), lexem_text(&args[1]), lexem_text(&args[2])),
+        _ => lexem.value.clone()
+    }
+}
+
+// trimmed source line of lexem, empty when the file wasn't added
+pub fn source_line(sources: &HashMap<String, Vec<String>>, lexem: &Lexem) -> String{
+    match sources.get(&lexem.filename).and_then(|lines| lines.get(lexem.row.wrapping_sub(1))){
+        Some(line) => line.trim().to_string(),
+        None => String::new()
+    }
+}
+
+pub fn token_text(token: &Token) -> String{
+    match token{
+        Token::Instruction { name, args } => {
+            if args.is_empty(){
+                return name.value.clone();
+            }
+
+            format!("{} {}", name.value, args.iter().map(lexem_text).collect::<Vec<String>>().join(", "))
+        }
+        Token::Label { name } => format!("{}:", name.value)
+    }
+}
+
+// text shown in place of something that didn't end up in the output
+#[derive(Debug, Clone)]
+pub struct ListingNote{
+    // index of the token it is shown before
+    pub before: usize,
+    pub location: Lexem,
+    pub text: String
+}
+
+pub struct Listing<'a>{
+    tokens: &'a [Token],
+    spans: &'a [(usize, usize)],
+    bytes: &'a [u8],
+    sources: HashMap<String, Vec<String>>,
+    notes: Vec<ListingNote>,
+    pub text: String
+}
+
+impl Listing<'_>{
+    pub fn new<'a>(tokens: &'a [Token], spans: &'a [(usize, usize)], bytes: &'a [u8]) -> Listing<'a>{
+        Listing{
+            tokens,
+            spans,
+            bytes,
+            sources: HashMap::new(),
+            notes: Vec::new(),
+            text: String::new()
+        }
+    }
+
+    pub fn add_source(self: &mut Self, filename: &str, content: &str){
+        self.sources.insert(filename.to_string(), content.lines().map(|line| line.to_string()).collect());
+    }
+
+    pub fn add_notes(self: &mut Self, notes: &[ListingNote]){
+        self.notes.extend_from_slice(notes);
+        self.notes.sort_by_key(|note| note.before);
+    }
+
+    fn line_header(self: &Self, text: &mut String, last_line: &mut Option<(String, usize)>, lexem: &Lexem){
+        let line = (lexem.filename.clone(), lexem.row);
+        if last_line.as_ref() != Some(&line){
+            *text += format!("{}:{}\t{}\n", lexem.filename, lexem.row, source_line(&self.sources, lexem)).as_str();
+            *last_line = Some(line);
+        }
+    }
+
+    fn render_notes(self: &Self, text: &mut String, last_line: &mut Option<(String, usize)>, before: usize){
+        for note in self.notes.iter().filter(|note| note.before == before){
+            self.line_header(text, last_line, &note.location);
+            *text += format!("  {:4}  {:<width$}  // {}\n", "", "", note.text, width = WORDS_PER_ROW * 5 - 1).as_str();
+        }
+    }
+
+    pub fn render(self: &mut Self){
+        let mut text = String::new();
+        let mut last_line: Option<(String, usize)> = None;
+
+        for (i, (token, (start, end))) in self.tokens.iter().zip(self.spans.iter()).enumerate(){
+            self.render_notes(&mut text, &mut last_line, i);
+
+            let name = match token{
+                Token::Instruction { name, .. } | Token::Label { name } => name
+            };
+
+            self.line_header(&mut text, &mut last_line, name);
+
+            let words: Vec<String> = self.bytes[*start..*end].chunks(2).map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect()).collect();
+
+            for (i, row) in words.chunks(WORDS_PER_ROW).enumerate(){
+                let instruction = if i == 0 { token_text(token) } else { String::new() };
+                text += format!("  {:04X}  {:<width$}  {}\n", start / 2 + i * WORDS_PER_ROW, row.join(" "), instruction, width = WORDS_PER_ROW * 5 - 1).trim_end();
+                text += "\n";
+            }
+        }
+
+        self.render_notes(&mut text, &mut last_line, self.tokens.len());
+
+        self.text = text;
+    }
+}
@@ -1,6 +1,25 @@
 pub mod lexer;
+pub mod syntax;
 pub mod instruction_lexer;
 pub mod pseudo_instructions;
+pub mod constant_loads;
+pub mod peephole;
+pub mod repetitions;
+pub mod aliases;
+pub mod structs;
 pub mod parser;
 pub mod codegen;
-pub mod common;
\ No newline at end of file
+pub mod disassembler;
+pub mod listing;
+pub mod control_flow;
+pub mod stack_usage;
+pub mod dead_code;
+pub mod analysis;
+pub mod formatter;
+pub mod docs;
+pub mod conventions;
+pub mod emulator;
+pub mod unit_tests;
+pub mod assembler;
+pub mod common;
+pub mod diagnostics;
\ No newline at end of file
@@ -1,8 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::{get_value_from_number_token, Lexem, LexemType};
+use crate::{get_value_from_number_token, parse_pragma, Aliases, Diagnostic, Lexem, LexemType, ListingNote, Pragma, MEMORY_WORDS, REGISTERS_TO_VAL, VARIADIC_PSEUDO_INSTRUCTIONS};
 
 use super::pseudo_instructions::PseudoInstructions;
+use super::constant_loads::{ConstantLoads, CONSTANT_LOAD};
+use super::peephole::Peephole;
+use super::repetitions::Repetitions;
+use super::structs::Structs;
+
+// closures are parsed and evaluated recursively, this keeps the recursion off the end of the stack
+pub const MAX_CLOSURE_DEPTH: usize = 256;
 
 #[derive(Debug, Clone)]
 pub enum Token{
@@ -24,7 +31,101 @@ impl std::fmt::Display for Token{
     }
 }
 
-fn fix_sub_label(last_label: &String, args: Vec<Lexem>) -> Vec<Lexem>{
+// Sub-label scoping
+// main:       -> main
+// .loop:      -> main.loop
+// ..inner:    -> main.loop.inner
+// scope name ... endscope and proc name ... endp prefix every label inside them with name
+struct LabelScope{
+    scopes: Vec<String>,
+    // enclosing labels, index is the amount of leading dots
+    labels: Vec<String>,
+    frames: Vec<(Lexem, &'static str, Vec<String>)>
+}
+
+fn label_depth(name: &str) -> usize{
+    name.len() - name.trim_start_matches('.').len()
+}
+
+impl LabelScope{
+    fn new() -> LabelScope{
+        LabelScope{
+            scopes: Vec::new(),
+            labels: Vec::new(),
+            frames: Vec::new()
+        }
+    }
+
+    fn path(self: &Self, labels: &[String], name: &str) -> Vec<String>{
+        let mut path = self.scopes.clone();
+        for label in labels{
+            if !label.is_empty(){
+                path.push(label.clone());
+            }
+        }
+        path.push(name.to_string());
+        path
+    }
+
+    fn define(self: &mut Self, name: &Lexem) -> Result<Vec<String>, Diagnostic>{
+        let depth = label_depth(&name.value);
+        let bare = name.value.trim_start_matches('.');
+
+        if self.labels.len() < depth{
+            return Err(Diagnostic::at(name, format!("Sub-label {} has no parent label", name.value)));
+        }
+
+        self.labels.truncate(depth);
+        let path = self.path(&self.labels, bare);
+        self.labels.push(bare.to_string());
+
+        Ok(path)
+    }
+
+    fn resolve(self: &Self, arg: &Lexem) -> Result<String, Diagnostic>{
+        let depth = label_depth(&arg.value);
+
+        if depth == 0{
+            return Ok(arg.value.clone());
+        }
+
+        if self.labels.len() < depth{
+            return Err(Diagnostic::at(arg, format!("No enclosing label for {}", arg.value)));
+        }
+
+        Ok(self.path(&self.labels[..depth], arg.value.trim_start_matches('.')).join("."))
+    }
+
+    fn enter(self: &mut Self, opener: &Lexem, name: &Lexem, end: &'static str) -> Result<(), Diagnostic>{
+        if name.ttype != LexemType::Ident || name.value.starts_with("."){
+            return Err(Diagnostic::at(name, format!("Expected scope name got {}", name.value)));
+        }
+
+        self.frames.push((opener.clone(), end, self.labels.clone()));
+        self.scopes.push(name.value.clone());
+        self.labels = vec![String::new()];
+
+        Ok(())
+    }
+
+    fn leave(self: &mut Self, closer: &Lexem) -> Result<(), Diagnostic>{
+        match self.frames.pop(){
+            Some((_, end, labels)) => {
+                if end != closer.value.to_lowercase(){
+                    return Err(Diagnostic::at(closer, format!("Expected {} got {}", end, closer.value)));
+                }
+                self.scopes.pop();
+                self.labels = labels;
+                Ok(())
+            }
+            None => {
+                return Err(Diagnostic::at(closer, format!("{} without matching scope", closer.value)));
+            }
+        }
+    }
+}
+
+fn fix_sub_label(scope: &LabelScope, args: Vec<Lexem>) -> Result<Vec<Lexem>, Diagnostic>{
 
     let mut new_args: Vec<Lexem> = Vec::new();
 
@@ -39,7 +140,7 @@ fn fix_sub_label(last_label: &String, args: Vec<Lexem>) -> Vec<Lexem>{
                     new_arg.push(arg);
                 }
 
-                let new_arg = fix_sub_label(last_label, new_arg);
+                let new_arg = fix_sub_label(scope, new_arg)?;
 
                 let mut args: Vec<Box<Lexem>> = Vec::new();
 
@@ -51,13 +152,44 @@ fn fix_sub_label(last_label: &String, args: Vec<Lexem>) -> Vec<Lexem>{
             }
 
             LexemType::Ident => {
+                new_args.push(Lexem::new(scope.resolve(&arg)?, arg.ttype, arg.row, arg.col, arg.filename));
+            }
+
+            _ => {
+                new_args.push(arg);
+            }
+        }
+    }
 
-                if arg.value.starts_with("."){
-                    new_args.push(Lexem::new(last_label.clone()+arg.value.as_str(), arg.ttype, arg.row, arg.col, arg.filename));
-                }else{
-                    new_args.push(Lexem::new(arg.value.clone(), arg.ttype, arg.row, arg.col, arg.filename));
+    Ok(new_args)
+}
+
+// plain names used inside scope blocks prefer labels of the innermost scope
+fn fix_scoped_label(scopes: &[String], labels: &HashMap<String, usize>, args: Vec<Lexem>) -> Vec<Lexem>{
+
+    let mut new_args: Vec<Lexem> = Vec::new();
+
+    for arg in args{
+        match arg.ttype.clone() {
+
+            LexemType::Closure { args } => {
+                let new_arg = fix_scoped_label(scopes, labels, args.iter().map(|arg| *arg.clone()).collect());
+
+                new_args.push(Lexem::new(arg.value.clone(), LexemType::Closure { args: [Box::new(new_arg[0].clone()), Box::new(new_arg[1].clone()), Box::new(new_arg[2].clone())] }, arg.row, arg.col, arg.filename.clone()));
+            }
+
+            LexemType::Ident => {
+                let mut value = arg.value.clone();
+
+                for i in (1..=scopes.len()).rev(){
+                    let scoped = scopes[..i].join(".") + "." + arg.value.as_str();
+                    if labels.contains_key(&scoped){
+                        value = scoped;
+                        break;
+                    }
                 }
 
+                new_args.push(Lexem::new(value, arg.ttype, arg.row, arg.col, arg.filename));
             }
 
             _ => {
@@ -69,6 +201,83 @@ fn fix_sub_label(last_label: &String, args: Vec<Lexem>) -> Vec<Lexem>{
     new_args
 }
 
+// Local labels
+// 1: ... 1b / 1f  - nearest numeric label backward / forward
+// @@: ... @b / @f - nearest anonymous label backward / forward
+fn local_reference(value: &str) -> Option<(String, bool)>{
+    match value{
+        "@b" => return Some(("@@".to_string(), false)),
+        "@f" => return Some(("@@".to_string(), true)),
+        _ => {}
+    }
+
+    // digits and a b or f, all ascii so splitting off the last byte is safe
+    if value.len() < 2 || !value.is_ascii(){
+        return None;
+    }
+
+    let (number, direction) = value.split_at(value.len()-1);
+
+    if !number.chars().all(|ch| ch.is_ascii_digit()){
+        return None;
+    }
+
+    match direction{
+        "b" => Some((number.to_string(), false)),
+        "f" => Some((number.to_string(), true)),
+        _ => None
+    }
+}
+
+fn is_local_label(name: &Lexem) -> bool{
+    matches!(name.ttype, LexemType::Number { .. }) || name.value == "@@"
+}
+
+fn local_label_name(name: &str, index: usize) -> String{
+    format!("{}#{}", name, index)
+}
+
+fn fix_local_labels(args: Vec<Lexem>, local_counts: &HashMap<String, usize>, forward_references: &mut Vec<(Lexem, String, usize)>) -> Result<Vec<Lexem>, Diagnostic>{
+
+    let mut new_args: Vec<Lexem> = Vec::new();
+
+    for arg in args{
+        match arg.ttype.clone() {
+
+            LexemType::Closure { args } => {
+                let new_arg = fix_local_labels(args.iter().map(|arg| *arg.clone()).collect(), local_counts, forward_references)?;
+
+                new_args.push(Lexem::new(arg.value.clone(), LexemType::Closure { args: [Box::new(new_arg[0].clone()), Box::new(new_arg[1].clone()), Box::new(new_arg[2].clone())] }, arg.row, arg.col, arg.filename.clone()));
+            }
+
+            LexemType::Ident => {
+                match local_reference(&arg.value){
+                    Some((name, forward)) => {
+                        let count = *local_counts.get(&name).unwrap_or(&0);
+
+                        if forward{
+                            forward_references.push((arg.clone(), name.clone(), count));
+                            new_args.push(Lexem::new(local_label_name(&name, count), arg.ttype, arg.row, arg.col, arg.filename));
+                        }else{
+                            if count == 0{
+                                return Err(Diagnostic::at(&arg, format!("No local label {} defined before {}", name, arg.value)));
+                            }
+                            new_args.push(Lexem::new(local_label_name(&name, count-1), arg.ttype, arg.row, arg.col, arg.filename));
+                        }
+                    }
+                    None => new_args.push(arg)
+                }
+            }
+
+            _ => {
+                new_args.push(arg);
+            }
+        }
+    }
+
+    Ok(new_args)
+}
+
 
 fn unpseudo_arg(arg: Lexem, pseudo_name: &Lexem, arg_hashmap: &HashMap<String, Lexem>) -> Lexem{
     
@@ -94,83 +303,144 @@ fn unpseudo_arg(arg: Lexem, pseudo_name: &Lexem, arg_hashmap: &HashMap<String, L
     }
 }
 
-fn eval_closure(arg: Lexem, args: [Box<Lexem>; 3]) -> Lexem{
+pub fn eval_closure(arg: Lexem, args: [Box<Lexem>; 3]) -> Result<Lexem, Diagnostic>{
     
     let lhs = *args[0].clone();
     let lhs = match lhs.ttype.clone(){
         LexemType::Closure { args } => {
-            eval_closure( lhs, args)
+            eval_closure( lhs, args)?
         }
         _ => lhs
     };
 
     match lhs.ttype{
         LexemType::Number { .. } => {}
-        LexemType::Ident { .. } => {
-            println!("{}:{}:{} Use of undeclared label {}", lhs.filename, lhs.row, lhs.col, lhs.value);
-            std::process::exit(1);
+        LexemType::Ident => {
+            return Err(Diagnostic::at(&lhs, format!("Use of undeclared label {}", lhs.value)));
         }
         _ => {
-            println!("{}:{}:{} Expected Number got {}", lhs.filename, lhs.row, lhs.col, lhs.ttype);
-            std::process::exit(1);
+            return Err(Diagnostic::at(&lhs, format!("Expected Number got {}", lhs.ttype)));
         }
     }
 
     let rhs = *args[2].clone();
     let rhs = match rhs.ttype.clone(){
         LexemType::Closure { args } => {
-            eval_closure(rhs, args)
+            eval_closure(rhs, args)?
         }
         _ => rhs
     };
 
     match rhs.ttype{
         LexemType::Number { .. } => {}
-        LexemType::Ident { .. } => {
-            println!("{}:{}:{} Use of undeclared label {}", rhs.filename, rhs.row, rhs.col, rhs.value);
-            std::process::exit(1);
+        LexemType::Ident => {
+            return Err(Diagnostic::at(&rhs, format!("Use of undeclared label {}", rhs.value)));
         }
         _ => {
-            println!("{}:{}:{} Expected Number got {}", rhs.filename, rhs.row, rhs.col, rhs.ttype);
-            std::process::exit(1);
+            return Err(Diagnostic::at(&rhs, format!("Expected Number got {}", rhs.ttype)));
         }
     }
     
 
-    let lhs = get_value_from_number_token(&lhs);
-    let rhs = get_value_from_number_token(&rhs);
+    let lhs = get_value_from_number_token(&lhs)?;
+    let rhs = get_value_from_number_token(&rhs)?;
 
     let op = *args[1].clone();
     if op.ttype != LexemType::Operator{
-        println!("{}:{}:{} Expected Operator got {}", op.filename, op.row, op.col, op.ttype);
-        std::process::exit(1);
-    }
-
-    let ret_val: usize;
-
-    match op.value.as_str(){
-        "+" => {ret_val = lhs+rhs},
-        "-" => {ret_val = lhs-rhs},
-        "*" => {ret_val = lhs*rhs},
-        "/" => {ret_val = lhs/rhs},
-        "&" => {ret_val = lhs&rhs},
-        "|" => {ret_val = lhs|rhs},
-        "^" => {ret_val = lhs^rhs},
-        "<<" => {ret_val = lhs<<rhs},
-        ">>" => {ret_val = lhs>>rhs},
+        return Err(Diagnostic::at(&op, format!("Expected Operator got {}", op.ttype)));
+    }
+
+    let ret_val: Option<usize> = match op.value.as_str(){
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" => {
+            if rhs == 0{
+                return Err(Diagnostic::at(&op, format!("Division by zero in ({} / {})", lhs, rhs)));
+            }
+            Some(lhs / rhs)
+        }
+        "&" => Some(lhs&rhs),
+        "|" => Some(lhs|rhs),
+        "^" => Some(lhs^rhs),
+        // bits shifted out are lost, only the amount is checked
+        "<<" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+        ">>" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+        "<" => Some((lhs<rhs) as usize),
+        ">" => Some((lhs>rhs) as usize),
+        "<=" => Some((lhs<=rhs) as usize),
+        ">=" => Some((lhs>=rhs) as usize),
+        "==" => Some((lhs==rhs) as usize),
+        "!=" => Some((lhs!=rhs) as usize),
         _ => {
-            println!("{}:{}:{} Invalid Operator {}",op.filename, op.row, op.col, op.value);
-            std::process::exit(1);
+            return Err(Diagnostic::at(&op, format!("Invalid Operator {}", op.value)));
+        }
+    };
+
+    let ret_val = match ret_val{
+        Some(a) => a,
+        None => return Err(Diagnostic::at(&op, format!("Result of ({} {} {}) is out of range", lhs, op.value, rhs)))
+    };
+
+    Ok(Lexem::new(format!("{}", ret_val), LexemType::Number { radix: 10 }, arg.row, arg.col, arg.filename))
+}
+
+fn colapse_args(args: &[Lexem]) -> Result<Vec<Lexem>, Diagnostic>{
+    let mut new_args: Vec<Lexem> = Vec::new();
+
+    for arg in args{
+        match arg.ttype.clone(){
+            LexemType::Closure { args } => new_args.push(eval_closure(arg.clone(), args)?),
+            _ => new_args.push(arg.clone())
         }
     }
 
-    Lexem::new(format!("{}", ret_val), LexemType::Number { radix: 10 }, arg.row, arg.col, arg.filename)
+    Ok(new_args)
+}
+
+fn insert_label(labels: &mut HashMap<String, usize>, definitions: &mut Vec<(Lexem, Vec<String>, usize)>, name: &Lexem, path: Vec<String>, scope_depth: usize, address: usize) -> Result<(), Diagnostic>{
+    let full_name = path.join(".");
+
+    if labels.contains_key(&full_name){
+        return Err(Diagnostic::at(name, format!("Label already defined {}", full_name)));
+    }
+
+    labels.insert(full_name, address);
+    definitions.push((name.clone(), path, scope_depth));
+
+    Ok(())
 }
 
 pub struct Parser{
     cursor: usize,
     lexems: Vec<Lexem>,
-    pub tokens: Vec<Token>
+    pub tokens: Vec<Token>,
+    pub pragmas: Vec<Pragma>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub aliases: Aliases,
+    pub peephole: Peephole,
+    // what the peephole pass removed, for the listing
+    pub notes: Vec<ListingNote>,
+    // every label and proc with its address, local ones by their plain name
+    pub code_labels: Vec<(String, usize)>,
+    // export/entry symbols, reachable from outside
+    pub entry_labels: Vec<(String, usize)>,
+    // every label and constant by full name
+    pub labels: HashMap<String, usize>,
+    // full name and where it is used, for every operand naming a label
+    pub label_references: Vec<(String, Lexem)>,
+    // full proc name and the registers of its uses list
+    pub proc_uses: Vec<(String, Vec<Lexem>)>,
+    // name of every test ... endtest block and its statements, left out of the program
+    pub tests: Vec<(Lexem, Vec<Token>)>,
+    local_definitions: Vec<(String, Lexem)>,
+    entry_points: Vec<Lexem>,
+    constant_loads: ConstantLoads,
+    // index in tokens before labels and directives were removed, for every token after
+    token_origins: Vec<usize>,
+    token_addresses: Vec<usize>,
+    label_definitions: Vec<(String, Lexem)>,
+    referenced_labels: HashSet<String>
 }
 
 impl Parser{
@@ -178,7 +448,25 @@ impl Parser{
         Parser{
             cursor: 0,
             lexems: Vec::new(),
-            tokens: Vec::new()
+            tokens: Vec::new(),
+            pragmas: Vec::new(),
+            diagnostics: Vec::new(),
+            aliases: Aliases::new(),
+            peephole: Peephole::new(),
+            notes: Vec::new(),
+            code_labels: Vec::new(),
+            entry_labels: Vec::new(),
+            labels: HashMap::new(),
+            label_references: Vec::new(),
+            proc_uses: Vec::new(),
+            tests: Vec::new(),
+            local_definitions: Vec::new(),
+            entry_points: Vec::new(),
+            constant_loads: ConstantLoads::new(),
+            token_origins: Vec::new(),
+            token_addresses: Vec::new(),
+            label_definitions: Vec::new(),
+            referenced_labels: HashSet::new()
         }
     }
 
@@ -193,11 +481,8 @@ impl Parser{
     }
 
     fn chop_newline(self: &mut Self){
-        while self.peek_lexem().unwrap().ttype == LexemType::NewLine{
+        while self.peek_lexem().is_some_and(|lexem| lexem.ttype == LexemType::NewLine){
             self.chop_lexem();
-            if self.cursor >= self.lexems.len(){
-                return;
-            }
         }
     }
 
@@ -219,7 +504,7 @@ impl Parser{
         }
 
 
-        if self.peek_lexem().unwrap().ttype != LexemType::Ident{
+        if self.peek_lexem().unwrap().ttype != LexemType::Ident && self.peek_lexem().unwrap().ttype != (LexemType::Number { radix: 10 }){
             self.cursor = initial_cursor;
             return false;
         }
@@ -243,60 +528,78 @@ impl Parser{
         return true;
     }
 
-    fn parse_arg(self: &mut Self) -> Lexem{
-        let lexem = self.chop_lexem();
+    // the next lexem, end of file is reported right after the previous one
+    fn expect_lexem(self: &mut Self, expected: &str) -> Result<Lexem, Diagnostic>{
+        match self.peek_lexem(){
+            Some(lexem) => {
+                self.cursor += 1;
+                Ok(lexem)
+            }
+            None => {
+                let last = &self.lexems[self.cursor.saturating_sub(1).min(self.lexems.len().saturating_sub(1))];
+                Err(Diagnostic::error(&last.filename, last.row, last.col+last.value.len(), format!("Expected {} got end of file", expected)))
+            }
+        }
+    }
+
+    fn parse_arg(self: &mut Self) -> Result<Lexem, Diagnostic>{
+        return self.parse_nested_arg(0);
+    }
+
+    fn parse_nested_arg(self: &mut Self, depth: usize) -> Result<Lexem, Diagnostic>{
+        let lexem = self.expect_lexem("arg")?;
 
         if lexem.value == "("{
-            
-            
-            let lhs = Box::new(self.parse_arg());
-            let operator = Box::new(self.parse_arg());
-            let rhs = Box::new(self.parse_arg());
+            if depth >= MAX_CLOSURE_DEPTH{
+                return Err(Diagnostic::at(&lexem, format!("Expression nested deeper than {}", MAX_CLOSURE_DEPTH)));
+            }
+
+            let lhs = Box::new(self.parse_nested_arg(depth+1)?);
+            let operator = Box::new(self.parse_nested_arg(depth+1)?);
+            let rhs = Box::new(self.parse_nested_arg(depth+1)?);
             let lexem = Lexem::new("Closure".to_string(), LexemType::Closure { args: [lhs, operator, rhs] }, lexem.row, lexem.col, lexem.filename);
 
-            let test = self.chop_lexem();
+            let test = self.expect_lexem("\")\"")?;
 
             if test.value == ")"{
-                return lexem;
+                return Ok(lexem);
             }else{
-                println!("{}:{}:{} Expected \")\" got \"{}\"", test.filename, test.row, test.col, test.value);
-                std::process::exit(1);
+                return Err(Diagnostic::at(&test, format!("Expected \")\" got \"{}\"", test.value)));
             }
         }else{
-            return lexem;
+            return Ok(lexem);
         }
     }
 
-    fn parse_args(self: &mut Self) -> Option<Vec<Lexem>>{
+    fn parse_args(self: &mut Self) -> Result<Vec<Lexem>, Diagnostic>{
 
         let mut args: Vec<Lexem> = Vec::new();
 
         // let arg_types = &[LexemType::Number{radix: 0} ,LexemType::Ident, LexemType::Register];
 
         if self.cursor >= self.lexems.len(){
-            return  Some(Vec::new());
+            return  Ok(Vec::new());
         }
 
         if self.peek_lexem().unwrap().ttype == LexemType::NewLine{
-            return Some(Vec::new());
+            return Ok(Vec::new());
         }
 
 
-        args.push(self.parse_arg());
+        args.push(self.parse_arg()?);
 
         if self.cursor >= self.lexems.len(){
-            return Some(args);
+            return Ok(args);
         }
 
         while self.cursor < self.lexems.len() && self.peek_lexem().unwrap().value == ","{
             let x = self.chop_lexem();
 
             if self.cursor >= self.lexems.len(){
-                println!("{}:{}:{} Expected arg got end of file", x.filename, x.row, x.col+1);
-                std::process::exit(1);
+                return Err(Diagnostic::error(&x.filename, x.row, x.col+1, "Expected arg got end of file".to_string()));
             }
 
-            args.push(self.parse_arg());
+            args.push(self.parse_arg()?);
 
             if self.cursor < self.lexems.len() && self.peek_lexem().unwrap().ttype == LexemType::NewLine{
                 break;
@@ -304,10 +607,117 @@ impl Parser{
 
         }
 
-        return Some(args);
+        return Ok(args);
+    }
+
+    // pragma arguments don't follow the usual syntax, they run until the end of line
+    fn parse_pragma(self: &mut Self, name: &Lexem) -> Result<(), Diagnostic>{
+        let mut args: Vec<Lexem> = Vec::new();
+
+        while self.cursor < self.lexems.len() && self.peek_lexem().unwrap().ttype != LexemType::NewLine{
+            args.push(self.chop_lexem());
+        }
+
+        self.pragmas.append(&mut parse_pragma(name, &args)?);
+
+        Ok(())
+    }
+
+    fn expect_on_line(self: &mut Self, after: &Lexem, expected: &str) -> Result<Lexem, Diagnostic>{
+        match self.peek_lexem(){
+            Some(lexem) if lexem.ttype != LexemType::NewLine => {
+                self.cursor += 1;
+                Ok(lexem)
+            }
+            _ => Err(Diagnostic::error(&after.filename, after.row, after.col+after.value.len(), format!("Expected {} got end of line", expected)))
+        }
+    }
+
+    fn expect_ident(self: &mut Self, after: &Lexem, expected: &str) -> Result<Lexem, Diagnostic>{
+        let lexem = self.expect_on_line(after, expected)?;
+
+        if lexem.ttype != LexemType::Ident{
+            return Err(Diagnostic::at(&lexem, format!("Expected {} got {}", expected, lexem.value)));
+        }
+
+        Ok(lexem)
+    }
+
+    fn expect_value(self: &mut Self, after: &Lexem, expected: &str) -> Result<Lexem, Diagnostic>{
+        let lexem = self.expect_on_line(after, &format!("\"{}\"", expected))?;
+
+        if lexem.value != expected{
+            return Err(Diagnostic::at(&lexem, format!("Expected \"{}\" got \"{}\"", expected, lexem.value)));
+        }
+
+        Ok(lexem)
+    }
+
+    fn expect_arg(self: &mut Self, after: &Lexem, expected: &str) -> Result<Lexem, Diagnostic>{
+        self.expect_on_line(after, expected)?;
+        self.cursor -= 1;
+
+        self.parse_arg()
+    }
+
+    // field name: type [* count]
+    fn parse_field(self: &mut Self, name: &Lexem) -> Result<(), Diagnostic>{
+        let field = self.expect_ident(name, "field name")?;
+        let colon = self.expect_value(&field, ":")?;
+        let ttype = self.expect_ident(&colon, "type")?;
+
+        let mut args = vec![field, ttype];
+
+        if self.peek_lexem().is_some_and(|lexem| lexem.value == "*"){
+            let star = self.chop_lexem();
+            args.push(self.expect_arg(&star, "count")?);
+        }
+
+        self.tokens.push(Token::Instruction { name: name.clone(), args });
+
+        Ok(())
+    }
+
+    // mmio [instance:] struct at address
+    fn parse_mmio(self: &mut Self, name: &Lexem) -> Result<(), Diagnostic>{
+        let instance = self.expect_ident(name, "struct name")?;
+        let mut ttype = instance.clone();
+
+        if self.peek_lexem().is_some_and(|lexem| lexem.value == ":"){
+            let colon = self.chop_lexem();
+            ttype = self.expect_ident(&colon, "struct name")?;
+        }
+
+        let at = self.expect_value(&ttype, "at")?;
+        let address = self.expect_arg(&at, "address")?;
+
+        self.tokens.push(Token::Instruction { name: name.clone(), args: vec![instance, ttype, address] });
+
+        Ok(())
     }
 
-    fn parse_lexem_instruction(self: &mut Self) -> bool{
+    // proc name [uses reg, reg, ...]
+    fn parse_proc(self: &mut Self, name: &Lexem) -> Result<(), Diagnostic>{
+        let routine = self.expect_ident(name, "proc name")?;
+        let mut args = vec![routine];
+
+        if self.peek_lexem().is_some_and(|lexem| lexem.value.to_lowercase() == "uses"){
+            let uses = self.chop_lexem();
+            let registers = self.parse_args()?;
+
+            if registers.is_empty(){
+                return Err(Diagnostic::error(&uses.filename, uses.row, uses.col+uses.value.len(), "Expected register got end of line".to_string()));
+            }
+
+            args.extend(registers);
+        }
+
+        self.tokens.push(Token::Instruction { name: name.clone(), args });
+
+        Ok(())
+    }
+
+    fn parse_lexem_instruction(self: &mut Self) -> Result<bool, Diagnostic>{
 
         
         let initial_cursor = self.cursor;
@@ -316,80 +726,133 @@ impl Parser{
 
         if self.cursor >= self.lexems.len(){
             self.cursor = initial_cursor;
-            return false;
+            return Ok(false);
         }
         
         if self.peek_lexem().unwrap().ttype != LexemType::Ident{
             self.cursor = initial_cursor;
-            return false;
+            return Ok(false);
         }
 
         let name = self.chop_lexem();
 
-        let args = match self.parse_args(){
-            Some(a) => a,
-            None => {
-                self.cursor = initial_cursor;
-                return false;
+        match name.value.to_lowercase().as_str(){
+            "pragma" => {
+                self.parse_pragma(&name)?;
+                return Ok(true);
             }
-        };
+            "field" => {
+                self.parse_field(&name)?;
+                return Ok(true);
+            }
+            "mmio" => {
+                self.parse_mmio(&name)?;
+                return Ok(true);
+            }
+            "proc" => {
+                self.parse_proc(&name)?;
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        let args = self.parse_args()?;
 
         self.tokens.push(Token::Instruction { name, args });
 
 
-        return true;
+        return Ok(true);
     }
 
-    fn parse_token(self: &mut Self){
-        if self.parse_lexem_label(){return}
+    fn parse_token(self: &mut Self) -> Result<(), Diagnostic>{
+        if self.parse_lexem_label(){return Ok(())}
         
-        if self.parse_lexem_instruction(){return}
+        if self.parse_lexem_instruction()?{return Ok(())}
         
         self.chop_newline();
 
         if self.cursor >= self.lexems.len(){
-            return;
+            return Ok(());
         }
 
         let lexem = self.peek_lexem().unwrap();
-        println!("{}:{}:{} got unexpected token {}", lexem.filename, lexem.row, lexem.col, lexem.value);
         // dbg!(&self.tokens);
-        std::process::exit(1);
+        return Err(Diagnostic::at(&lexem, format!("got unexpected token {}", lexem.value)));
     }
 
-    pub fn first_stage_parse<'a>(self: &mut Self, lexems: &Vec<Lexem>){
-        self.lexems = lexems.clone();
+    pub fn first_stage_parse(self: &mut Self, lexems: &[Lexem]) -> Result<(), Diagnostic>{
+        self.lexems = lexems.to_vec();
         self.cursor = 0;
 
         self.tokens.clear();
+        self.pragmas.clear();
+        self.diagnostics.clear();
+        self.notes.clear();
         
         while self.cursor < self.lexems.len(){
-            self.parse_token()
+            self.parse_token()?
         }
+
+        Ok(())
     }
 
 
-    fn convert_pseudo_instructions(self: &mut Self){
-        let pseudo_instructions = PseudoInstructions::initialize();
+    fn expand_repetitions(self: &mut Self) -> Result<(), Diagnostic>{
+        let mut repetitions: Repetitions = Repetitions::new();
+
+        self.tokens = repetitions.expand(&self.tokens)?;
+
+        Ok(())
+    }
+
+    fn resolve_aliases(self: &mut Self) -> Result<(), Diagnostic>{
+        self.tokens = self.aliases.resolve(&self.tokens)?;
+        self.diagnostics.append(&mut self.aliases.diagnostics);
+
+        Ok(())
+    }
+
+    fn convert_pseudo_instructions(self: &mut Self) -> Result<(), Diagnostic>{
+        let pseudo_instructions = PseudoInstructions::initialize()?;
 
         let mut after_pseudo: Vec<Token> = Vec::new();
 
         for token in self.tokens.iter_mut(){
+            let mut expanded: Vec<Token> = Vec::new();
+
             match token{
                 Token::Instruction { name, args } =>{
-                    if pseudo_instructions.keys().collect::<Vec<&String>>().contains(&&name.value){
+                    // sized once labels are known, see constant_loads
+                    if name.value == CONSTANT_LOAD{
+                        if args.len() != 2{
+                            return Err(Diagnostic::at(name, format!("Expects 2 ammount of args got {}", args.len())));
+                        }
+                        expanded.push(Token::Instruction {  name: name.clone(), args: args.clone() });
+                    }else if let Some((instruction, reversed)) = VARIADIC_PSEUDO_INSTRUCTIONS.get(name.value.as_str()){
+                        if args.is_empty(){
+                            return Err(Diagnostic::at(name, format!("{} expects at least one register", name.value)));
+                        }
+
+                        let mut args = args.clone();
+                        if *reversed{
+                            args.reverse();
+                        }
+
+                        for arg in args{
+                            expanded.push(Token::Instruction { name: Lexem::new(instruction.to_string(), name.ttype.clone(), name.row, name.col, name.filename.clone()), args: vec![arg] });
+                        }
+                    }else if pseudo_instructions.keys().collect::<Vec<&String>>().contains(&&name.value){
                         let mut arg_hashmap: HashMap<String, Lexem> = HashMap::new();
 
                         let pseudo = match pseudo_instructions.get(name.value.as_str()){
                             Some(a) => a,
                             None => {
-                                println!("{}:{}:{} Pasrser pseudo_instructions: Impossible Error", name.filename, name.row, name.col);
-                                std::process::exit(1);
+                                return Err(Diagnostic::at(name, "Pasrser pseudo_instructions: Impossible Error".to_string()));
                             }
                         }.clone();
 
                         if args.len() != pseudo.0.len(){
-                            println!("{}:{}:{} Expects {} ammount of args got {}", name.filename, name.row, name.col, pseudo.0.len(), args.len());
+                            return Err(Diagnostic::at(name, format!("Expects {} ammount of args got {}", pseudo.0.len(), args.len())));
                         }
 
                         for (i, arg) in pseudo.0.iter().enumerate(){
@@ -405,29 +868,32 @@ impl Parser{
                                     for arg in args{
                                         new_args.push(unpseudo_arg(arg, pseudo_name, &arg_hashmap));
                                     }
-                                    after_pseudo.push(Token::Instruction { name, args: new_args });
-                                    // after_pseudo.push(Token::Instruction { name: Lexem::new(name.value, name.ttype, pseudo_name.row, pseudo_name.col, pseudo_name.filename.clone()), args: new_args });
+                                    // expanded instructions report at the call site
+                                    expanded.push(Token::Instruction { name: Lexem::new(name.value, name.ttype, pseudo_name.row, pseudo_name.col, pseudo_name.filename.clone()), args: new_args });
                                 }
                                 Token::Label { name } => {
-                                    println!("{}:{}:{} Currently labels are not possible inside pseudo instruction: {}", pseudo_name.filename, pseudo_name.row, pseudo_name.col, name.value);
+                                    return Err(Diagnostic::at(pseudo_name, format!("Currently labels are not possible inside pseudo instruction: {}", name.value)));
                                 }
                             }
                         }
 
-
                     }else{
-                        after_pseudo.push(Token::Instruction {  name: name.clone(), args: args.clone() });
+                        expanded.push(Token::Instruction {  name: name.clone(), args: args.clone() });
                     }
                 },
                 Token::Label { name } => {
-                    after_pseudo.push(Token::Label { name: name.clone() });
+                    expanded.push(Token::Label { name: name.clone() });
                 }
             }
+
+            after_pseudo.append(&mut expanded);
         }
         self.tokens = after_pseudo;
+
+        Ok(())
     }
 
-    fn discover_labels(self: &mut Self) -> (Vec<Token>, HashMap<String, usize>) {
+    fn discover_labels(self: &mut Self) -> Result<(Vec<Token>, HashMap<String, usize>), Diagnostic> {
         let mut origin: usize = 0;
         self.cursor = 0;
 
@@ -435,31 +901,162 @@ impl Parser{
 
         let mut labels: HashMap<String, usize> = HashMap::new();
 
-        let mut last_label = String::new();
+        let mut scope = LabelScope::new();
+        let mut definitions: Vec<(Lexem, Vec<String>, usize)> = Vec::new();
+        let mut token_scopes: Vec<Vec<String>> = Vec::new();
+        let mut token_origins: Vec<usize> = Vec::new();
+        let mut token_addresses: Vec<usize> = Vec::new();
+        let mut code_labels: Vec<(String, usize)> = Vec::new();
+        let mut entry_points: Vec<Lexem> = Vec::new();
+        let mut local_definitions: Vec<(String, Lexem)> = Vec::new();
+        let mut proc_uses: Vec<(String, Vec<Lexem>)> = Vec::new();
+        // name, statements and the scope the test is in
+        let mut tests: Vec<(Lexem, Vec<Token>, Vec<String>)> = Vec::new();
+        let mut open_test: Option<(Lexem, Vec<Token>, Vec<String>)> = None;
+
+        let mut local_counts: HashMap<String, usize> = HashMap::new();
+        let mut forward_references: Vec<(Lexem, String, usize)> = Vec::new();
+
+        let mut structs: Structs = Structs::new();
+        // offsets an mmio instance named after its struct may replace
+        let mut struct_constants: HashSet<String> = HashSet::new();
+
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        // set after hlt/ret until the next label
+        let mut unreachable = false;
         
-        for token in self.tokens.iter(){
+        for (i, token) in self.tokens.iter().enumerate(){
             match token{
                 Token::Instruction { name, args } => {
                     
                     let name = name.clone();
-                    let args = args.clone();
+                    let args = fix_local_labels(args.clone(), &local_counts, &mut forward_references)?;
+
+                    let directive = name.value.to_lowercase();
+
+                    if open_test.is_some(){
+                        match directive.as_str(){
+                            "endtest" => tests.extend(open_test.take()),
+                            "test" => return Err(Diagnostic::at(&name, "Expected endtest got test".to_string())),
+                            _ => {
+                                let args = fix_sub_label(&scope, args)?;
+                                if let Some((_, statements, _)) = open_test.as_mut(){
+                                    statements.push(Token::Instruction { name, args });
+                                }
+                            }
+                        }
+                        continue;
+                    }
+
+                    if structs.is_open() && directive != "field" && directive != "endstruct"{
+                        return Err(Diagnostic::at(&name, format!("Expected field or endstruct got {}", name.value)));
+                    }
+
+                    match directive.as_str() {
+                        "struct" => {
+                            structs.begin(&name, &args)?;
+                        }
+
+                        "field" => {
+                            structs.field(&name, &args, &labels)?;
+                        }
+
+                        "endstruct" => {
+                            for (constant, value) in structs.end(&name)?{
+                                if labels.contains_key(&constant){
+                                    return Err(Diagnostic::at(&name, format!("Label already defined {}", constant)));
+                                }
+                                labels.insert(constant.clone(), value);
+                                struct_constants.insert(constant);
+                            }
+                        }
+
+                        "mmio" => {
+                            for (constant, value) in structs.instance(&args, &labels)?{
+                                if labels.contains_key(&constant) && !struct_constants.contains(&constant){
+                                    return Err(Diagnostic::at(&args[0], format!("Label already defined {}", constant)));
+                                }
+                                struct_constants.remove(&constant);
+                                labels.insert(constant, value);
+                            }
+                        }
+
+                        "scope" => {
+                            if args.len() != 1{
+                                return Err(Diagnostic::at(&name, "scope expects name".to_string()));
+                            }
+
+                            scope.enter(&name, &args[0], "endscope")?;
+                        }
+
+                        "proc" => {
+                            if args.is_empty(){
+                                return Err(Diagnostic::at(&name, "proc expects name".to_string()));
+                            }
+
+                            for register in args[1..].iter(){
+                                if !REGISTERS_TO_VAL.contains_key(register.value.to_lowercase().as_str()){
+                                    return Err(Diagnostic::at(register, format!("Expected register got {}", register.value)));
+                                }
+                            }
+
+                            let path = scope.define(&args[0])?;
+                            if args.len() > 1{
+                                proc_uses.push((path.join("."), args[1..].to_vec()));
+                            }
+                            code_labels.push((path.join("."), origin+self.cursor));
+                            insert_label(&mut labels, &mut definitions, &args[0], path, scope.scopes.len(), origin+self.cursor)?;
+                            unreachable = false;
+
+                            scope.enter(&name, &args[0], "endp")?;
+                        }
+
+                        "export" | "entry" => {
+                            if args.is_empty(){
+                                return Err(Diagnostic::at(&name, format!("{} expects label", name.value)));
+                            }
+
+                            for arg in fix_sub_label(&scope, args)?{
+                                if arg.ttype != LexemType::Ident{
+                                    return Err(Diagnostic::at(&arg, format!("Expected label got {}", arg.value)));
+                                }
+                                entry_points.push(arg);
+                            }
+                        }
+
+                        "test" => {
+                            if args.len() != 1 || args[0].ttype != LexemType::String{
+                                return Err(Diagnostic::at(&name, "test expects name in quotes".to_string()));
+                            }
+
+                            open_test = Some((args[0].clone(), Vec::new(), scope.scopes.clone()));
+                        }
+
+                        "endtest" => {
+                            return Err(Diagnostic::at(&name, "endtest without matching test".to_string()));
+                        }
+
+                        "endscope" | "endp" => {
+                            scope.leave(&name)?;
+                        }
 
 
-                    match name.value.to_lowercase().as_str() {
                         "org" => {
                             if args.len() != 1{
-                                println!("{}:{}:{} you need to provide addr", name.filename, name.row, name.col);
-                                std::process::exit(1);
+                                return Err(Diagnostic::at(&name, "you need to provide addr".to_string()));
                             }
 
                             let arg = args[0].clone();
                             if !matches!(arg.ttype, LexemType::Number { .. }){
-                                println!("{}:{}:{} Expected number got {}", arg.filename, arg.row, arg.col, arg.ttype);
-                                std::process::exit(1);
+                                return Err(Diagnostic::at(&arg, format!("Expected number got {}", arg.ttype)));
                             }
 
-                            origin = get_value_from_number_token(&arg);
+                            origin = get_value_from_number_token(&arg)?;
+                            if origin >= MEMORY_WORDS{
+                                return Err(Diagnostic::at(&arg, format!("org {:#x} is outside of memory", origin)));
+                            }
                             self.cursor = 0;
+                            unreachable = false;
                         }
 
                         "dw" => {
@@ -469,15 +1066,19 @@ impl Parser{
                             for arg in args.iter(){
                                 match arg.ttype{
                                     LexemType::Ident => to_add += 1,
-                                    LexemType::String => to_add += arg.value.len()*2,
-                                    LexemType::Number { .. } => to_add += 1,
+                                    // every character is a word of its own, like codegen writes it
+                                    LexemType::String => to_add += arg.value.chars().count(),
+                                    LexemType::Number { .. } | LexemType::Closure { .. } => to_add += 1,
                                     _ => {
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
+                                        return Err(Diagnostic::at(arg, format!("Unexpected token {}", arg.ttype)));
                                     }
                                 }
                             }
     
-                            cleaned_tokens.push(Token::Instruction { name, args });
+                            cleaned_tokens.push(Token::Instruction { name, args: fix_sub_label(&scope, args)? });
+                            token_scopes.push(scope.scopes.clone());
+                            token_origins.push(i);
+                            token_addresses.push(origin+self.cursor);
                             self.cursor += to_add;
                         }
 
@@ -488,15 +1089,18 @@ impl Parser{
                             for arg in args.iter(){
                                 match arg.ttype{
                                     LexemType::Ident => to_add += 2,
-                                    LexemType::String => to_add += arg.value.len()*4,
-                                    LexemType::Number { .. } => to_add += 2,
+                                    LexemType::String => to_add += arg.value.chars().count()*2,
+                                    LexemType::Number { .. } | LexemType::Closure { .. } => to_add += 2,
                                     _ => {
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
+                                        return Err(Diagnostic::at(arg, format!("Unexpected token {}", arg.ttype)));
                                     }
                                 }
                             }
     
-                            cleaned_tokens.push(Token::Instruction { name, args });
+                            cleaned_tokens.push(Token::Instruction { name, args: fix_sub_label(&scope, args)? });
+                            token_scopes.push(scope.scopes.clone());
+                            token_origins.push(i);
+                            token_addresses.push(origin+self.cursor);
                             self.cursor += to_add;
                         }
 
@@ -507,52 +1111,159 @@ impl Parser{
                             for arg in args.iter(){
                                 match arg.ttype{
                                     LexemType::Ident => to_add += 4,
-                                    LexemType::String => to_add += arg.value.len()*8,
-                                    LexemType::Number { .. } => to_add += 4,
+                                    LexemType::String => to_add += arg.value.chars().count()*4,
+                                    LexemType::Number { .. } | LexemType::Closure { .. } => to_add += 4,
                                     _ => {
-                                        println!("{}:{}:{} Unexpected token {}", arg.filename, arg.row, arg.col, arg.ttype);
+                                        return Err(Diagnostic::at(arg, format!("Unexpected token {}", arg.ttype)));
                                     }
                                 }
                             }
     
-                            cleaned_tokens.push(Token::Instruction { name, args });
+                            cleaned_tokens.push(Token::Instruction { name, args: fix_sub_label(&scope, args)? });
+                            token_scopes.push(scope.scopes.clone());
+                            token_origins.push(i);
+                            token_addresses.push(origin+self.cursor);
                             self.cursor += to_add;
                         }
 
                         _ => {
                             // cleaned_tokens.push(Token::Instruction { name, args });
 
-                            cleaned_tokens.push( Token::Instruction{ name, args: fix_sub_label(&last_label, args)});
+                            if unreachable{
+                                diagnostics.push(Diagnostic::warning("unreachable-code", &name, format!("{} can never be reached, it follows hlt or ret without a label", name.value)));
+                                unreachable = false;
+                            }
 
-                            self.cursor += 1;
+                            match name.value.to_lowercase().as_str(){
+                                "hlt" | "ret" => unreachable = true,
+                                _ => {}
+                            }
+
+                            cleaned_tokens.push( Token::Instruction{ name, args: fix_sub_label(&scope, args)?});
+                            token_scopes.push(scope.scopes.clone());
+                            token_origins.push(i);
+                            token_addresses.push(origin+self.cursor);
+
+                            if !self.peephole.is_removed(i){
+                                self.cursor += self.constant_loads.size(i);
+                            }
                         }
 
                     }
                 }
                 Token::Label { name } => {
 
-                    match labels.get(&name.value) {
-                        Some(_) => {
-                            println!("{}:{}:{} Label already defined {}", name.filename, name.row, name.col, name.value);
-                            std::process::exit(1)
-                        }
-                        None => {
+                    if open_test.is_some(){
+                        return Err(Diagnostic::at(name, format!("Labels aren't allowed inside test, got {}", name.value)));
+                    }
 
-                            if name.value.starts_with("."){
-                                labels.insert(last_label.clone()+name.value.as_str(), origin+self.cursor);
-                            }else{
-                                last_label = name.value.clone();
-                                labels.insert(last_label.clone(), origin+self.cursor);
-                            }
+                    if structs.is_open(){
+                        return Err(Diagnostic::at(name, format!("Expected field or endstruct got label {}", name.value)));
+                    }
 
-                        }
+                    unreachable = false;
+
+                    if is_local_label(name){
+                        let count = local_counts.entry(name.value.clone()).or_insert(0);
+                        labels.insert(local_label_name(&name.value, *count), origin+self.cursor);
+                        local_definitions.push((local_label_name(&name.value, *count), name.clone()));
+                        code_labels.push((name.value.clone(), origin+self.cursor));
+                        *count += 1;
+                        continue;
                     }
 
+                    let path = scope.define(name)?;
+                    code_labels.push((path.join("."), origin+self.cursor));
+                    insert_label(&mut labels, &mut definitions, name, path, scope.scopes.len(), origin+self.cursor)?;
+
+                }
+            }
+        }
+
+        structs.check_closed()?;
+
+        if let Some((name, _, _)) = open_test{
+            return Err(Diagnostic::at(&name, "test without matching endtest".to_string()));
+        }
+
+        if let Some((opener, end, _)) = scope.frames.last(){
+            return Err(Diagnostic::at(opener, format!("{} without matching {}", opener.value, end)));
+        }
+
+        for (arg, name, index) in forward_references{
+            if *local_counts.get(&name).unwrap_or(&0) <= index{
+                return Err(Diagnostic::at(&arg, format!("No local label {} defined after {}", name, arg.value)));
+            }
+        }
+
+        // shadowing: a scoped label hiding the same name of an outer scope,
+        // or a sub-label reusing the name of one of its parents
+        for (name, path, scope_depth) in definitions.iter(){
+            let relative = path[*scope_depth..].join(".");
+            let mut shadowed_names: Vec<String> = Vec::new();
+
+            for i in 0..*scope_depth{
+                let mut shadowed = path[..i].to_vec();
+                shadowed.push(relative.clone());
+                shadowed_names.push(shadowed.join("."));
+            }
+
+            for i in (*scope_depth).max(1)..path.len()-1{
+                let mut shadowed = path[..i].to_vec();
+                shadowed.push(path.last().unwrap().clone());
+                shadowed_names.push(shadowed.join("."));
+            }
+
+            for shadowed in shadowed_names{
+                if labels.contains_key(&shadowed){
+                    diagnostics.push(Diagnostic::warning("shadowed-label", name, format!("label {} shadows {}", path.join("."), shadowed)));
                 }
             }
         }
 
-        (cleaned_tokens, labels)
+        for (token, scopes) in cleaned_tokens.iter_mut().zip(token_scopes.iter()){
+            if scopes.is_empty(){
+                continue;
+            }
+            if let Token::Instruction { args, .. } = token{
+                *args = fix_scoped_label(scopes, &labels, args.clone());
+            }
+        }
+
+        self.tests = tests.into_iter().map(|(name, statements, scopes)| {
+            let statements = statements.into_iter().map(|token| match token{
+                Token::Instruction { name, args } => Token::Instruction { name, args: fix_scoped_label(&scopes, &labels, args) },
+                label => label
+            }).collect();
+            (name, statements)
+        }).collect();
+
+        self.label_definitions = definitions.into_iter().map(|(name, path, _)| (path.join("."), name)).collect();
+        self.token_origins = token_origins;
+        self.token_addresses = token_addresses;
+        self.code_labels = code_labels;
+        self.entry_points = entry_points;
+        self.local_definitions = local_definitions;
+        self.proc_uses = proc_uses;
+        self.diagnostics.append(&mut diagnostics);
+
+        Ok((cleaned_tokens, labels))
+    }
+
+    // addresses of labels some instruction or data refers to
+    pub fn referenced_addresses(self: &Self) -> HashSet<usize>{
+        self.code_labels.iter().filter(|(name, _)| self.referenced_labels.contains(name)).map(|(_, address)| *address).collect()
+    }
+
+    // full name and where it is defined, local labels included
+    pub fn label_definitions(self: &Self) -> Vec<(String, Lexem)>{
+        let mut definitions = self.label_definitions.clone();
+        definitions.extend_from_slice(&self.local_definitions);
+        definitions
+    }
+
+    pub fn origins(self: &Self) -> &[usize]{
+        &self.token_origins
     }
 
     fn fix_args(self: &mut Self, labels: &HashMap<String, usize>, args: &mut Vec<Lexem>) -> Vec<Lexem>{
@@ -581,6 +1292,8 @@ impl Parser{
                 LexemType::Ident =>{
                     match labels.get(&arg.value){
                         Some(x) => {
+                            self.referenced_labels.insert(arg.value.clone());
+                            self.label_references.push((arg.value.clone(), arg.clone()));
                             new_args.push(Lexem::new(format!("{}",x),LexemType::Number { radix: 10 },arg.row,arg.col, arg.filename.clone()));
                         },
                         None => {
@@ -600,73 +1313,150 @@ impl Parser{
         new_args
     }
 
-    fn calculate_labels(self: &mut Self){
-        let (mut cleaned_tokens, labels) = self.discover_labels();
+    fn expand_constant_loads(self: &mut Self) -> Result<(), Diagnostic>{
+        let (tokens, origins) = self.constant_loads.expand(&self.tokens, &self.token_origins)?;
+
+        self.tokens = tokens;
+        self.token_origins = origins;
+
+        Ok(())
+    }
+
+    fn apply_peephole(self: &mut Self){
+        let (tokens, origins, mut notes) = self.peephole.apply(&self.tokens, &self.token_origins);
+
+        self.tokens = tokens;
+        self.token_origins = origins;
+        self.notes.append(&mut notes);
+    }
+
+    fn calculate_labels(self: &mut Self) -> Result<(), Diagnostic>{
+        let diagnostics_len = self.diagnostics.len();
+
+        // constant loads are sized by label addresses and peephole removals move labels,
+        // lay out again until no constant load grows and nothing else gets removed
+        let (mut cleaned_tokens, labels) = loop{
+            self.diagnostics.truncate(diagnostics_len);
+
+            let (cleaned_tokens, labels) = self.discover_labels()?;
+
+            let mut resolved = cleaned_tokens.clone();
+            for token in resolved.iter_mut(){
+                if let Token::Instruction { args, .. } = token{
+                    *args = self.fix_args(&labels, args);
+                }
+            }
+
+            if self.constant_loads.grow(&resolved, &self.token_origins, &self.tokens)?{
+                continue;
+            }
+
+            if !self.peephole.update(&resolved, &self.token_origins, &self.token_addresses, &self.tokens, &self.constant_loads){
+                break (cleaned_tokens, labels);
+            }
+        };
+
+        self.referenced_labels.clear();
+        self.label_references.clear();
+
+        self.entry_labels.clear();
+        for point in self.entry_points.iter(){
+            match labels.get(&point.value){
+                Some(address) => {
+                    self.referenced_labels.insert(point.value.clone());
+                    self.label_references.push((point.value.clone(), point.clone()));
+                    self.entry_labels.push((point.value.clone(), *address));
+                }
+                None => {
+                    return Err(Diagnostic::at(point, format!("Use of undeclared label {}", point.value)));
+                }
+            }
+        }
 
         for arg in cleaned_tokens.iter_mut(){
             match arg{
                 Token::Instruction { name: _, args } =>{
                     *args = self.fix_args(&labels, args);
                 }
-                Token::Label { .. } => {
-                    println!("Internal error labels shouldve been removed in this stage");
-                    std::process::exit(1);
+                Token::Label { name } => {
+                    return Err(Diagnostic::at(name, "Internal error labels shouldve been removed in this stage".to_string()));
                 }
             }
         }
 
+        let mut tests = std::mem::take(&mut self.tests);
+        for (_, statements) in tests.iter_mut(){
+            for token in statements.iter_mut(){
+                if let Token::Instruction { args, .. } = token{
+                    *args = self.fix_args(&labels, args);
+                }
+            }
+        }
+        self.tests = tests;
+
+        // export/entry labels count as referenced, labels at the reset address are where execution starts
+        for (label, name) in self.label_definitions.iter(){
+            if !self.referenced_labels.contains(label) && labels.get(label) != Some(&0){
+                self.diagnostics.push(Diagnostic::warning("unused-label", name, format!("label {} is never used", label)));
+            }
+        }
+
         self.tokens = cleaned_tokens;
+        self.labels = labels;
+
+        Ok(())
     }
 
     // fn colapse_closure(self: &mut Self, arg: Lexem){
 
     // }
 
-    fn colapse_closures(self: &mut Self){
+    fn colapse_closures(self: &mut Self) -> Result<(), Diagnostic>{
         
         let mut new_tokens = Vec::new();
         
         for token in self.tokens.iter(){
             match token{
                 Token::Instruction { name, args } => {
-
-                    let mut new_args: Vec<Lexem> = Vec::new();
-
-                    for arg in args{
-                        match arg.ttype.clone(){
-
-                            LexemType::Closure { args } => {
-                                new_args.push(eval_closure(arg.clone(), args))
-                            }
-
-                            _ => {
-                                new_args.push(arg.clone());
-                            }
-                        }
-                    }
-
-                    new_tokens.push(Token::Instruction { name: name.clone(), args: new_args })
-
+                    new_tokens.push(Token::Instruction { name: name.clone(), args: colapse_args(args)? })
                 }
                 Token::Label { name } => {
-                    println!("{}:{}:{} This shouldnt exist now", name.filename, name.row, name.col);
-                    std::process::exit(1);
+                    return Err(Diagnostic::at(name, "This shouldnt exist now".to_string()));
                 }
             }
         }
 
         self.tokens = new_tokens;
+
+        for (_, statements) in self.tests.iter_mut(){
+            for token in statements.iter_mut(){
+                if let Token::Instruction { args, .. } = token{
+                    *args = colapse_args(args)?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn parse<'a>(self: &mut Self, lexems: &Vec<Lexem>){
+    pub fn parse(self: &mut Self, lexems: &[Lexem]) -> Result<(), Diagnostic>{
         
-        self.first_stage_parse(lexems);
+        self.first_stage_parse(lexems)?;
+
+        self.expand_repetitions()?;
+
+        self.convert_pseudo_instructions()?;
+
+        self.resolve_aliases()?;
+
+        self.calculate_labels()?;
 
-        self.convert_pseudo_instructions();
+        self.colapse_closures()?;
 
-        self.calculate_labels();
+        self.expand_constant_loads()?;
 
-        self.colapse_closures();
+        self.apply_peephole();
 
+        Ok(())
     }
 }
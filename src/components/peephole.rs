@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+
+use phf::phf_map;
+
+use crate::{constant_value, token_text, Diagnostic, Lexem, ListingNote, Token};
+
+use super::constant_loads::{is_constant_load, is_entry, ConstantLoads, MachineState};
+
+// Peephole rules, -O enables all of them, -Oname a single one and -Ono-name turns one off
+// runs on the resolved instructions while laying out, so removing one moves the labels after it
+// an instruction a label points at is never removed, neither is li or mov r0, r0 (nop)
+//
+// Independent of -O, a lui loading the upper bits that are already loaded is dropped
+// (lui elision, on unless --no-lui-elide), what is loaded is forgotten at labels, calls and branches
+pub const PEEPHOLE_RULES: phf::Map<&'static str, &'static str> = phf_map!{
+    "self-move" => "`mov rX, rX` with the same register twice",
+    "push-pop" => "`psh rX` directly followed by `pop rX`, both are removed",
+    "repeated-lui" => "`lui` loading the upper bits that are already loaded",
+    "branch-next" => "`brc` without extra field jumping to the instruction right after it",
+};
+
+pub const LUI_ELISION: &str = "lui-elide";
+// routines --strip-dead found unreachable
+pub const DEAD_CODE: &str = "dead-code";
+
+fn mnemonic(token: &Token) -> String{
+    match token{
+        Token::Instruction { name, .. } => name.value.to_lowercase(),
+        Token::Label { .. } => String::new()
+    }
+}
+
+fn operands(token: &Token) -> Vec<String>{
+    match token{
+        Token::Instruction { args, .. } => args.iter().map(|arg| arg.value.to_lowercase()).collect(),
+        Token::Label { .. } => Vec::new()
+    }
+}
+
+fn immediate(token: &Token, index: usize) -> Option<usize>{
+    match token{
+        Token::Instruction { args, .. } => args.get(index).and_then(constant_value),
+        Token::Label { .. } => None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Peephole{
+    pub enabled: HashSet<&'static str>,
+    pub elide_lui: bool,
+    // origin of removed token -> rule that removed it
+    removed: HashMap<usize, &'static str>,
+    // removals a later layout made wrong, never tried again so the layout settles
+    pinned: HashSet<usize>
+}
+
+impl Peephole{
+    pub fn new() -> Peephole{
+        Peephole{
+            enabled: HashSet::new(),
+            elide_lui: true,
+            removed: HashMap::new(),
+            pinned: HashSet::new()
+        }
+    }
+
+    // returns false for flags that aren't -O
+    pub fn parse_flag(self: &mut Self, flag: &str) -> Result<bool, Diagnostic>{
+        let name = match flag.strip_prefix("-O"){
+            Some(a) => a,
+            None => return Ok(false)
+        };
+
+        if name.is_empty(){
+            self.enabled.extend(PEEPHOLE_RULES.keys());
+            return Ok(true);
+        }
+
+        let (name, enable) = match name.strip_prefix("no-"){
+            Some(a) => (a, false),
+            None => (name, true)
+        };
+
+        let rule = match PEEPHOLE_RULES.get_key(name){
+            Some(a) => *a,
+            None => {
+                let mut names: Vec<&str> = PEEPHOLE_RULES.keys().copied().collect();
+                names.sort();
+                return Err(Diagnostic::new(format!("Unknown optimization {}, expected one of {}", name, names.join(", "))));
+            }
+        };
+
+        if enable{
+            self.enabled.insert(rule);
+        }else{
+            self.enabled.remove(rule);
+        }
+
+        Ok(true)
+    }
+
+    pub fn strip(self: &mut Self, origins: &[usize]){
+        for origin in origins{
+            self.removed.insert(*origin, DEAD_CODE);
+        }
+    }
+
+    pub fn is_removed(self: &Self, origin: usize) -> bool{
+        self.removed.contains_key(&origin)
+    }
+
+    // enabled rule for what single_rule matched
+    fn active_rule(self: &Self, rule: &'static str) -> Option<&'static str>{
+        if self.enabled.contains(rule){
+            return Some(rule);
+        }
+
+        if rule == "repeated-lui" && self.elide_lui{
+            return Some(LUI_ELISION);
+        }
+
+        None
+    }
+
+    // rule removing a single token, state is what is known right before it
+    fn single_rule(token: &Token, state: &MachineState, next_address: Option<usize>) -> Option<&'static str>{
+        let operands = operands(token);
+
+        match mnemonic(token).as_str(){
+            "mov" if operands.len() == 2 && operands[0] == operands[1] && operands[0] != "r0" => Some("self-move"),
+            "lui" if operands.len() == 1 && state.latch.is_some() && immediate(token, 0) == state.latch => Some("repeated-lui"),
+            "brc" if operands.len() == 2 => {
+                let target = (state.latch? << 6) | immediate(token, 1)?;
+                if next_address? == target { Some("branch-next") } else { None }
+            }
+            _ => None
+        }
+    }
+
+    fn step(state: &mut MachineState, token: &Token, origin: usize, constant_loads: &ConstantLoads){
+        if !is_constant_load(token){
+            state.step(token);
+            return;
+        }
+
+        let rd = operands(token).first().cloned().unwrap_or_default();
+
+        match constant_loads.form(origin){
+            Some((form, value)) => state.load(&rd, *value, form),
+            None => {
+                state.latch = None;
+                state.registers.remove(&rd);
+            }
+        }
+    }
+
+    // walks resolved tokens, drops removals the current layout made wrong and adds new ones
+    // returns true when anything changed and labels have to be laid out again
+    pub fn update(self: &mut Self, tokens: &[Token], origins: &[usize], addresses: &[usize], source: &[Token], constant_loads: &ConstantLoads) -> bool{
+        if self.enabled.is_empty() && !self.elide_lui{
+            return false;
+        }
+
+        // address of the next token still emitted
+        let mut next_addresses: Vec<Option<usize>> = vec![None; tokens.len()];
+        for i in (0..tokens.len().saturating_sub(1)).rev(){
+            next_addresses[i] = if self.is_removed(origins[i+1]) { next_addresses[i+1] } else { Some(addresses[i+1]) };
+        }
+
+        let mut state = MachineState::new();
+        let mut changed = false;
+        let mut from = 0;
+        // last emitted token and whether a label points at it
+        let mut previous: Option<(usize, bool)> = None;
+
+        for (i, token) in tokens.iter().enumerate(){
+            let origin = origins[i];
+
+            let labelled = source[from..origin].iter().any(is_entry);
+            if labelled{
+                state.invalidate();
+            }
+            from = origin + 1;
+
+            if let Some(rule) = self.removed.get(&origin).copied(){
+                // pairs and self moves don't depend on the layout
+                let valid = match rule{
+                    "repeated-lui" | "branch-next" => Self::single_rule(token, &state, next_addresses[i]) == Some(rule),
+                    LUI_ELISION => Self::single_rule(token, &state, next_addresses[i]) == Some("repeated-lui"),
+                    _ => true
+                };
+
+                if valid{
+                    continue;
+                }
+
+                self.removed.remove(&origin);
+                self.pinned.insert(origin);
+                changed = true;
+            }else if !labelled && !self.pinned.contains(&origin) && !is_constant_load(token){
+                if let Some(rule) = Self::single_rule(token, &state, next_addresses[i]).and_then(|rule| self.active_rule(rule)){
+                    self.removed.insert(origin, rule);
+                    changed = true;
+                    continue;
+                }
+
+                if let Some((j, false)) = previous{
+                    let pair = mnemonic(&tokens[j]) == "psh" && mnemonic(token) == "pop" && operands(&tokens[j]).len() == 1 && operands(&tokens[j]) == operands(token);
+
+                    if pair && self.enabled.contains("push-pop") && !self.pinned.contains(&origins[j]){
+                        self.removed.insert(origins[j], "push-pop");
+                        self.removed.insert(origin, "push-pop");
+                        previous = None;
+                        changed = true;
+                        continue;
+                    }
+                }
+            }
+
+            Self::step(&mut state, token, origin, constant_loads);
+
+            // the fall through keeps the latch, but it is only trusted in straight-line code
+            if matches!(mnemonic(token).split('.').next(), Some("brc" | "bri")){
+                state.invalidate();
+            }
+
+            previous = Some((i, labelled));
+        }
+
+        changed
+    }
+
+    // removes what update decided on, leaving a listing note for every removed instruction
+    pub fn apply(self: &Self, tokens: &[Token], origins: &[usize]) -> (Vec<Token>, Vec<usize>, Vec<ListingNote>){
+        let mut kept: Vec<Token> = Vec::new();
+        let mut kept_origins: Vec<usize> = Vec::new();
+        let mut notes: Vec<ListingNote> = Vec::new();
+
+        for (token, origin) in tokens.iter().zip(origins.iter()){
+            match (self.removed.get(origin), token){
+                (Some(rule), Token::Instruction { name, .. }) => {
+                    let location: Lexem = name.clone();
+                    let text = match *rule{
+                        LUI_ELISION => format!("{} elided, upper bits are already loaded", token_text(token)),
+                        DEAD_CODE => format!("{} removed, never reached", token_text(token)),
+                        _ => format!("{} removed by -O{}", token_text(token), rule)
+                    };
+                    notes.push(ListingNote { before: kept.len(), location, text });
+                }
+                _ => {
+                    kept.push(token.clone());
+                    kept_origins.push(*origin);
+                }
+            }
+        }
+
+        (kept, kept_origins, notes)
+    }
+}
@@ -1,16 +1,28 @@
 use std::collections::HashMap;
 
-use crate::{Lexer, Parser, Token, PSEUDO_INSTRUCTIONS};
+use crate::{eval_closure, get_value_from_number_token, Diagnostic, Lexem, LexemType, Lexer, Parser, Token, PSEUDO_INSTRUCTIONS, PSEUDO_INSTRUCTION_DOCS, VARIADIC_PSEUDO_INSTRUCTIONS};
+
+// name -> (argument names, body)
+pub type PseudoInstructionMap = HashMap<String, (Vec<String>,Vec<Token>)>;
+
+// value of a number or a closure made only of numbers
+pub fn constant_value(arg: &Lexem) -> Option<usize>{
+    match arg.ttype.clone(){
+        LexemType::Number { .. } => get_value_from_number_token(arg).ok(),
+        LexemType::Closure { args } => get_value_from_number_token(&eval_closure(arg.clone(), args).ok()?).ok(),
+        _ => None
+    }
+}
 
 #[derive(Debug)]
 pub struct PseudoInstructions{}
 
 impl PseudoInstructions{
-    pub fn initialize() -> HashMap<String, (Vec<String>,Vec<Token>)>{
+    pub fn initialize() -> Result<PseudoInstructionMap, Diagnostic>{
         let mut pseudo_instructions_lexer: Lexer = Lexer::new();
         let mut pseudo_instructions_parser: Parser = Parser::new();
 
-        let mut pseudo_instructions: HashMap<String, (Vec<String>,Vec<Token>)> = HashMap::new();
+        let mut pseudo_instructions: PseudoInstructionMap = HashMap::new();
         
         for (name, code) in PSEUDO_INSTRUCTIONS.entries(){
             let mut p_args = Vec::new();
@@ -21,13 +33,12 @@ impl PseudoInstructions{
 
             let source_filename = "PSEUDO_INSTRUCTION_NAME_".to_string() + pure_name.to_uppercase().as_str();
 
-            pseudo_instructions_lexer.lex(&source_filename, name);
+            pseudo_instructions_lexer.lex(&source_filename, name)?;
 
-            pseudo_instructions_parser.first_stage_parse(&pseudo_instructions_lexer.lexems);
+            pseudo_instructions_parser.first_stage_parse(&pseudo_instructions_lexer.lexems)?;
 
             if pseudo_instructions_parser.tokens.len() > 1{
-                println!("PSEUDO_INSTRUCTIONS: You can only have one name per pseudoinstruction");
-                std::process::exit(1);
+                return Err(Diagnostic::new("PSEUDO_INSTRUCTIONS: You can only have one name per pseudoinstruction".to_string()));
             }
 
             match pseudo_instructions_parser.tokens[0].clone(){
@@ -37,17 +48,16 @@ impl PseudoInstructions{
                     }
                 }
                 Token::Label { .. } => {
-                    println!("PSEUDO_INSTRUCTIONS: You can only define instruction");
-                    std::process::exit(1);
+                    return Err(Diagnostic::new("PSEUDO_INSTRUCTIONS: You can only define instruction".to_string()));
                 }
             }
 
 
             let source_filename = "PSEUDO_INSTRUCTION_CODE_".to_string() + pure_name.to_uppercase().as_str();
 
-            pseudo_instructions_lexer.lex(source_filename.as_str(), code);
+            pseudo_instructions_lexer.lex(source_filename.as_str(), code)?;
 
-            pseudo_instructions_parser.first_stage_parse(&pseudo_instructions_lexer.lexems);
+            pseudo_instructions_parser.first_stage_parse(&pseudo_instructions_lexer.lexems)?;
 
             pseudo_instructions.insert(pure_name.to_string(), (p_args.clone(),pseudo_instructions_parser.tokens.clone()));
 
@@ -56,7 +66,51 @@ impl PseudoInstructions{
         }
 
         
-        pseudo_instructions
+        Ok(pseudo_instructions)
+
+    }
+
+    // Markdown reference of every pseudo instruction and what it expands to
+    pub fn reference() -> String{
+        let mut reference = String::from("# Pseudo instructions\n\nGenerated by `zasm --pseudo-reference`, expansions are shown in listings (`--listing`).\n");
+
+        let mut names: Vec<(&str, &str)> = PSEUDO_INSTRUCTIONS.entries().map(|(name, code)| (*name, *code)).collect();
+        names.sort();
+
+        for (name, code) in names{
+            let pure_name = name.split(" ").collect::<Vec<&str>>()[0];
+
+            reference += format!("\n## `{}`\n\n", name).as_str();
+
+            if let Some(doc) = PSEUDO_INSTRUCTION_DOCS.get(pure_name){
+                reference += format!("{}\n\n", doc).as_str();
+            }
+
+            reference += "```\n";
+            for line in code.lines().map(|line| line.trim()).filter(|line| !line.is_empty()){
+                reference += format!("{}\n", line).as_str();
+            }
+            reference += "```\n";
+        }
+
+        let mut names: Vec<(&str, &(&str, bool))> = VARIADIC_PSEUDO_INSTRUCTIONS.entries().map(|(name, instruction)| (*name, instruction)).collect();
+        names.sort();
+
+        for (name, (instruction, reversed)) in names{
+            reference += format!("\n## `{} r1, r2, r3`\n\n", name).as_str();
+
+            if let Some(doc) = PSEUDO_INSTRUCTION_DOCS.get(name){
+                reference += format!("{}\n\n", doc).as_str();
+            }
+
+            reference += "```\n";
+            match reversed{
+                true => reference += format!("{} r3\n{} r2\n{} r1\n", instruction, instruction, instruction).as_str(),
+                false => reference += format!("{} r1\n{} r2\n{} r3\n", instruction, instruction, instruction).as_str()
+            }
+            reference += "```\n";
+        }
 
+        reference
     }
 }
\ No newline at end of file
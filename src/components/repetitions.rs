@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use crate::{eval_closure, get_value_from_number_token, Diagnostic, Lexem, LexemType, Token};
+
+// Assembly time loops
+// rept count[, counter] ... endr
+// irp name, a, b, c ... endr
+// while (expr)[, counter] ... endw
+//
+// `iter` always holds the counter of the innermost loop,
+// sub-labels defined inside a loop body get the iteration number appended (.loop -> .loop.3)
+
+pub const ITERATION_COUNTER: &str = "iter";
+
+// bounds a single loop and all loops of a file together, nested loops multiply
+pub const MAX_ITERATIONS: usize = 65536;
+
+fn block_end(name: &str) -> Option<&'static str>{
+    match name{
+        "rept" | "irp" => Some("endr"),
+        "while" => Some("endw"),
+        _ => None
+    }
+}
+
+fn token_name(token: &Token) -> String{
+    match token{
+        Token::Instruction { name, .. } => name.value.to_lowercase(),
+        Token::Label { .. } => String::new()
+    }
+}
+
+fn substitute_arg(arg: &Lexem, env: &HashMap<String, Lexem>, renames: &HashMap<String, String>) -> Lexem{
+    match arg.ttype.clone(){
+        LexemType::Closure { args } => {
+            let mut new_args: Vec<Box<Lexem>> = Vec::new();
+            for arg in args.iter(){
+                new_args.push(Box::new(substitute_arg(arg, env, renames)));
+            }
+
+            Lexem::new(arg.value.clone(), LexemType::Closure { args: [new_args[0].clone(), new_args[1].clone(), new_args[2].clone()] }, arg.row, arg.col, arg.filename.clone())
+        }
+        LexemType::Ident => {
+            if let Some(value) = env.get(&arg.value){
+                return Lexem::new(value.value.clone(), value.ttype.clone(), arg.row, arg.col, arg.filename.clone());
+            }
+            if let Some(renamed) = renames.get(&arg.value){
+                return Lexem::new(renamed.clone(), LexemType::Ident, arg.row, arg.col, arg.filename.clone());
+            }
+            arg.clone()
+        }
+        _ => arg.clone()
+    }
+}
+
+fn substitute_token(token: &Token, env: &HashMap<String, Lexem>, renames: &HashMap<String, String>) -> Token{
+    match token{
+        Token::Instruction { name, args } => {
+            let name = match env.get(&name.value){
+                Some(value) => Lexem::new(value.value.clone(), LexemType::Ident, name.row, name.col, name.filename.clone()),
+                None => name.clone()
+            };
+
+            let args = args.iter().map(|arg| substitute_arg(arg, env, renames)).collect();
+
+            Token::Instruction { name, args }
+        }
+        Token::Label { name } => {
+            match renames.get(&name.value){
+                Some(renamed) => Token::Label { name: Lexem::new(renamed.clone(), name.ttype.clone(), name.row, name.col, name.filename.clone()) },
+                None => token.clone()
+            }
+        }
+    }
+}
+
+fn eval_constant(arg: &Lexem, env: &HashMap<String, Lexem>) -> Result<usize, Diagnostic>{
+    let arg = substitute_arg(arg, env, &HashMap::new());
+
+    let arg = match arg.ttype.clone(){
+        LexemType::Closure { args } => eval_closure(arg, args)?,
+        _ => arg
+    };
+
+    match arg.ttype{
+        LexemType::Number { .. } => get_value_from_number_token(&arg),
+        _ => {
+            return Err(Diagnostic::at(&arg, format!("Expected constant expression got {}", arg.value)));
+        }
+    }
+}
+
+fn counter_lexem(value: usize, at: &Lexem) -> Lexem{
+    Lexem::new(format!("{}", value), LexemType::Number { radix: 10 }, at.row, at.col, at.filename.clone())
+}
+
+pub struct Repetitions{
+    cursor: usize,
+    tokens: Vec<Token>,
+    iterations: usize,
+}
+
+impl Repetitions{
+    pub fn new() -> Repetitions{
+        Repetitions{
+            cursor: 0,
+            tokens: Vec::new(),
+            iterations: 0
+        }
+    }
+
+    fn chop_body(self: &mut Self, header: &Lexem, end: &str) -> Result<Vec<Token>, Diagnostic>{
+        let mut body: Vec<Token> = Vec::new();
+        let mut ends: Vec<&'static str> = Vec::new();
+
+        while self.cursor < self.tokens.len(){
+            let token = self.tokens[self.cursor].clone();
+            self.cursor += 1;
+
+            let name = token_name(&token);
+
+            if let Some(nested_end) = block_end(&name){
+                ends.push(nested_end);
+            }else if name == "endr" || name == "endw"{
+                match ends.pop(){
+                    Some(expected) => {
+                        if expected != name{
+                            if let Token::Instruction { name: lexem, .. } = &token{
+                                return Err(Diagnostic::at(lexem, format!("Expected {} got {}", expected, lexem.value)));
+                            }
+                        }
+                    }
+                    None => {
+                        if name != end{
+                            if let Token::Instruction { name: lexem, .. } = &token{
+                                return Err(Diagnostic::at(lexem, format!("Expected {} got {}", end, lexem.value)));
+                            }
+                        }
+                        return Ok(body);
+                    }
+                }
+            }
+
+            body.push(token);
+        }
+
+        return Err(Diagnostic::at(header, format!("{} without matching {}", header.value, end)));
+    }
+
+    fn iteration(self: &mut Self, header: &Lexem, body: &[Token], env: &HashMap<String, Lexem>, iteration: usize) -> Result<Vec<Token>, Diagnostic>{
+        self.iterations += 1;
+        if self.iterations > MAX_ITERATIONS{
+            return Err(Diagnostic::at(header, format!("Loops exceeded {} iterations in total", MAX_ITERATIONS)));
+        }
+
+        let mut renames: HashMap<String, String> = HashMap::new();
+
+        for token in body{
+            if let Token::Label { name } = token{
+                if name.value.starts_with("."){
+                    renames.insert(name.value.clone(), format!("{}.{}", name.value, iteration));
+                }
+            }
+        }
+
+        let body: Vec<Token> = body.iter().map(|token| substitute_token(token, &HashMap::new(), &renames)).collect();
+
+        let mut nested = Repetitions::new();
+        nested.tokens = body;
+        nested.iterations = self.iterations;
+        let expanded = nested.expand_tokens(env);
+        self.iterations = nested.iterations;
+        expanded
+    }
+
+    fn expand_block(self: &mut Self, name: &Lexem, args: &[Lexem], env: &HashMap<String, Lexem>) -> Result<Vec<Token>, Diagnostic>{
+        let directive = name.value.to_lowercase();
+        let end = block_end(&directive).unwrap();
+        let body = self.chop_body(name, end)?;
+
+        let mut expanded: Vec<Token> = Vec::new();
+
+        match directive.as_str(){
+            "rept" => {
+                if args.is_empty() || args.len() > 2{
+                    return Err(Diagnostic::at(name, "rept expects count and optional counter name".to_string()));
+                }
+
+                let count = eval_constant(&args[0], env)?;
+                if count > MAX_ITERATIONS{
+                    return Err(Diagnostic::at(name, format!("rept count {} exceeds {}", count, MAX_ITERATIONS)));
+                }
+
+                for i in 0..count{
+                    let mut env = env.clone();
+                    env.insert(ITERATION_COUNTER.to_string(), counter_lexem(i, name));
+                    if args.len() == 2{
+                        env.insert(args[1].value.clone(), counter_lexem(i, name));
+                    }
+                    expanded.append(&mut self.iteration(name, &body, &env, i)?);
+                }
+            }
+
+            "irp" => {
+                if args.is_empty() || args[0].ttype != LexemType::Ident{
+                    return Err(Diagnostic::at(name, "irp expects name followed by values".to_string()));
+                }
+
+                for (i, value) in args.iter().skip(1).enumerate(){
+                    let value = substitute_arg(value, env, &HashMap::new());
+                    let mut env = env.clone();
+                    env.insert(ITERATION_COUNTER.to_string(), counter_lexem(i, name));
+                    env.insert(args[0].value.clone(), value);
+                    expanded.append(&mut self.iteration(name, &body, &env, i)?);
+                }
+            }
+
+            "while" => {
+                if args.is_empty() || args.len() > 2{
+                    return Err(Diagnostic::at(name, "while expects condition and optional counter name".to_string()));
+                }
+
+                let mut i = 0;
+                loop{
+                    let mut env = env.clone();
+                    env.insert(ITERATION_COUNTER.to_string(), counter_lexem(i, name));
+                    if args.len() == 2{
+                        env.insert(args[1].value.clone(), counter_lexem(i, name));
+                    }
+
+                    if eval_constant(&args[0], &env)? == 0{
+                        break;
+                    }
+
+                    if i >= MAX_ITERATIONS{
+                        return Err(Diagnostic::at(name, format!("while loop exceeded {} iterations", MAX_ITERATIONS)));
+                    }
+
+                    expanded.append(&mut self.iteration(name, &body, &env, i)?);
+                    i += 1;
+                }
+            }
+
+            _ => unreachable!()
+        }
+
+        Ok(expanded)
+    }
+
+    fn expand_tokens(self: &mut Self, env: &HashMap<String, Lexem>) -> Result<Vec<Token>, Diagnostic>{
+        let mut expanded: Vec<Token> = Vec::new();
+
+        self.cursor = 0;
+
+        while self.cursor < self.tokens.len(){
+            let token = self.tokens[self.cursor].clone();
+            self.cursor += 1;
+
+            if let Token::Instruction { name, args } = &token{
+                let directive = name.value.to_lowercase();
+
+                if block_end(&directive).is_some(){
+                    expanded.append(&mut self.expand_block(name, args, env)?);
+                    continue;
+                }
+
+                if directive == "endr" || directive == "endw"{
+                    return Err(Diagnostic::at(name, format!("{} without matching loop", name.value)));
+                }
+            }
+
+            expanded.push(substitute_token(&token, env, &HashMap::new()));
+        }
+
+        Ok(expanded)
+    }
+
+    pub fn expand(self: &mut Self, tokens: &[Token]) -> Result<Vec<Token>, Diagnostic>{
+        self.tokens = tokens.to_vec();
+        self.expand_tokens(&HashMap::new())
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{ControlFlowGraph, Diagnostic, EdgeKind, Lexem, Token};
+
+// Stack use in words along the call graph
+// psh adds a word, pop removes one, cal pushes the return address that ret pops
+// a function starts at the reset address or at something cal jumps to, its body is what is reachable
+// from there without following calls. Worst use of a function is the deepest it gets itself
+// or at any call, its own depth plus the return address plus the worst use of the callee
+
+#[derive(Debug, Clone)]
+pub struct FunctionUsage{
+    pub name: String,
+    // block index in the control flow graph
+    pub entry: usize,
+    // deepest the function gets without its calls
+    pub own: usize,
+    pub worst: usize,
+    pub calls: Vec<String>,
+    // recursion somewhere below, worst is only a lower bound
+    pub unbounded: bool,
+    // calls through a register that couldn't be followed
+    pub unknown_calls: bool
+}
+
+fn token_name(token: &Token) -> &Lexem{
+    match token{
+        Token::Instruction { name, .. } | Token::Label { name } => name
+    }
+}
+
+fn mnemonic(token: &Token) -> String{
+    token_name(token).value.to_lowercase().split('.').next().unwrap_or("").to_string()
+}
+
+pub struct StackUsage<'a>{
+    cfg: &'a ControlFlowGraph<'a>,
+    tokens: &'a [Token],
+    usages: HashMap<usize, FunctionUsage>,
+    // functions being walked, to find recursion
+    visiting: Vec<usize>,
+    pub functions: Vec<FunctionUsage>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl StackUsage<'_>{
+    pub fn new<'a>(cfg: &'a ControlFlowGraph<'a>, tokens: &'a [Token]) -> StackUsage<'a>{
+        StackUsage{
+            cfg,
+            tokens,
+            usages: HashMap::new(),
+            visiting: Vec::new(),
+            functions: Vec::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    fn block_name(self: &Self, block: usize) -> String{
+        match self.cfg.blocks[block].labels.first(){
+            Some(label) => label.clone(),
+            None => format!("{:04X}", self.cfg.blocks[block].start)
+        }
+    }
+
+    fn function(self: &mut Self, entry: usize) -> FunctionUsage{
+        if let Some(usage) = self.usages.get(&entry){
+            return usage.clone();
+        }
+
+        self.visiting.push(entry);
+
+        let mut usage = FunctionUsage {
+            name: self.block_name(entry),
+            entry,
+            own: 0,
+            worst: 0,
+            calls: Vec::new(),
+            unbounded: false,
+            unknown_calls: false
+        };
+
+        // depth when entering each block of the body
+        let mut depths: HashMap<usize, i64> = HashMap::new();
+        let mut reported: HashSet<usize> = HashSet::new();
+        let mut worklist: Vec<usize> = vec![entry];
+        depths.insert(entry, 0);
+
+        while let Some(block) = worklist.pop(){
+            let mut depth = depths[&block];
+            let (first, last) = (self.cfg.blocks[block].first, self.cfg.blocks[block].last);
+
+            for token in self.tokens[first..=last].iter(){
+                match mnemonic(token).as_str(){
+                    "psh" => depth += 1,
+                    "pop" => {
+                        depth -= 1;
+                        if depth < 0 && reported.insert(block){
+                            self.diagnostics.push(Diagnostic::warning("unbalanced-stack", token_name(token), format!("{} pops more than it pushed", usage.name)));
+                        }
+                    }
+                    "cal" => {
+                        let callee = self.cfg.edges.iter().find(|edge| edge.from == block && edge.kind == EdgeKind::Call).and_then(|edge| edge.to);
+
+                        let callee_worst = match callee{
+                            Some(callee) if self.visiting.contains(&callee) => {
+                                self.diagnostics.push(Diagnostic::warning("recursion", token_name(token), format!("{} calls {} recursively, its stack use has no bound", usage.name, self.block_name(callee))));
+                                usage.unbounded = true;
+                                0
+                            }
+                            Some(callee) => {
+                                let callee = self.function(callee);
+                                usage.unbounded |= callee.unbounded || callee.unknown_calls;
+                                if !usage.calls.contains(&callee.name){
+                                    usage.calls.push(callee.name.clone());
+                                }
+                                callee.worst
+                            }
+                            None => {
+                                usage.unknown_calls = true;
+                                0
+                            }
+                        };
+
+                        usage.worst = usage.worst.max((depth.max(0) as usize) + 1 + callee_worst);
+                    }
+                    "ret" if depth != 0 && reported.insert(block) => {
+                        self.diagnostics.push(Diagnostic::warning("unbalanced-stack", token_name(token), format!("{} returns with {} word(s) left on the stack", usage.name, depth)));
+                    }
+                    _ => {}
+                }
+
+                usage.own = usage.own.max(depth.max(0) as usize);
+            }
+
+            for edge in self.cfg.edges.iter().filter(|edge| edge.from == block && edge.kind != EdgeKind::Call){
+                let to = match edge.to{
+                    Some(a) => a,
+                    None => continue
+                };
+
+                match depths.get(&to){
+                    None => {
+                        depths.insert(to, depth);
+                        worklist.push(to);
+                    }
+                    Some(known) if *known != depth && reported.insert(to) => {
+                        let location = token_name(&self.tokens[self.cfg.blocks[to].first]).clone();
+                        self.diagnostics.push(Diagnostic::warning("unbalanced-stack", &location, format!("{} reaches this with {} and {} word(s) on the stack", usage.name, known, depth)));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        usage.worst = usage.worst.max(usage.own);
+
+        self.visiting.pop();
+        self.usages.insert(entry, usage.clone());
+
+        usage
+    }
+
+    pub fn analyze(self: &mut Self){
+        if self.cfg.blocks.is_empty(){
+            return;
+        }
+
+        let mut entries: Vec<usize> = vec![self.cfg.blocks.iter().position(|block| block.start == 0).unwrap_or(0)];
+
+        for edge in self.cfg.edges.iter().filter(|edge| edge.kind == EdgeKind::Call){
+            if let Some(to) = edge.to{
+                if !entries.contains(&to){
+                    entries.push(to);
+                }
+            }
+        }
+
+        for entry in entries{
+            let usage = self.function(entry);
+            self.functions.push(usage);
+        }
+    }
+
+    // worst case of the program, from the reset address
+    pub fn total(self: &Self) -> usize{
+        self.functions.first().map(|usage| usage.worst).unwrap_or(0)
+    }
+
+    // warns when the worst case doesn't fit
+    pub fn check(self: &mut Self, stack_size: usize){
+        let total = self.total();
+
+        if total <= stack_size{
+            return;
+        }
+
+        let entry = match self.functions.first(){
+            Some(a) => a,
+            None => return
+        };
+
+        let location = token_name(&self.tokens[self.cfg.blocks[entry.entry].first]).clone();
+        self.diagnostics.push(Diagnostic::warning("stack-overflow", &location, format!("worst case stack use is {} words, the stack has {}", total, stack_size)));
+    }
+
+    pub fn report(self: &Self, stack_size: Option<usize>) -> String{
+        let width = self.functions.iter().map(|usage| usage.name.len()).max().unwrap_or(0).max("function".len());
+
+        let mut text = format!("{:<width$}  {:>5}  {:>5}  calls\n", "function", "own", "worst", width = width);
+
+        for usage in self.functions.iter(){
+            let mut calls = usage.calls.clone();
+            if usage.unknown_calls{
+                calls.push("?".to_string());
+            }
+
+            let worst = if usage.unbounded { format!("{}+", usage.worst) } else { format!("{}", usage.worst) };
+
+            text += format!("{:<width$}  {:>5}  {:>5}  {}", usage.name, usage.own, worst, calls.join(", "), width = width).trim_end();
+            text += "\n";
+        }
+
+        let bounded = self.functions.first().is_none_or(|usage| !usage.unbounded);
+        let total = if bounded { format!("{}", self.total()) } else { format!("at least {}", self.total()) };
+
+        match stack_size{
+            Some(size) => text += format!("worst case {} of {} words\n", total, size).as_str(),
+            None => text += format!("worst case {} words\n", total).as_str()
+        }
+
+        text
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use crate::{eval_closure, get_value_from_number_token, Diagnostic, Lexem, LexemType, MEMORY_WORDS};
+
+// Typed layouts, sizes and offsets are in words
+// struct Uart
+//     field data: word
+//     field fifo: word * 4
+// endstruct
+// mmio Uart at 0x3F00          instance named after the struct
+// mmio uart1: Uart at 0x3F10
+//
+// a struct defines Uart.data, Uart.fifo (offsets) and sizeof.Uart,
+// an instance defines uart1 (base address) and uart1.data, uart1.fifo (addresses)
+
+pub const SIZEOF_PREFIX: &str = "sizeof";
+
+fn builtin_size(name: &str) -> Option<usize>{
+    match name{
+        "word" => Some(1),
+        "dword" => Some(2),
+        "qword" => Some(4),
+        _ => None
+    }
+}
+
+// identifiers are looked up in constants defined so far
+fn substitute_constants(arg: &Lexem, constants: &HashMap<String, usize>) -> Result<Lexem, Diagnostic>{
+    match arg.ttype.clone(){
+        LexemType::Closure { args } => {
+            let mut new_args: Vec<Box<Lexem>> = Vec::new();
+            for arg in args.iter(){
+                new_args.push(Box::new(substitute_constants(arg, constants)?));
+            }
+
+            Ok(Lexem::new(arg.value.clone(), LexemType::Closure { args: [new_args[0].clone(), new_args[1].clone(), new_args[2].clone()] }, arg.row, arg.col, arg.filename.clone()))
+        }
+        LexemType::Ident => match constants.get(&arg.value){
+            Some(value) => Ok(Lexem::new(format!("{}", value), LexemType::Number { radix: 10 }, arg.row, arg.col, arg.filename.clone())),
+            None => Err(Diagnostic::at(arg, format!("Expected constant got {}", arg.value)))
+        }
+        _ => Ok(arg.clone())
+    }
+}
+
+fn eval_constant(arg: &Lexem, constants: &HashMap<String, usize>) -> Result<usize, Diagnostic>{
+    let arg = substitute_constants(arg, constants)?;
+
+    match arg.ttype.clone(){
+        LexemType::Closure { args } => get_value_from_number_token(&eval_closure(arg, args)?),
+        _ => get_value_from_number_token(&arg)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StructLayout{
+    pub size: usize,
+    // field path relative to the struct, nested struct fields included
+    pub fields: Vec<(String, usize)>
+}
+
+pub struct Structs{
+    pub layouts: HashMap<String, StructLayout>,
+    current: Option<(Lexem, StructLayout)>
+}
+
+impl Structs{
+    pub fn new() -> Structs{
+        Structs{
+            layouts: HashMap::new(),
+            current: None
+        }
+    }
+
+    pub fn is_open(self: &Self) -> bool{
+        self.current.is_some()
+    }
+
+    pub fn begin(self: &mut Self, directive: &Lexem, args: &[Lexem]) -> Result<(), Diagnostic>{
+        if let Some((open, _)) = &self.current{
+            return Err(Diagnostic::at(directive, format!("struct {} isn't closed", open.value)));
+        }
+
+        if args.len() != 1 || args[0].ttype != LexemType::Ident{
+            return Err(Diagnostic::at(directive, "struct expects name".to_string()));
+        }
+
+        if self.layouts.contains_key(&args[0].value) || builtin_size(&args[0].value).is_some(){
+            return Err(Diagnostic::at(&args[0], format!("Type already defined {}", args[0].value)));
+        }
+
+        self.current = Some((args[0].clone(), StructLayout { size: 0, fields: Vec::new() }));
+
+        Ok(())
+    }
+
+    // args are [name, type] or [name, type, count]
+    pub fn field(self: &mut Self, directive: &Lexem, args: &[Lexem], constants: &HashMap<String, usize>) -> Result<(), Diagnostic>{
+        let layout = match &mut self.current{
+            Some((_, layout)) => layout,
+            None => {
+                return Err(Diagnostic::at(directive, "field outside of struct".to_string()));
+            }
+        };
+
+        let name = &args[0];
+        let ttype = &args[1];
+
+        if layout.fields.iter().any(|(field, _)| *field == name.value){
+            return Err(Diagnostic::at(name, format!("Field already defined {}", name.value)));
+        }
+
+        let count = match args.get(2){
+            Some(a) => eval_constant(a, constants)?,
+            None => 1
+        };
+
+        let offset = layout.size;
+
+        let size = match builtin_size(&ttype.value){
+            Some(a) => a,
+            None => match self.layouts.get(&ttype.value){
+                Some(nested) => {
+                    for (field, field_offset) in nested.fields.iter(){
+                        layout.fields.push((format!("{}.{}", name.value, field), offset + field_offset));
+                    }
+                    nested.size
+                }
+                None => {
+                    return Err(Diagnostic::at(ttype, format!("Unknown type {}", ttype.value)));
+                }
+            }
+        };
+
+        let size = match size.checked_mul(count).and_then(|a| a.checked_add(offset)){
+            Some(a) if a <= MEMORY_WORDS => a,
+            _ => {
+                return Err(Diagnostic::at(name, format!("Field {} doesn't fit into memory", name.value)));
+            }
+        };
+
+        layout.fields.push((name.value.clone(), offset));
+        layout.size = size;
+
+        Ok(())
+    }
+
+    // constants defined by the struct
+    pub fn end(self: &mut Self, directive: &Lexem) -> Result<Vec<(String, usize)>, Diagnostic>{
+        let (name, layout) = match self.current.take(){
+            Some(a) => a,
+            None => {
+                return Err(Diagnostic::at(directive, "endstruct without matching struct".to_string()));
+            }
+        };
+
+        let mut constants: Vec<(String, usize)> = layout.fields.iter().map(|(field, offset)| (format!("{}.{}", name.value, field), *offset)).collect();
+        constants.push((format!("{}.{}", SIZEOF_PREFIX, name.value), layout.size));
+
+        self.layouts.insert(name.value.clone(), layout);
+
+        Ok(constants)
+    }
+
+    // args are [instance, struct, address], addresses defined by the instance
+    pub fn instance(self: &Self, args: &[Lexem], constants: &HashMap<String, usize>) -> Result<Vec<(String, usize)>, Diagnostic>{
+        let layout = match self.layouts.get(&args[1].value){
+            Some(a) => a,
+            None => {
+                return Err(Diagnostic::at(&args[1], format!("Unknown struct {}", args[1].value)));
+            }
+        };
+
+        let address = eval_constant(&args[2], constants)?;
+        if address >= MEMORY_WORDS || layout.size > MEMORY_WORDS - address{
+            return Err(Diagnostic::at(&args[2], format!("{} at {:#x} doesn't fit into memory", args[0].value, address)));
+        }
+
+        let mut addresses: Vec<(String, usize)> = vec![(args[0].value.clone(), address)];
+
+        for (field, offset) in layout.fields.iter(){
+            addresses.push((format!("{}.{}", args[0].value, field), address + offset));
+        }
+
+        Ok(addresses)
+    }
+
+    pub fn check_closed(self: &Self) -> Result<(), Diagnostic>{
+        match &self.current{
+            Some((name, _)) => Err(Diagnostic::at(name, format!("struct {} without matching endstruct", name.value))),
+            None => Ok(())
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use crate::{Lexem, LexemType};
+
+// Lossless concrete syntax tree
+// every character of the source is either the text of a token or trivia in front of it
+// (whitespace and comments), what is left after the last token is trailing trivia of the file,
+// so printing the tree gives back the file byte for byte
+//
+// File
+//   Line
+//     Label      main :
+//     Statement  lim  Operand(R1)  ,  Operand(( 5 + 5 ))
+//     \n         (leading trivia holds the comment at the end of the line)
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TriviaKind{
+    Whitespace,
+    // // and ; up to the end of the line
+    LineComment,
+    // /* */, can span lines
+    BlockComment
+}
+
+#[derive(Debug, Clone)]
+pub struct Trivia{
+    pub kind: TriviaKind,
+    pub text: String,
+    // byte range of text in the source
+    pub span: (usize, usize),
+    pub row: usize,
+    pub col: usize
+}
+
+impl Trivia{
+    pub fn is_comment(self: &Self) -> bool{
+        self.kind != TriviaKind::Whitespace
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxToken{
+    pub leading: Vec<Trivia>,
+    // exactly as written, lexem holds the value (escapes resolved, radix prefix removed)
+    pub text: String,
+    // byte range of text in the source
+    pub span: (usize, usize),
+    pub lexem: Lexem
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind{
+    File,
+    Line,
+    Label,
+    Statement,
+    Operand
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement{
+    Node(SyntaxNode),
+    Token(SyntaxToken)
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxNode{
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>
+}
+
+fn is_single(token: &SyntaxToken, value: &str) -> bool{
+    token.lexem.ttype == LexemType::Single && token.lexem.value == value
+}
+
+impl SyntaxNode{
+    pub fn new(kind: NodeKind) -> SyntaxNode{
+        SyntaxNode{
+            kind,
+            children: Vec::new()
+        }
+    }
+
+    // tokens of the subtree in source order
+    pub fn tokens(self: &Self) -> Vec<&SyntaxToken>{
+        let mut tokens: Vec<&SyntaxToken> = Vec::new();
+
+        for child in self.children.iter(){
+            match child{
+                SyntaxElement::Node(node) => tokens.extend(node.tokens()),
+                SyntaxElement::Token(token) => tokens.push(token)
+            }
+        }
+
+        tokens
+    }
+
+    // direct children of a kind
+    pub fn nodes(self: &Self, kind: NodeKind) -> Vec<&SyntaxNode>{
+        self.children.iter().filter_map(|child| match child{
+            SyntaxElement::Node(node) if node.kind == kind => Some(node),
+            _ => None
+        }).collect()
+    }
+
+    pub fn text(self: &Self) -> String{
+        let mut text = String::new();
+
+        for token in self.tokens(){
+            for trivia in token.leading.iter(){
+                text += trivia.text.as_str();
+            }
+            text += token.text.as_str();
+        }
+
+        text
+    }
+
+    // comments in front of any token of the subtree
+    pub fn comments(self: &Self) -> Vec<&Trivia>{
+        self.tokens().into_iter().flat_map(|token| token.leading.iter().filter(|trivia| trivia.is_comment())).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxTree{
+    pub root: SyntaxNode,
+    pub trailing: Vec<Trivia>
+}
+
+impl SyntaxTree{
+    pub fn new() -> SyntaxTree{
+        SyntaxTree{
+            root: SyntaxNode::new(NodeKind::File),
+            trailing: Vec::new()
+        }
+    }
+
+    // operands are split at commas outside parentheses
+    fn statement(tokens: Vec<SyntaxToken>) -> SyntaxNode{
+        let mut statement = SyntaxNode::new(NodeKind::Statement);
+        let mut tokens = tokens.into_iter();
+
+        if let Some(mnemonic) = tokens.next(){
+            statement.children.push(SyntaxElement::Token(mnemonic));
+        }
+
+        let mut operand = SyntaxNode::new(NodeKind::Operand);
+        let mut depth = 0;
+
+        for token in tokens{
+            if is_single(&token, "(") {
+                depth += 1;
+            }
+            if is_single(&token, ")") && depth > 0{
+                depth -= 1;
+            }
+
+            if is_single(&token, ",") && depth == 0{
+                statement.children.push(SyntaxElement::Node(std::mem::replace(&mut operand, SyntaxNode::new(NodeKind::Operand))));
+                statement.children.push(SyntaxElement::Token(token));
+                continue;
+            }
+
+            operand.children.push(SyntaxElement::Token(token));
+        }
+
+        if !operand.children.is_empty(){
+            statement.children.push(SyntaxElement::Node(operand));
+        }
+
+        statement
+    }
+
+    fn line(tokens: Vec<SyntaxToken>, newline: Option<SyntaxToken>) -> SyntaxNode{
+        let mut line = SyntaxNode::new(NodeKind::Line);
+        let mut tokens = tokens;
+
+        while tokens.len() >= 2 && matches!(tokens[0].lexem.ttype, LexemType::Ident | LexemType::Number { .. }) && is_single(&tokens[1], ":"){
+            let mut label = SyntaxNode::new(NodeKind::Label);
+            label.children.extend(tokens.drain(0..2).map(SyntaxElement::Token));
+            line.children.push(SyntaxElement::Node(label));
+        }
+
+        if !tokens.is_empty(){
+            line.children.push(SyntaxElement::Node(SyntaxTree::statement(tokens)));
+        }
+
+        if let Some(newline) = newline{
+            line.children.push(SyntaxElement::Token(newline));
+        }
+
+        line
+    }
+
+    pub fn build(tokens: Vec<SyntaxToken>, trailing: Vec<Trivia>) -> SyntaxTree{
+        let mut root = SyntaxNode::new(NodeKind::File);
+        let mut current: Vec<SyntaxToken> = Vec::new();
+
+        for token in tokens{
+            if token.lexem.ttype == LexemType::NewLine{
+                root.children.push(SyntaxElement::Node(SyntaxTree::line(std::mem::take(&mut current), Some(token))));
+                continue;
+            }
+            current.push(token);
+        }
+
+        if !current.is_empty(){
+            root.children.push(SyntaxElement::Node(SyntaxTree::line(current, None)));
+        }
+
+        SyntaxTree { root, trailing }
+    }
+
+    pub fn lines(self: &Self) -> Vec<&SyntaxNode>{
+        self.root.nodes(NodeKind::Line)
+    }
+
+    pub fn text(self: &Self) -> String{
+        let mut text = self.root.text();
+
+        for trivia in self.trailing.iter(){
+            text += trivia.text.as_str();
+        }
+
+        text
+    }
+
+    // operand text as written, by (filename, row, col) of its first token, resolved lexems keep that position
+    pub fn operands(self: &Self) -> HashMap<(String, usize, usize), String>{
+        let mut operands: HashMap<(String, usize, usize), String> = HashMap::new();
+
+        for line in self.lines(){
+            for statement in line.nodes(NodeKind::Statement){
+                for operand in statement.nodes(NodeKind::Operand){
+                    if let Some(first) = operand.tokens().first(){
+                        let lexem = &first.lexem;
+                        operands.insert((lexem.filename.clone(), lexem.row, lexem.col), operand.text().trim().to_string());
+                    }
+                }
+            }
+        }
+
+        operands
+    }
+
+    // what the parser works with
+    pub fn lexems(self: &Self) -> Vec<Lexem>{
+        self.root.tokens().into_iter().map(|token| token.lexem.clone()).collect()
+    }
+
+    // every comment as a lexem, the text is kept without trailing whitespace
+    pub fn comments(self: &Self, filename: &str) -> Vec<Lexem>{
+        let trailing = self.trailing.iter().filter(|trivia| trivia.is_comment());
+
+        self.root.comments().into_iter().chain(trailing).map(|trivia| {
+            Lexem::new(trivia.text.trim_end().to_string(), LexemType::Comment, trivia.row, trivia.col, filename.to_string())
+        }).collect()
+    }
+}
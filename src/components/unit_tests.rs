@@ -0,0 +1,326 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{get_value_from_number_token, Diagnostic, Disassembler, Emulator, Lexem, LexemType, Stop, Token, CONDITIONS_TO_VAL, REGISTERS_TO_VAL};
+
+// Unit tests written next to the routines they test, run in the emulator by zasm test
+//
+// test "strlen counts characters"
+//     set a0, message          register
+//     poke buffer, 1, 2, 3     words from an address on
+//     steps 500                budget for the runs below (100000 by default)
+//     run strlen               calls the label, until it returns or halts
+//     expect a0, 12            register
+//     expect z                 condition
+//     peek buffer, 1, 2, 3     words from an address on
+// endtest
+//
+// statements run in order on a fresh machine with the program loaded, a failed expect doesn't stop the test
+
+pub const DEFAULT_STEPS: usize = 100000;
+
+#[derive(Debug, Clone)]
+pub enum TestStep{
+    Set{ register: usize, value: u16 },
+    Poke{ address: u16, values: Vec<u16> },
+    Steps{ budget: usize },
+    Run{ address: u16 },
+    Expect{ register: usize, value: u16 },
+    Condition{ condition: usize },
+    Peek{ address: u16, values: Vec<u16> }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnitTest{
+    pub name: String,
+    pub location: Lexem,
+    // index of the assembled program in the runner
+    pub program: usize,
+    pub steps: Vec<(Lexem, TestStep)>
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult{
+    pub name: String,
+    pub location: Lexem,
+    pub failures: Vec<Diagnostic>,
+    // instructions run
+    pub executed: usize
+}
+
+impl TestResult{
+    pub fn passed(self: &Self) -> bool{
+        self.failures.is_empty()
+    }
+}
+
+fn register(lexem: &Lexem) -> Result<usize, Diagnostic>{
+    match REGISTERS_TO_VAL.get(lexem.value.to_lowercase().as_str()){
+        Some(r) => Ok(*r),
+        None => Err(Diagnostic::at(lexem, format!("Expected register got {}", lexem.value)))
+    }
+}
+
+fn value(lexem: &Lexem) -> Result<usize, Diagnostic>{
+    match lexem.ttype{
+        LexemType::Number { .. } => get_value_from_number_token(lexem),
+        LexemType::Ident => Err(Diagnostic::at(lexem, format!("Use of undeclared label {}", lexem.value))),
+        _ => Err(Diagnostic::at(lexem, format!("Expected number got {}", lexem.value)))
+    }
+}
+
+fn word(lexem: &Lexem) -> Result<u16, Diagnostic>{
+    let value = value(lexem)?;
+
+    if value > 0xFFFF{
+        return Err(Diagnostic::at(lexem, format!("{} doesn't fit in a word", value)));
+    }
+
+    Ok(value as u16)
+}
+
+fn expect_args(name: &Lexem, args: &[Lexem], count: usize) -> Result<(), Diagnostic>{
+    if args.len() != count{
+        return Err(Diagnostic::at(name, format!("{} expects {} argument(s) got {}", name.value, count, args.len())));
+    }
+    Ok(())
+}
+
+fn words(name: &Lexem, args: &[Lexem]) -> Result<(u16, Vec<u16>), Diagnostic>{
+    if args.len() < 2{
+        return Err(Diagnostic::at(name, format!("{} expects address and values", name.value)));
+    }
+
+    Ok((word(&args[0])?, args[1..].iter().map(word).collect::<Result<Vec<u16>, Diagnostic>>()?))
+}
+
+fn format_word(value: u16) -> String{
+    format!("{} (0x{:04X})", value, value)
+}
+
+impl UnitTest{
+    // statements of a test block after labels and closures are resolved
+    pub fn new(name: &Lexem, statements: &[Token], program: usize) -> Result<UnitTest, Diagnostic>{
+        let mut steps: Vec<(Lexem, TestStep)> = Vec::new();
+
+        for token in statements{
+            let (directive, args) = match token{
+                Token::Instruction { name, args } => (name, args),
+                Token::Label { name } => return Err(Diagnostic::at(name, format!("Labels aren't allowed inside test, got {}", name.value)))
+            };
+
+            let step = match directive.value.to_lowercase().as_str(){
+                "set" => {
+                    expect_args(directive, args, 2)?;
+                    TestStep::Set { register: register(&args[0])?, value: word(&args[1])? }
+                }
+                "poke" => {
+                    let (address, values) = words(directive, args)?;
+                    TestStep::Poke { address, values }
+                }
+                "steps" => {
+                    expect_args(directive, args, 1)?;
+                    TestStep::Steps { budget: value(&args[0])? }
+                }
+                "run" => {
+                    expect_args(directive, args, 1)?;
+                    TestStep::Run { address: word(&args[0])? }
+                }
+                "expect" if args.len() == 1 => {
+                    match CONDITIONS_TO_VAL.get(args[0].value.to_lowercase().as_str()){
+                        Some(condition) => TestStep::Condition { condition: *condition },
+                        None => return Err(Diagnostic::at(&args[0], format!("Expected condition got {}", args[0].value)))
+                    }
+                }
+                "expect" => {
+                    expect_args(directive, args, 2)?;
+                    TestStep::Expect { register: register(&args[0])?, value: word(&args[1])? }
+                }
+                "peek" => {
+                    let (address, values) = words(directive, args)?;
+                    TestStep::Peek { address, values }
+                }
+                _ => return Err(Diagnostic::at(directive, format!("Expected set, poke, steps, run, expect or peek got {}", directive.value)))
+            };
+
+            steps.push((directive.clone(), step));
+        }
+
+        if !steps.iter().any(|(_, step)| matches!(step, TestStep::Run { .. })){
+            return Err(Diagnostic::at(name, format!("test \"{}\" never runs anything", name.value)));
+        }
+
+        Ok(UnitTest {
+            name: name.value.clone(),
+            location: name.clone(),
+            program,
+            steps
+        })
+    }
+
+    pub fn run(self: &Self, image: &[u8], decoder: &Disassembler) -> TestResult{
+        let mut emulator = Emulator::new();
+        emulator.load(image);
+
+        let mut budget = DEFAULT_STEPS;
+        let mut failures: Vec<Diagnostic> = Vec::new();
+
+        for (location, step) in self.steps.iter(){
+            match step{
+                TestStep::Set { register, value } => emulator.registers[*register] = *value,
+                TestStep::Poke { address, values } => {
+                    for (i, value) in values.iter().enumerate(){
+                        emulator.memory[address.wrapping_add(i as u16) as usize] = *value;
+                    }
+                }
+                TestStep::Steps { budget: steps } => budget = *steps,
+                TestStep::Run { address } => {
+                    match emulator.call(decoder, *address, budget){
+                        Ok(Stop::Returned) | Ok(Stop::Halted) => {}
+                        // nothing after it means anything
+                        Err(e) => {
+                            failures.push(Diagnostic::at(location, e));
+                            break;
+                        }
+                    }
+                }
+                TestStep::Expect { register, value } => {
+                    let got = if *register == 0 { 0 } else { emulator.registers[*register] };
+
+                    if got != *value{
+                        failures.push(Diagnostic::at(location, format!("expected r{} to be {}, got {}", register, format_word(*value), format_word(got))));
+                    }
+                }
+                TestStep::Condition { condition } => {
+                    if !emulator.condition(*condition){
+                        let name = CONDITIONS_TO_VAL.entries().find(|(_, value)| **value == *condition).map(|(name, _)| *name).unwrap_or("?");
+                        failures.push(Diagnostic::at(location, format!("expected condition {} to hold, flags are 0x{:02X}", name, emulator.flags)));
+                    }
+                }
+                TestStep::Peek { address, values } => {
+                    for (i, value) in values.iter().enumerate(){
+                        let address = address.wrapping_add(i as u16);
+                        let got = emulator.memory[address as usize];
+
+                        if got != *value{
+                            failures.push(Diagnostic::at(location, format!("expected {} at 0x{:04X}, got {}", format_word(*value), address, format_word(got))));
+                        }
+                    }
+                }
+            }
+        }
+
+        TestResult {
+            name: self.name.clone(),
+            location: self.location.clone(),
+            failures,
+            executed: emulator.steps
+        }
+    }
+}
+
+pub struct TestRunner<'a>{
+    decoder: Disassembler<'a>,
+    programs: Vec<Vec<u8>>,
+    pub tests: Vec<UnitTest>,
+    // threads, 0 is one per cpu
+    pub jobs: usize,
+    pub results: Vec<TestResult>
+}
+
+impl TestRunner<'_>{
+    pub fn new(decoder: Disassembler<'_>) -> TestRunner<'_>{
+        TestRunner{
+            decoder,
+            programs: Vec::new(),
+            tests: Vec::new(),
+            jobs: 0,
+            results: Vec::new()
+        }
+    }
+
+    pub fn parse_flag(self: &mut Self, flag: &str) -> Result<bool, Diagnostic>{
+        let jobs = match flag.strip_prefix("--jobs=").or_else(|| flag.strip_prefix("-j")){
+            Some(a) => a,
+            None => return Ok(false)
+        };
+
+        self.jobs = match jobs.parse::<usize>(){
+            Ok(a) => a,
+            Err(_) => return Err(Diagnostic::new(format!("Expected number after --jobs got \"{}\"", jobs)))
+        };
+
+        Ok(true)
+    }
+
+    // an assembled program and the test blocks the parser found in it
+    pub fn add(self: &mut Self, image: Vec<u8>, tests: &[(Lexem, Vec<Token>)]) -> Result<(), Diagnostic>{
+        let program = self.programs.len();
+
+        for (name, statements) in tests.iter(){
+            self.tests.push(UnitTest::new(name, statements, program)?);
+        }
+
+        self.programs.push(image);
+
+        Ok(())
+    }
+
+    // every test on its own machine, results are kept in the order of the tests
+    pub fn run(self: &mut Self){
+        let jobs = match self.jobs{
+            0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n
+        }.min(self.tests.len()).max(1);
+
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<TestResult>>> = Mutex::new(vec![None; self.tests.len()]);
+
+        let (tests, programs, decoder) = (&self.tests, &self.programs, &self.decoder);
+
+        std::thread::scope(|scope| {
+            for _ in 0..jobs{
+                scope.spawn(|| loop{
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let test = match tests.get(i){
+                        Some(a) => a,
+                        None => break
+                    };
+
+                    let result = test.run(&programs[test.program], decoder);
+                    results.lock().unwrap()[i] = Some(result);
+                });
+            }
+        });
+
+        self.results = results.into_inner().unwrap().into_iter().flatten().collect();
+    }
+
+    pub fn failed(self: &Self) -> usize{
+        self.results.iter().filter(|result| !result.passed()).count()
+    }
+
+    pub fn report(self: &Self) -> String{
+        let mut text = String::new();
+
+        for result in self.results.iter(){
+            let status = if result.passed() { "ok" } else { "FAILED" };
+            text += format!("test {} \"{}\" ... {}\n", result.location.filename, result.name, status).as_str();
+        }
+
+        if self.failed() > 0{
+            text += "\nfailures:\n";
+            for result in self.results.iter().filter(|result| !result.passed()){
+                text += format!("\n\"{}\" ({} instructions)\n", result.name, result.executed).as_str();
+                for failure in result.failures.iter(){
+                    text += format!("  {}\n", failure).as_str();
+                }
+            }
+        }
+
+        let status = if self.failed() > 0 { "FAILED" } else { "ok" };
+        text += format!("\ntest result: {}. {} passed; {} failed\n", status, self.results.len() - self.failed(), self.failed()).as_str();
+
+        text
+    }
+}
@@ -0,0 +1,25 @@
+// Everything both zasm and zasm-lsp are built from
+
+pub mod components;
+pub use components::lexer::*;
+pub use components::syntax::*;
+pub use components::parser::*;
+pub use components::codegen::*;
+pub use components::common::*;
+pub use components::instruction_lexer::*;
+pub use components::diagnostics::*;
+pub use components::disassembler::*;
+pub use components::aliases::*;
+pub use components::listing::*;
+pub use components::pseudo_instructions::*;
+pub use components::peephole::*;
+pub use components::control_flow::*;
+pub use components::stack_usage::*;
+pub use components::dead_code::*;
+pub use components::analysis::*;
+pub use components::formatter::*;
+pub use components::docs::*;
+pub use components::conventions::*;
+pub use components::emulator::*;
+pub use components::unit_tests::*;
+pub use components::assembler::*;
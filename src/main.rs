@@ -10,12 +10,7 @@ use std::io::Write;
 use std::path::Path;
 use std::{fs::File, io::Read};
 
-mod components;
-use components::lexer::*;
-use components::parser::*;
-use components::codegen::*;
-use components::common::*;
-use components::instruction_lexer::*;
+use zasm::*;
 
 /*
 
@@ -24,6 +19,297 @@ use components::instruction_lexer::*;
 */
 
 
+fn exit_on_error<T>(result: Result<T, Diagnostic>) -> T{
+    match result{
+        Ok(a) => a,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// zasm fmt [--check] [options] [files], without files stdin is formatted to stdout
+fn fmt(filename: &str, args: std::env::Args){
+    let mut config: FormatConfig = FormatConfig::new();
+
+    let mut check = false;
+
+    let mut sources: Vec<String> = Vec::new();
+
+    for arg in args{
+        if arg == "--check"{
+            check = true;
+            continue;
+        }
+
+        if exit_on_error(config.parse_flag(&arg)){
+            continue;
+        }
+
+        if arg.starts_with("--"){
+            println!("{}: Unknown fmt option {}", filename, arg);
+            std::process::exit(1);
+        }
+
+        sources.push(arg);
+    }
+
+    let format = |source_filename: &str, content: &str| -> String{
+        let mut lexer: Lexer = Lexer::new();
+        exit_on_error(lexer.lex(source_filename, content));
+
+        let mut formatter: Formatter = Formatter::new(config.clone());
+        formatter.format(&lexer.tree);
+        exit_on_error(formatter.verify(source_filename, &lexer.tree));
+
+        formatter.text
+    };
+
+    if sources.is_empty(){
+        let mut content = String::new();
+        let _ = std::io::stdin().read_to_string(&mut content);
+
+        let text = format("<stdin>", &content);
+
+        if check{
+            std::process::exit(if text == content { 0 } else { 1 });
+        }
+
+        print!("{}", text);
+        return;
+    }
+
+    let mut unformatted = 0;
+
+    for source_filename in sources.iter(){
+        let mut content = String::new();
+
+        match File::open(source_filename).and_then(|mut file| file.read_to_string(&mut content)){
+            Ok(_) => {}
+            Err(e) => {
+                println!("{}: {}", source_filename, e);
+                std::process::exit(1);
+            }
+        }
+
+        let text = format(source_filename, &content);
+
+        if text == content{
+            continue;
+        }
+
+        if check{
+            println!("{}: not formatted", source_filename);
+            unformatted += 1;
+            continue;
+        }
+
+        match File::create(source_filename).and_then(|mut file| file.write_all(text.as_bytes())){
+            Ok(_) => println!("Formatted file: {}", source_filename),
+            Err(e) => {
+                println!("{}: {}", source_filename, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if unformatted > 0{
+        std::process::exit(1);
+    }
+}
+
+// zasm doc file.a [-o out.md|out.html] [--html], markdown to stdout by default
+fn doc(filename: &str, args: std::env::Args, instructions: &std::collections::HashMap<&'static str, Vec<InstructionPart>>){
+    let mut source_filename: Option<String> = None;
+
+    let mut output_filename: Option<String> = None;
+
+    let mut html = false;
+
+    let mut warnings: Warnings = Warnings::new();
+
+    let mut args = args;
+
+    while let Some(arg) = args.next(){
+        if arg == "-o"{
+            output_filename = match args.next(){
+                Some(a) => Some(a),
+                None => {
+                    println!("{}: Expected file after -o", filename);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+
+        if arg == "--html"{
+            html = true;
+            continue;
+        }
+
+        if exit_on_error(warnings.parse_flag(&arg)){
+            continue;
+        }
+
+        if source_filename.is_some(){
+            println!("{}: Unexpected argument {}", filename, arg);
+            std::process::exit(1);
+        }
+
+        source_filename = Some(arg);
+    }
+
+    let source_filename = match source_filename{
+        Some(a) => a,
+        None => {
+            println!("{}: Source Filename wasn't provided", filename);
+            std::process::exit(1);
+        }
+    };
+
+    let mut content = String::new();
+
+    match File::open(&source_filename).and_then(|mut file| file.read_to_string(&mut content)){
+        Ok(_) => {}
+        Err(e) => {
+            println!("{}: {}", source_filename, e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut lexer: Lexer = Lexer::new();
+    exit_on_error(lexer.lex(&source_filename, &content));
+
+    let mut parser: Parser = Parser::new();
+    exit_on_error(parser.parse(&lexer.lexems));
+
+    let mut codegen: CodeGen = CodeGen::new(&parser.tokens, instructions);
+    codegen.operands = lexer.tree.operands();
+    exit_on_error(codegen.gen());
+
+    let mut docs: Docs = Docs::new();
+    docs.extract(&lexer.tree, &source_filename, &parser.label_definitions(), &parser.labels, codegen.bytes.len() / 2);
+
+    warnings.add_pragmas(&parser.pragmas);
+    warnings.report(&docs.diagnostics);
+
+    if let Some(output) = &output_filename{
+        html |= Path::new(output).extension().and_then(|ext| ext.to_str()) == Some("html");
+    }
+
+    let title = Path::new(&source_filename).file_name().and_then(|name| name.to_str()).unwrap_or(&source_filename).to_string();
+
+    let text = match html{
+        true => docs.to_html(&title),
+        false => docs.to_markdown(&title)
+    };
+
+    match output_filename{
+        Some(output) => {
+            match File::create(&output).and_then(|mut file| file.write_all(text.as_bytes())){
+                Ok(_) => println!("Documented {} routine(s): {}", docs.routines.len(), output),
+                Err(e) => {
+                    println!("{}: {}", output, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => print!("{}", text)
+    }
+}
+
+// zasm test [files or directories] [--jobs=N], runs every test ... endtest block in the emulator
+fn test(filename: &str, args: std::env::Args, instructions: &std::collections::HashMap<&'static str, Vec<InstructionPart>>){
+    let mut runner: TestRunner = TestRunner::new(Disassembler::new(instructions));
+
+    let mut paths: Vec<String> = Vec::new();
+
+    for arg in args{
+        if exit_on_error(runner.parse_flag(&arg)){
+            continue;
+        }
+
+        if arg.starts_with("--"){
+            println!("{}: Unknown test option {}", filename, arg);
+            std::process::exit(1);
+        }
+
+        paths.push(arg);
+    }
+
+    if paths.is_empty(){
+        paths.push(".".to_string());
+    }
+
+    let mut sources: Vec<String> = Vec::new();
+    for path in paths.iter(){
+        discover(Path::new(path), &mut sources);
+    }
+
+    for source_filename in sources.iter(){
+        let mut content = String::new();
+
+        match File::open(source_filename).and_then(|mut file| file.read_to_string(&mut content)){
+            Ok(_) => {}
+            Err(e) => {
+                println!("{}: {}", source_filename, e);
+                std::process::exit(1);
+            }
+        }
+
+        let mut assembler: Assembler = Assembler::new(instructions);
+        exit_on_error(assembler.assemble(source_filename, &content));
+
+        if assembler.parser.tests.is_empty(){
+            continue;
+        }
+
+        exit_on_error(runner.add(assembler.bytes.clone(), &assembler.parser.tests));
+    }
+
+    if runner.tests.is_empty(){
+        println!("{}: No tests found", filename);
+        return;
+    }
+
+    runner.run();
+
+    print!("{}", runner.report());
+
+    if runner.failed() > 0{
+        std::process::exit(1);
+    }
+}
+
+// .a and .asm files under path, a file given by name is always taken
+fn discover(path: &Path, sources: &mut Vec<String>){
+    if !path.is_dir(){
+        sources.push(path.to_string_lossy().to_string());
+        return;
+    }
+
+    let mut entries: Vec<std::path::PathBuf> = match std::fs::read_dir(path){
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => return
+    };
+    entries.sort();
+
+    for entry in entries{
+        let name = entry.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+
+        if name.starts_with('.') || name == "target"{
+            continue;
+        }
+
+        if entry.is_dir(){
+            discover(&entry, sources);
+        }else if matches!(entry.extension().and_then(|ext| ext.to_str()), Some("a") | Some("asm")){
+            sources.push(entry.to_string_lossy().to_string());
+        }
+    }
+}
+
 fn main() {
 
     
@@ -33,13 +319,177 @@ fn main() {
 
     let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
 
-    instruction_lexer.lex_instructions();
+    exit_on_error(instruction_lexer.lex_instructions());
 
     let mut args = std::env::args();
 
     let filename = args.next().unwrap();
 
-    let source_filename = match args.next(){
+    if std::env::args().nth(1).as_deref() == Some("fmt"){
+        args.next();
+        fmt(&filename, args);
+        return;
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("test"){
+        args.next();
+        test(&filename, args, &instruction_lexer.instructions);
+        return;
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("doc"){
+        args.next();
+        doc(&filename, args, &instruction_lexer.instructions);
+        return;
+    }
+
+    let mut warnings: Warnings = Warnings::new();
+
+    let mut source_filename: Option<String> = None;
+
+    let mut disassemble = false;
+
+    let mut abi_names = false;
+
+    let mut listing_filename: Option<String> = None;
+
+    let mut cfg_filename: Option<String> = None;
+
+    let mut stack_size: Option<usize> = None;
+
+    let mut stack_report = false;
+
+    let mut strip_dead = false;
+
+    let mut aliases: Aliases = Aliases::new();
+
+    let mut peephole: Peephole = Peephole::new();
+
+    while let Some(arg) = args.next(){
+        if arg == "-d"{
+            disassemble = true;
+            continue;
+        }
+
+        if arg == "--pseudo-reference"{
+            print!("{}", PseudoInstructions::reference());
+            return;
+        }
+
+        if arg == "--listing"{
+            listing_filename = match args.next(){
+                Some(a) => Some(a),
+                None => {
+                    println!("{}: Expected file after --listing", filename);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+
+        if arg == "--cfg"{
+            cfg_filename = match args.next(){
+                Some(a) => Some(a),
+                None => {
+                    println!("{}: Expected file after --cfg", filename);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+
+        if arg == "--stack-size"{
+            let size = args.next().unwrap_or_default();
+
+            let parsed = match size.strip_prefix("0x"){
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => size.parse::<usize>()
+            };
+
+            stack_size = match parsed{
+                Ok(a) => Some(a),
+                Err(_) => {
+                    println!("{}: Expected stack size in words after --stack-size got \"{}\"", filename, size);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+
+        if arg == "--strip-dead"{
+            strip_dead = true;
+            continue;
+        }
+
+        if arg == "--stack-report"{
+            stack_report = true;
+            continue;
+        }
+
+        if arg == "--no-lui-elide"{
+            peephole.elide_lui = false;
+            continue;
+        }
+
+        if arg == "--abi-names"{
+            abi_names = true;
+            continue;
+        }
+
+        if arg == "--abi"{
+            let abi_filename = match args.next(){
+                Some(a) => a,
+                None => {
+                    println!("{}: Expected file after --abi", filename);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut content = String::new();
+
+            match File::open(&abi_filename).and_then(|mut file| file.read_to_string(&mut content)){
+                Ok(_) => {}
+                Err(e) => {
+                    println!("{}: {}", abi_filename, e);
+                    std::process::exit(1);
+                }
+            }
+
+            exit_on_error(aliases.load(&abi_filename, &content));
+            continue;
+        }
+
+
+        // -W name
+        let arg = if arg == "-W"{
+            match args.next(){
+                Some(name) => format!("-W{}", name),
+                None => {
+                    println!("{}: Expected warning name after -W", filename);
+                    std::process::exit(1);
+                }
+            }
+        }else{
+            arg
+        };
+
+        if exit_on_error(warnings.parse_flag(&arg)){
+            continue;
+        }
+
+        if exit_on_error(peephole.parse_flag(&arg)){
+            continue;
+        }
+
+        if source_filename.is_some(){
+            println!("{}: Unexpected argument {}", filename, arg);
+            std::process::exit(1);
+        }
+
+        source_filename = Some(arg);
+    }
+
+    let source_filename = match source_filename{
         Some(n) => {n},
         None => {
             println!("{}: Source Filename wasn't provided", filename);
@@ -51,36 +501,88 @@ fn main() {
 
     let mut file = File::open(path).unwrap();
 
+    if disassemble{
+        let mut bytes: Vec<u8> = Vec::new();
+
+        file.read_to_end(&mut bytes).unwrap();
+
+        let mut disassembler: Disassembler = Disassembler::new(&instruction_lexer.instructions);
+
+        if abi_names{
+            disassembler.register_names = aliases.register_names();
+        }
+
+        exit_on_error(disassembler.disassemble(&bytes));
+
+        for line in disassembler.lines.iter(){
+            println!("{:04X}: {:04X}  {}", line.address, line.word, line.text);
+        }
+
+        return;
+    }
+
     let mut content = String::new();
 
     file.read_to_string(&mut content).unwrap();
 
-    let mut lexer: Lexer = Lexer::new();
+    let mut assembler: Assembler = Assembler::new(&instruction_lexer.instructions);
 
-    lexer.lex(&source_filename, &content);
+    assembler.aliases = aliases;
+    assembler.peephole = peephole;
+    assembler.strip_dead = strip_dead;
+    assembler.stack_usage = stack_report;
+    assembler.stack_size = stack_size;
 
-    // dbg!(&lexer.lexems);
-    
-    let mut parser: Parser = Parser::new();
-    
-    parser.parse(&lexer.lexems);
-    
-    // dbg!(&parser.tokens);
-    
-    let mut codegen: CodeGen = CodeGen::new(&parser.tokens, &instruction_lexer.instructions);
+    exit_on_error(assembler.assemble(&source_filename, &content));
 
-    codegen.gen();
+    warnings.add_pragmas(&assembler.parser.pragmas);
 
-    let output_str = match path.extension(){
-        Some(a) => path.to_str().unwrap().replace(a.to_str().unwrap(),"zirc16"),
-        None => path.to_str().unwrap().to_string() + ".zirc16"
-    };
+    if stack_report{
+        print!("{}", assembler.stack_report);
+    }
+
+    let warning_count = warnings.report(&assembler.diagnostics);
+
+    if warnings.werror && warning_count > 0{
+        println!("{}: {} warning(s) treated as errors", source_filename, warning_count);
+        std::process::exit(1);
+    }
+
+    if let Some(listing_filename) = listing_filename{
+        let mut listing: Listing = Listing::new(&assembler.parser.tokens, &assembler.spans, &assembler.bytes);
+
+        listing.add_source(&source_filename, &content);
+        listing.add_notes(&assembler.parser.notes);
+        listing.render();
+
+        let mut file = File::create(&listing_filename).unwrap();
+
+        let _ = file.write(listing.text.as_bytes());
+    }
+
+    // .json for scripts, graphviz dot otherwise
+    if let Some(cfg_filename) = cfg_filename{
+        let mut cfg: ControlFlowGraph = ControlFlowGraph::new(&assembler.parser.tokens, &assembler.spans, &assembler.parser.code_labels);
+        cfg.add_source(&source_filename, &content);
+        cfg.build();
+
+        let text = match Path::new(&cfg_filename).extension().and_then(|ext| ext.to_str()){
+            Some("json") => cfg.to_json(),
+            _ => cfg.to_dot()
+        };
+
+        let mut file = File::create(&cfg_filename).unwrap();
+
+        let _ = file.write(text.as_bytes());
+    }
+
+    let output_str = path.with_extension("zirc16").to_str().unwrap().to_string();
 
     let mut file = File::create(&output_str).unwrap();
 
-    let _ =file.write(&codegen.bytes);
+    let _ =file.write(&assembler.bytes);
 
-    println!("Assembled file: {} ({} bytes)", output_str, codegen.bytes.len());
+    println!("Assembled file: {} ({} bytes)", output_str, assembler.bytes.len());
     
 
 }
@@ -0,0 +1,58 @@
+// Programs with test blocks assembled and run the way zasm test does, every test has to pass
+
+use zasm::*;
+
+fn run(source: &str) -> String{
+    let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
+    instruction_lexer.lex_instructions().unwrap();
+
+    let mut assembler: Assembler = Assembler::new(&instruction_lexer.instructions);
+    assembler.assemble("emulator.a", source).unwrap();
+
+    let mut runner: TestRunner = TestRunner::new(Disassembler::new(&instruction_lexer.instructions));
+    runner.jobs = 1;
+    runner.add(assembler.bytes.clone(), &assembler.parser.tests).unwrap();
+    runner.run();
+
+    assert!(!runner.results.is_empty(), "no tests in\n{}", source);
+    assert_eq!(runner.failed(), 0, "{}", runner.report());
+
+    runner.report()
+}
+
+// lui carries 10 bits where other instructions have a register
+#[test]
+fn lui_above_register_range(){
+    run("
+far:
+    limb r1, 0x1000
+    limb r2, 0xFFC0
+    ret
+
+test \"far\"
+    run far
+    expect r1, 0x1000
+    expect r2, 0xFFC0
+endtest
+");
+}
+
+// li is sized by what is known about the machine, the form it takes mustn't touch the flags
+#[test]
+fn li_keeps_flags(){
+    run("
+compare:
+    li r3, 0x3C
+    cmp r1, r2
+    li r3, 0x41
+    ret
+
+test \"li after cmp\"
+    set r1, 5
+    set r2, 5
+    run compare
+    expect e
+    expect r3, 0x41
+endtest
+");
+}
@@ -0,0 +1,139 @@
+// Golden files for the assembler, every tests/golden/NAME.a is assembled and compared with
+//   NAME.zirc16       the bytes, missing when assembling fails
+//   NAME.diagnostics  the error or the enabled warnings, one per line, missing when there are none
+// ZASM_BLESS=1 cargo test --test golden writes what the assembler produces now instead of comparing
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use zasm::*;
+
+fn golden_dir() -> PathBuf{
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn sources() -> Vec<PathBuf>{
+    let mut sources: Vec<PathBuf> = fs::read_dir(golden_dir()).unwrap().flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("a"))
+        .collect();
+    sources.sort();
+    sources
+}
+
+fn instructions() -> InstructionsLexer{
+    let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
+    instruction_lexer.lex_instructions().unwrap();
+    instruction_lexer
+}
+
+// bytes when it assembles and the diagnostics as zasm prints them
+fn assemble(instruction_lexer: &InstructionsLexer, source: &Path) -> (Option<Vec<u8>>, String){
+    let filename = source.file_name().unwrap().to_string_lossy().to_string();
+    let content = fs::read_to_string(source).unwrap();
+
+    let mut assembler: Assembler = Assembler::new(&instruction_lexer.instructions);
+
+    match assembler.assemble(&filename, &content){
+        Ok(()) => {
+            let mut warnings: Warnings = Warnings::new();
+            warnings.add_pragmas(&assembler.parser.pragmas);
+
+            let text: String = assembler.diagnostics.iter().filter(|diagnostic| warnings.is_enabled(diagnostic)).map(|diagnostic| format!("{}\n", diagnostic)).collect();
+            (Some(assembler.bytes.clone()), text)
+        }
+        Err(e) => (None, format!("{}\n", e))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String{
+    bytes.chunks(2).map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect::<String>()).collect::<Vec<String>>().join(" ")
+}
+
+#[test]
+fn golden(){
+    let instruction_lexer = instructions();
+    let bless = std::env::var_os("ZASM_BLESS").is_some();
+
+    let sources = sources();
+    assert!(!sources.is_empty(), "no golden files in {}", golden_dir().display());
+
+    let mut failures: Vec<String> = Vec::new();
+
+    for source in sources.iter(){
+        let (bytes, diagnostics) = assemble(&instruction_lexer, source);
+
+        let bytes_path = source.with_extension("zirc16");
+        let diagnostics_path = source.with_extension("diagnostics");
+
+        if bless{
+            match &bytes{
+                Some(bytes) => fs::write(&bytes_path, bytes).unwrap(),
+                None => {
+                    let _ = fs::remove_file(&bytes_path);
+                }
+            }
+            match diagnostics.is_empty(){
+                true => {
+                    let _ = fs::remove_file(&diagnostics_path);
+                }
+                false => fs::write(&diagnostics_path, &diagnostics).unwrap()
+            }
+            continue;
+        }
+
+        let name = source.file_name().unwrap().to_string_lossy();
+
+        let expected_bytes = fs::read(&bytes_path).ok();
+        if expected_bytes != bytes{
+            let show = |bytes: &Option<Vec<u8>>| bytes.as_ref().map(|bytes| hex(bytes)).unwrap_or("nothing (error)".to_string());
+            failures.push(format!("{}: bytes differ\n  expected {}\n  got      {}", name, show(&expected_bytes), show(&bytes)));
+        }
+
+        let expected_diagnostics = fs::read_to_string(&diagnostics_path).unwrap_or_default();
+        if expected_diagnostics != diagnostics{
+            failures.push(format!("{}: diagnostics differ\n  expected:\n{}  got:\n{}", name, expected_diagnostics, diagnostics));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}\n\nrerun with ZASM_BLESS=1 if the new output is right", failures.join("\n\n"));
+}
+
+// every encoding in INSTRUCTIONS comes out of some golden file, only words written for instructions count
+// so data that happens to decode (dw 0 is hlt) doesn't
+#[test]
+fn every_instruction_is_covered(){
+    let instruction_lexer = instructions();
+    let mut covered: HashSet<String> = HashSet::new();
+
+    for source in sources(){
+        let filename = source.file_name().unwrap().to_string_lossy().to_string();
+        let content = fs::read_to_string(&source).unwrap();
+
+        let mut assembler: Assembler = Assembler::new(&instruction_lexer.instructions);
+        if assembler.assemble(&filename, &content).is_err(){
+            continue;
+        }
+
+        for (token, (start, end)) in assembler.parser.tokens.iter().zip(assembler.spans.iter()){
+            match token{
+                Token::Instruction { name, .. } if !["dw", "dd", "dq"].contains(&name.value.to_lowercase().as_str()) => {}
+                _ => continue
+            }
+
+            let mut disassembler: Disassembler = Disassembler::new(&instruction_lexer.instructions);
+            disassembler.disassemble(&assembler.bytes[*start..*end]).unwrap();
+
+            for line in disassembler.lines.iter(){
+                let mnemonic = line.text.split([' ', '.']).next().unwrap_or("");
+                covered.insert(mnemonic.to_string());
+            }
+        }
+    }
+
+    let mut missing: Vec<&str> = INSTRUCTIONS.keys().copied().filter(|name| !covered.contains(*name)).collect();
+    missing.sort();
+
+    assert!(missing.is_empty(), "no golden file encodes {}", missing.join(", "));
+}
@@ -0,0 +1,123 @@
+// Random instructions built from the InstructionPart patterns go through
+//   source -> assembler -> bytes -> disassembler -> source -> assembler -> bytes
+// and both byte images have to match the encoding worked out from the pattern
+
+use std::collections::HashMap;
+
+use proptest::prelude::*;
+use zasm::*;
+
+fn instructions() -> InstructionsLexer{
+    let mut instruction_lexer: InstructionsLexer = InstructionsLexer::new();
+    instruction_lexer.lex_instructions().unwrap();
+    instruction_lexer
+}
+
+// source and encoding of one instruction, every field is picked from raw
+fn instruction(instruction_set: &HashMap<&'static str, Vec<InstructionPart>>, mnemonic: &'static str, raw: &[usize]) -> (String, u16){
+    let mut operands: Vec<String> = Vec::new();
+    let mut word: u16 = 0;
+    let mut bit: usize = 16;
+    let mut raw = raw.iter().cycle();
+
+    for part in instruction_set.get(mnemonic).unwrap(){
+        let (size, field) = match part{
+            InstructionPart::Const { val } => (val.len(), usize::from_str_radix(val, 2).unwrap()),
+            InstructionPart::Imm { size } | InstructionPart::Extra { size } => {
+                let field = raw.next().unwrap() & ((1 << size) - 1);
+                operands.push(format!("{}", field));
+                (*size, field)
+            }
+            InstructionPart::Type { val, size } => {
+                // only values with a name, the rest aren't valid operands
+                let mut names: Vec<(&'static str, usize)> = TYPES.get(val.as_str()).unwrap().entries().map(|(name, value)| (*name, *value)).collect();
+                names.sort();
+                let (name, field) = names[raw.next().unwrap() % names.len()];
+                operands.push(name.to_string());
+                (*size, field)
+            }
+        };
+
+        bit -= size;
+        word |= (field as u16) << bit;
+    }
+
+    match operands.is_empty(){
+        true => (mnemonic.to_string(), word),
+        false => (format!("{} {}", mnemonic, operands.join(", ")), word)
+    }
+}
+
+fn assemble(instruction_set: &HashMap<&'static str, Vec<InstructionPart>>, content: &str) -> Vec<u8>{
+    let mut assembler: Assembler = Assembler::new(instruction_set);
+    // the encoding of every line is compared, nothing may be dropped
+    assembler.peephole.elide_lui = false;
+
+    match assembler.assemble("roundtrip.a", content){
+        Ok(()) => assembler.bytes,
+        Err(e) => panic!("{}\n{}", e, content)
+    }
+}
+
+fn disassemble(instruction_set: &HashMap<&'static str, Vec<InstructionPart>>, bytes: &[u8]) -> String{
+    let mut disassembler: Disassembler = Disassembler::new(instruction_set);
+    disassembler.disassemble(bytes).unwrap();
+
+    disassembler.lines.iter().map(|line| format!("{}\n", line.text)).collect()
+}
+
+fn program() -> impl Strategy<Value = Vec<(usize, Vec<usize>)>>{
+    prop::collection::vec((any::<usize>(), prop::collection::vec(any::<usize>(), 4)), 1..32)
+}
+
+proptest!{
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn assemble_disassemble_assemble(program in program()){
+        let instruction_lexer = instructions();
+
+        let mut mnemonics: Vec<&'static str> = INSTRUCTIONS.keys().copied().collect();
+        mnemonics.sort();
+
+        let mut source = String::new();
+        let mut expected: Vec<u8> = Vec::new();
+
+        for (mnemonic, raw) in program.iter(){
+            let (line, word) = instruction(&instruction_lexer.instructions, mnemonics[mnemonic % mnemonics.len()], raw);
+            source += format!("{}\n", line).as_str();
+            expected.extend_from_slice(&word.to_be_bytes());
+        }
+
+        let bytes = assemble(&instruction_lexer.instructions, &source);
+        prop_assert_eq!(&bytes, &expected, "assembling\n{}", source);
+
+        let disassembled = disassemble(&instruction_lexer.instructions, &bytes);
+        let reassembled = assemble(&instruction_lexer.instructions, &disassembled);
+        prop_assert_eq!(&reassembled, &bytes, "reassembling\n{}", disassembled);
+
+        prop_assert_eq!(disassemble(&instruction_lexer.instructions, &reassembled), disassembled);
+    }
+}
+
+// every word decodes to at most one instruction
+#[test]
+fn encodings_are_unambiguous(){
+    let instruction_lexer = instructions();
+    let disassembler: Disassembler = Disassembler::new(&instruction_lexer.instructions);
+
+    let ambiguous: Vec<String> = disassembler.ambiguous().iter().map(|(a, b)| format!("{} and {}", a, b)).collect();
+    assert!(ambiguous.is_empty(), "constant bits overlap: {}", ambiguous.join(", "));
+}
+
+#[test]
+fn overlapping_encodings_are_found(){
+    let mut instruction_set: HashMap<&'static str, Vec<InstructionPart>> = HashMap::new();
+    instruction_set.insert("wide", vec![InstructionPart::Const { val: "0000".to_string() }, InstructionPart::Imm { size: 12 }]);
+    instruction_set.insert("narrow", vec![InstructionPart::Const { val: "00001111".to_string() }, InstructionPart::Imm { size: 8 }]);
+    instruction_set.insert("other", vec![InstructionPart::Const { val: "0001".to_string() }, InstructionPart::Imm { size: 12 }]);
+
+    let disassembler: Disassembler = Disassembler::new(&instruction_set);
+
+    assert_eq!(disassembler.ambiguous(), vec![("narrow", "wide")]);
+}